@@ -0,0 +1,142 @@
+/*
+ * C interface to the c8emu CHIP-8 emulator core, generated by cbindgen from
+ * src/lib.rs. Do not edit.
+ *
+ * An instance from c8emu_create must not be used from two threads at once.
+ * Separate instances are independent.
+ */
+
+#ifndef C8EMU_H
+#define C8EMU_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define C8EMU_WIDTH 64
+
+#define C8EMU_HEIGHT 32
+
+#define C8EMU_OK 0
+
+#define C8EMU_ERROR -1
+
+// An emulator instance.
+typedef struct C8Emu C8Emu;
+
+// CPU registers, as read by `c8emu_registers`.
+typedef struct C8Registers {
+  uint8_t v[16];
+  uint16_t i;
+  uint16_t pc;
+  uint8_t sp;
+  uint8_t dt;
+  uint8_t st;
+} C8Registers;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Create an emulator with nothing loaded, free it with `c8emu_destroy`.
+struct C8Emu *c8emu_create(void);
+
+// # Safety
+// `emu` must come from `c8emu_create`, or be null. It can't be used afterwards.
+void c8emu_destroy(struct C8Emu *emu);
+
+// Load a program from `len` bytes at `data`, resetting the machine. Quirks and
+// instructions per frame come from the ROM database for ROMs it knows.
+//
+// # Safety
+// `data` must point to `len` readable bytes.
+int c8emu_load_rom(struct C8Emu *emu, const uint8_t *data, size_t len);
+
+// Restart the random number generator, so RND gives the same numbers every run.
+//
+// # Safety
+// `emu` must be a live instance from `c8emu_create`.
+void c8emu_set_seed(struct C8Emu *emu, uint64_t seed);
+
+// Instructions run by `c8emu_frame`, at least 1.
+//
+// # Safety
+// `emu` must be a live instance from `c8emu_create`.
+void c8emu_set_ipf(struct C8Emu *emu, size_t ipf);
+
+// Execute a single instruction, without ticking the timers.
+//
+// # Safety
+// `emu` must be a live instance from `c8emu_create`.
+int c8emu_step(struct C8Emu *emu);
+
+// Run one 60 Hz frame: the instructions per frame, then one timer tick.
+//
+// # Safety
+// `emu` must be a live instance from `c8emu_create`.
+int c8emu_frame(struct C8Emu *emu);
+
+// Press or release keypad key 0x0 - 0xF, failing for any other key.
+//
+// # Safety
+// `emu` must be a live instance from `c8emu_create`.
+int c8emu_set_key(struct C8Emu *emu, uint8_t key, bool pressed);
+
+// Set the whole keypad, bit N held for key N.
+//
+// # Safety
+// `emu` must be a live instance from `c8emu_create`.
+void c8emu_set_keys(struct C8Emu *emu, uint16_t mask);
+
+// Copy the display to `out`, one byte per pixel (0 or 1) row by row. Returns the
+// bytes needed, `C8EMU_WIDTH * C8EMU_HEIGHT`, and copies nothing if `len` is less.
+//
+// # Safety
+// `out` must point to `len` writable bytes.
+size_t c8emu_framebuffer(const struct C8Emu *emu, uint8_t *out, size_t len);
+
+// # Safety
+// `out` must point to a `C8Registers`.
+void c8emu_registers(const struct C8Emu *emu, struct C8Registers *out);
+
+// The 4 KiB of memory, valid until the next call that changes the machine. Its
+// size is stored in `len` when that isn't null.
+//
+// # Safety
+// `len` must be null or point to a writable `size_t`.
+const uint8_t *c8emu_memory(const struct C8Emu *emu, size_t *len);
+
+// Whether the buzzer is sounding.
+//
+// # Safety
+// `emu` must be a live instance from `c8emu_create`.
+bool c8emu_sound_active(const struct C8Emu *emu);
+
+// Bytes needed by `c8emu_snapshot`, the same for every instance.
+size_t c8emu_state_size(void);
+
+// Save the whole machine to `out`, which needs `c8emu_state_size()` bytes.
+//
+// # Safety
+// `out` must point to `len` writable bytes.
+int c8emu_snapshot(struct C8Emu *emu, uint8_t *out, size_t len);
+
+// Restore a machine saved by `c8emu_snapshot`, from any instance.
+//
+// # Safety
+// `data` must point to `len` readable bytes.
+int c8emu_restore(struct C8Emu *emu, const uint8_t *data, size_t len);
+
+// Message for the last call that returned `C8EMU_ERROR`, owned by the instance.
+//
+// # Safety
+// `emu` must be a live instance from `c8emu_create`.
+const char *c8emu_last_error(const struct C8Emu *emu);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* C8EMU_H */
@@ -0,0 +1,362 @@
+//! C interface to the emulator core, declared in `include/c8emu.h`.
+//!
+//! Every function taking a `C8Emu` pointer needs one from `c8emu_create` that
+//! hasn't been destroyed, and must not be called on the same instance from two
+//! threads at once. Separate instances are independent.
+
+#[cfg(feature = "python")]
+mod python;
+
+use c8emu::cpu::state::STATE_SIZE;
+use c8emu::cpu::{Cpu, CpuError, DEFAULT_IPF, HEIGHT, WIDTH};
+use c8emu::romfile::RomImage;
+use std::ffi::{c_char, c_int, CString};
+
+pub const C8EMU_WIDTH: usize = 64;
+pub const C8EMU_HEIGHT: usize = 32;
+pub const C8EMU_OK: c_int = 0;
+pub const C8EMU_ERROR: c_int = -1; // Details from `c8emu_last_error`
+
+/// An emulator instance.
+pub struct C8Emu {
+    cpu: Cpu,
+    ipf: usize,
+    error: CString, // Message for the last failed call
+}
+
+/// CPU registers, as read by `c8emu_registers`.
+#[repr(C)]
+pub struct C8Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+}
+
+impl C8Emu {
+    /// Turn a result into a return code, keeping the error for `c8emu_last_error`.
+    fn check(&mut self, result: Result<(), CpuError>) -> c_int {
+        match result {
+            Ok(()) => C8EMU_OK,
+            Err(e) => self.fail(&e.to_string()),
+        }
+    }
+
+    fn fail(&mut self, msg: &str) -> c_int {
+        self.error = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+        C8EMU_ERROR
+    }
+}
+
+/// Create an emulator with nothing loaded, free it with `c8emu_destroy`.
+#[no_mangle]
+pub extern "C" fn c8emu_create() -> *mut C8Emu {
+    Box::into_raw(Box::new(C8Emu {
+        cpu: Cpu::new(),
+        ipf: DEFAULT_IPF,
+        error: CString::default(),
+    }))
+}
+
+/// # Safety
+/// `emu` must come from `c8emu_create`, or be null. It can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_destroy(emu: *mut C8Emu) {
+    if !emu.is_null() {
+        drop(Box::from_raw(emu));
+    }
+}
+
+/// Load a program from `len` bytes at `data`, resetting the machine. Quirks and
+/// instructions per frame come from the ROM database for ROMs it knows.
+///
+/// # Safety
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_load_rom(emu: *mut C8Emu, data: *const u8, len: usize) -> c_int {
+    let emu = &mut *emu;
+    if data.is_null() {
+        return emu.fail("No ROM data");
+    }
+    let image = RomImage {
+        bytes: std::slice::from_raw_parts(data, len).to_vec(),
+        info: None,
+    };
+    match emu.cpu.load_image(image, "ROM data") {
+        Ok(loaded) => {
+            emu.ipf = loaded
+                .info
+                .and_then(|info| info.tickrate)
+                .unwrap_or(DEFAULT_IPF);
+            C8EMU_OK
+        }
+        Err(e) => emu.fail(&e.to_string()),
+    }
+}
+
+/// Restart the random number generator, so RND gives the same numbers every run.
+///
+/// # Safety
+/// `emu` must be a live instance from `c8emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_set_seed(emu: *mut C8Emu, seed: u64) {
+    (*emu).cpu.set_seed(seed);
+}
+
+/// Instructions run by `c8emu_frame`, at least 1.
+///
+/// # Safety
+/// `emu` must be a live instance from `c8emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_set_ipf(emu: *mut C8Emu, ipf: usize) {
+    (*emu).ipf = ipf.max(1);
+}
+
+/// Execute a single instruction, without ticking the timers.
+///
+/// # Safety
+/// `emu` must be a live instance from `c8emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_step(emu: *mut C8Emu) -> c_int {
+    let emu = &mut *emu;
+    let result = emu.cpu.cpu_exec();
+    emu.check(result)
+}
+
+/// Run one 60 Hz frame: the instructions per frame, then one timer tick.
+///
+/// # Safety
+/// `emu` must be a live instance from `c8emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_frame(emu: *mut C8Emu) -> c_int {
+    let emu = &mut *emu;
+    let result = emu.cpu.run_frame(emu.ipf).map(|_| ());
+    emu.check(result)
+}
+
+/// Press or release keypad key 0x0 - 0xF, failing for any other key.
+///
+/// # Safety
+/// `emu` must be a live instance from `c8emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_set_key(emu: *mut C8Emu, key: u8, pressed: bool) -> c_int {
+    let emu = &mut *emu;
+    if key > 0xF {
+        return emu.fail(&format!("No keypad key {:#X}, expected 0x0 - 0xF", key));
+    }
+    emu.cpu.set_key(key as usize, pressed);
+    C8EMU_OK
+}
+
+/// Set the whole keypad, bit N held for key N.
+///
+/// # Safety
+/// `emu` must be a live instance from `c8emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_set_keys(emu: *mut C8Emu, mask: u16) {
+    (*emu).cpu.set_keypad_mask(mask);
+}
+
+/// Copy the display to `out`, one byte per pixel (0 or 1) row by row. Returns the
+/// bytes needed, `C8EMU_WIDTH * C8EMU_HEIGHT`, and copies nothing if `len` is less.
+///
+/// # Safety
+/// `out` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_framebuffer(emu: *const C8Emu, out: *mut u8, len: usize) -> usize {
+    let size = WIDTH * HEIGHT;
+    if !out.is_null() && len >= size {
+        let out = std::slice::from_raw_parts_mut(out, size);
+        for (byte, &on) in out
+            .iter_mut()
+            .zip((*emu).cpu.get_display().iter().flatten())
+        {
+            *byte = on as u8;
+        }
+    }
+    size
+}
+
+/// # Safety
+/// `out` must point to a `C8Registers`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_registers(emu: *const C8Emu, out: *mut C8Registers) {
+    let state = (*emu).cpu.get_state();
+    *out = C8Registers {
+        v: state.v,
+        i: state.i,
+        pc: state.pc,
+        sp: state.sp,
+        dt: state.dt,
+        st: state.st,
+    };
+}
+
+/// The 4 KiB of memory, valid until the next call that changes the machine. Its
+/// size is stored in `len` when that isn't null.
+///
+/// # Safety
+/// `len` must be null or point to a writable `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_memory(emu: *const C8Emu, len: *mut usize) -> *const u8 {
+    let memory = (*emu).cpu.get_memory();
+    if !len.is_null() {
+        *len = memory.len();
+    }
+    memory.as_ptr()
+}
+
+/// Whether the buzzer is sounding.
+///
+/// # Safety
+/// `emu` must be a live instance from `c8emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_sound_active(emu: *const C8Emu) -> bool {
+    (*emu).cpu.sound_active()
+}
+
+/// Bytes needed by `c8emu_snapshot`, the same for every instance.
+#[no_mangle]
+pub extern "C" fn c8emu_state_size() -> usize {
+    STATE_SIZE
+}
+
+/// Save the whole machine to `out`, which needs `c8emu_state_size()` bytes.
+///
+/// # Safety
+/// `out` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_snapshot(emu: *mut C8Emu, out: *mut u8, len: usize) -> c_int {
+    let emu = &mut *emu;
+    if out.is_null() || len < STATE_SIZE {
+        return emu.fail(&format!("Snapshots need {} bytes", STATE_SIZE));
+    }
+    let state = emu.cpu.save_state();
+    std::ptr::copy_nonoverlapping(state.as_ptr(), out, state.len());
+    C8EMU_OK
+}
+
+/// Restore a machine saved by `c8emu_snapshot`, from any instance.
+///
+/// # Safety
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_restore(emu: *mut C8Emu, data: *const u8, len: usize) -> c_int {
+    let emu = &mut *emu;
+    if data.is_null() {
+        return emu.fail("No snapshot data");
+    }
+    let result = emu.cpu.load_state(std::slice::from_raw_parts(data, len));
+    emu.check(result)
+}
+
+/// Message for the last call that returned `C8EMU_ERROR`, owned by the instance.
+///
+/// # Safety
+/// `emu` must be a live instance from `c8emu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn c8emu_last_error(emu: *const C8Emu) -> *const c_char {
+    (*emu).error.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::path::Path;
+
+    unsafe fn last_error(emu: *const C8Emu) -> String {
+        CStr::from_ptr(c8emu_last_error(emu))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    unsafe fn registers(emu: *const C8Emu) -> C8Registers {
+        let mut regs = C8Registers {
+            v: [0; 16],
+            i: 0,
+            pc: 0,
+            sp: 0,
+            dt: 0,
+            st: 0,
+        };
+        c8emu_registers(emu, &mut regs);
+        regs
+    }
+
+    #[test]
+    fn runs_and_restores_through_the_c_interface() {
+        // Draw font digit 0 at (0, 0), load DT with 5, then spin
+        let rom = [
+            0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x61, 0x05, 0xF1, 0x15, 0x12, 0x0A,
+        ];
+        unsafe {
+            let emu = c8emu_create();
+            assert_eq!(c8emu_load_rom(emu, std::ptr::null(), 0), C8EMU_ERROR);
+            assert_eq!(last_error(emu), "No ROM data");
+            assert_eq!(c8emu_load_rom(emu, rom.as_ptr(), rom.len()), C8EMU_OK);
+            c8emu_set_ipf(emu, 6);
+            assert_eq!(c8emu_frame(emu), C8EMU_OK);
+
+            let mut pixels = vec![0; C8EMU_WIDTH * C8EMU_HEIGHT];
+            assert_eq!(
+                c8emu_framebuffer(emu, pixels.as_mut_ptr(), pixels.len()),
+                pixels.len()
+            );
+            assert_eq!(pixels[..5], [1, 1, 1, 1, 0]);
+            let regs = registers(emu);
+            assert_eq!((regs.pc, regs.v[1], regs.dt), (0x20A, 5, 4));
+
+            let mut state = vec![0; c8emu_state_size()];
+            assert_eq!(c8emu_snapshot(emu, state.as_mut_ptr(), 1), C8EMU_ERROR);
+            assert_eq!(
+                c8emu_snapshot(emu, state.as_mut_ptr(), state.len()),
+                C8EMU_OK
+            );
+            assert_eq!(c8emu_frame(emu), C8EMU_OK);
+            assert_eq!(registers(emu).dt, 3);
+
+            assert_eq!(c8emu_restore(emu, state.as_ptr(), state.len()), C8EMU_OK);
+            assert_eq!(registers(emu).dt, 4);
+            assert_eq!(c8emu_restore(emu, state.as_ptr(), 10), C8EMU_ERROR);
+            assert!(!last_error(emu).is_empty());
+            assert_eq!(registers(emu).dt, 4);
+
+            assert_eq!(c8emu_set_key(emu, 0xF, true), C8EMU_OK);
+            assert_eq!(c8emu_set_key(emu, 0x1F, true), C8EMU_ERROR);
+            assert!(last_error(emu).contains("0x1F"));
+            let memory = c8emu_memory(emu, std::ptr::null_mut());
+            assert_eq!(*memory.add(0x200), 0x60);
+
+            c8emu_destroy(emu);
+            c8emu_destroy(std::ptr::null_mut());
+        }
+    }
+
+    /// The committed header must match what cbindgen makes of this file. Set
+    /// C8EMU_UPDATE_HEADER to rewrite it instead.
+    #[test]
+    fn header_is_up_to_date() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(dir.join("src/lib.rs"))
+            .generate()
+            .unwrap()
+            .write(&mut generated);
+
+        let path = dir.join("include/c8emu.h");
+        if std::env::var_os("C8EMU_UPDATE_HEADER").is_some() {
+            std::fs::write(&path, &generated).unwrap();
+        }
+        let committed = std::fs::read(&path).unwrap();
+        assert!(
+            committed == generated,
+            "include/c8emu.h is out of date, rerun with C8EMU_UPDATE_HEADER=1"
+        );
+    }
+}
@@ -0,0 +1,271 @@
+// Python module `c8emu`, for driving the core from scripts and notebooks
+
+// Raised on the code pyo3 generates for `PyResult` methods
+#![allow(clippy::useless_conversion)]
+
+use c8emu::cpu::{Cpu, CpuError, DEFAULT_IPF, HEIGHT, WIDTH};
+use c8emu::env::{self, EnvError, EnvSpec};
+use c8emu::romfile::RomImage;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use std::path::Path;
+
+fn error(e: CpuError) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+fn env_error(e: EnvError) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+/// A CHIP-8 machine, run a frame or an instruction at a time.
+#[pyclass(unsendable)]
+struct Emulator {
+    cpu: Cpu,
+    ipf: usize,
+}
+
+#[pymethods]
+impl Emulator {
+    #[new]
+    #[pyo3(signature = (seed=None))]
+    fn new(seed: Option<u64>) -> Self {
+        let mut cpu = Cpu::new();
+        if let Some(seed) = seed {
+            cpu.set_seed(seed);
+        }
+        Self {
+            cpu,
+            ipf: DEFAULT_IPF,
+        }
+    }
+
+    /// Load a program and reset, with quirks and speed from the ROM database.
+    fn load_rom(&mut self, rom: &[u8]) -> PyResult<()> {
+        let image = RomImage {
+            bytes: rom.to_vec(),
+            info: None,
+        };
+        let loaded = self.cpu.load_image(image, "ROM data").map_err(error)?;
+        self.ipf = loaded
+            .info
+            .and_then(|info| info.tickrate)
+            .unwrap_or(DEFAULT_IPF);
+        Ok(())
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.cpu.set_seed(seed);
+    }
+
+    /// Instructions run by each frame.
+    #[getter]
+    fn ipf(&self) -> usize {
+        self.ipf
+    }
+
+    #[setter]
+    fn set_ipf(&mut self, ipf: usize) {
+        self.ipf = ipf.max(1);
+    }
+
+    /// Execute a single instruction.
+    fn step(&mut self) -> PyResult<()> {
+        self.cpu.cpu_exec().map_err(error)
+    }
+
+    /// Run `count` 60 Hz frames.
+    #[pyo3(signature = (count=1))]
+    fn frame(&mut self, count: usize) -> PyResult<()> {
+        for _ in 0..count {
+            self.cpu.run_frame(self.ipf).map_err(error)?;
+        }
+        Ok(())
+    }
+
+    /// Press or release keypad key 0x0 - 0xF.
+    fn set_key(&mut self, key: usize, pressed: bool) -> PyResult<()> {
+        if key > 0xF {
+            return Err(PyValueError::new_err(format!(
+                "No keypad key {:#X}, expected 0x0 - 0xF",
+                key
+            )));
+        }
+        self.cpu.set_key(key, pressed);
+        Ok(())
+    }
+
+    /// Set the whole keypad, bit N held for key N.
+    fn set_keys(&mut self, mask: u16) {
+        self.cpu.set_keypad_mask(mask);
+    }
+
+    /// The display as 64 x 32 bytes, 0 or 1, row by row. Reshape with
+    /// `numpy.frombuffer(emu.framebuffer(), numpy.uint8).reshape(32, 64)`.
+    fn framebuffer<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        let display = self.cpu.get_display();
+        PyBytes::new_bound_with(py, WIDTH * HEIGHT, |out| {
+            for (byte, &on) in out.iter_mut().zip(display.iter().flatten()) {
+                *byte = on as u8;
+            }
+            Ok(())
+        })
+        .expect("framebuffer")
+    }
+
+    /// Registers as a dict: v (list of 16), i, pc, sp, dt, st and stack.
+    fn registers<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let state = self.cpu.get_state();
+        let registers = PyDict::new_bound(py);
+        registers.set_item("v", state.v.to_vec())?;
+        registers.set_item("i", state.i)?;
+        registers.set_item("pc", state.pc)?;
+        registers.set_item("sp", state.sp)?;
+        registers.set_item("dt", state.dt)?;
+        registers.set_item("st", state.st)?;
+        registers.set_item("stack", state.stack)?;
+        Ok(registers)
+    }
+
+    fn memory<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, self.cpu.get_memory())
+    }
+
+    fn write_memory(&mut self, addr: usize, data: &[u8]) {
+        for (offset, &byte) in data.iter().enumerate() {
+            self.cpu.set_memory(addr + offset, byte);
+        }
+    }
+
+    fn sound_active(&self) -> bool {
+        self.cpu.sound_active()
+    }
+
+    /// Save the whole machine, for `restore` on this or another emulator.
+    fn snapshot<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.cpu.save_state())
+    }
+
+    fn restore(&mut self, state: &[u8]) -> PyResult<()> {
+        self.cpu.load_state(state).map_err(error)
+    }
+}
+
+/// A game as a reinforcement learning environment, set up by a spec file.
+#[pyclass]
+struct Env {
+    env: env::Env,
+}
+
+#[pymethods]
+impl Env {
+    #[new]
+    #[pyo3(signature = (spec, seed=None))]
+    fn new(spec: &str, seed: Option<u64>) -> PyResult<Self> {
+        let spec = EnvSpec::load(Path::new(spec)).map_err(env_error)?;
+        Ok(Self {
+            env: env::Env::new(&spec, seed.unwrap_or_else(rand::random)),
+        })
+    }
+
+    #[getter]
+    fn action_count(&self) -> usize {
+        self.env.action_count()
+    }
+
+    /// Start an episode and return its first observation.
+    #[pyo3(signature = (seed=None))]
+    fn reset<'py>(&mut self, py: Python<'py>, seed: Option<u64>) -> PyResult<Bound<'py, PyBytes>> {
+        let observation = self.env.reset(seed).map_err(env_error)?;
+        Ok(PyBytes::new_bound(py, observation))
+    }
+
+    /// Returns (observation, reward, done). Observations are 64 x 32 bytes like
+    /// `Emulator.framebuffer`.
+    fn step<'py>(
+        &mut self,
+        py: Python<'py>,
+        action: usize,
+    ) -> PyResult<(Bound<'py, PyBytes>, f64, bool)> {
+        let step = self.env.step(action).map_err(env_error)?;
+        Ok((
+            PyBytes::new_bound(py, step.observation),
+            step.reward,
+            step.done,
+        ))
+    }
+
+    fn memory<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, self.env.cpu().get_memory())
+    }
+}
+
+/// `count` environments stepped together on all cores. Environments whose episode
+/// ends start the next one straight away.
+#[pyclass]
+struct VecEnv {
+    envs: env::VecEnv,
+}
+
+#[pymethods]
+impl VecEnv {
+    #[new]
+    #[pyo3(signature = (spec, count, seed=None, threads=None))]
+    fn new(spec: &str, count: usize, seed: Option<u64>, threads: Option<usize>) -> PyResult<Self> {
+        let spec = EnvSpec::load(Path::new(spec)).map_err(env_error)?;
+        let mut envs = env::VecEnv::new(&spec, count, seed.unwrap_or_else(rand::random));
+        if let Some(threads) = threads {
+            envs.set_threads(threads);
+        }
+        Ok(Self { envs })
+    }
+
+    fn __len__(&self) -> usize {
+        self.envs.len()
+    }
+
+    #[getter]
+    fn action_count(&self) -> usize {
+        self.envs.envs().first().map_or(0, |env| env.action_count())
+    }
+
+    /// Observations of every environment, one after another.
+    fn reset<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let envs = &mut self.envs;
+        let observations = py
+            .allow_threads(|| envs.reset().map(<[u8]>::to_vec))
+            .map_err(env_error)?;
+        Ok(PyBytes::new_bound(py, &observations))
+    }
+
+    /// Returns (observations, rewards, dones) for a list of actions, one per
+    /// environment.
+    fn step<'py>(
+        &mut self,
+        py: Python<'py>,
+        actions: Vec<usize>,
+    ) -> PyResult<(Bound<'py, PyBytes>, Vec<f64>, Vec<bool>)> {
+        let envs = &mut self.envs;
+        let (observations, rewards, dones) = py
+            .allow_threads(|| {
+                envs.step(&actions).map(|step| {
+                    let observations = step.observations.to_vec();
+                    (observations, step.rewards.to_vec(), step.dones.to_vec())
+                })
+            })
+            .map_err(env_error)?;
+        Ok((PyBytes::new_bound(py, &observations), rewards, dones))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "c8emu")]
+fn python_module(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Emulator>()?;
+    module.add_class::<Env>()?;
+    module.add_class::<VecEnv>()?;
+    module.add("WIDTH", WIDTH)?;
+    module.add("HEIGHT", HEIGHT)?;
+    Ok(())
+}
@@ -0,0 +1,222 @@
+//! Minimal libretro frontend for trying the core without RetroArch: runs a ROM for a
+//! number of frames, checks that a save state replays the same way, and prints the
+//! final frame.
+//!
+//! cargo run -p c8emu-libretro --example harness -- <core.so> <rom> [frames] [key=value ...]
+
+use libloading::{Library, Symbol};
+use std::collections::HashMap;
+use std::ffi::{c_char, c_uint, c_void, CStr, CString};
+use std::sync::Mutex;
+
+const SET_PIXEL_FORMAT: c_uint = 10;
+const SET_INPUT_DESCRIPTORS: c_uint = 11;
+const GET_VARIABLE: c_uint = 15;
+const SET_VARIABLES: c_uint = 16;
+const GET_VARIABLE_UPDATE: c_uint = 17;
+const REPLAY_FRAMES: usize = 120; // Frames run twice from a save state
+
+#[repr(C)]
+struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct Variable {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+static OPTIONS: Mutex<Option<HashMap<CString, CString>>> = Mutex::new(None);
+static FRAME: Mutex<(Vec<u32>, usize)> = Mutex::new((Vec::new(), 0)); // Pixels and width
+static SAMPLES: Mutex<(usize, usize)> = Mutex::new((0, 0)); // Total and non-silent frames
+
+unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match cmd {
+        SET_PIXEL_FORMAT | SET_INPUT_DESCRIPTORS => true,
+        SET_VARIABLES => {
+            let mut variable = data as *const Variable;
+            while !(*variable).key.is_null() {
+                let key = CStr::from_ptr((*variable).key).to_string_lossy();
+                let value = CStr::from_ptr((*variable).value).to_string_lossy();
+                println!("option {} = {}", key, value);
+                variable = variable.add(1);
+            }
+            true
+        }
+        GET_VARIABLE => {
+            let variable = &mut *(data as *mut Variable);
+            let options = OPTIONS.lock().unwrap();
+            let value = options
+                .as_ref()
+                .and_then(|options| options.get(CStr::from_ptr(variable.key)));
+            match value {
+                Some(value) => {
+                    variable.value = value.as_ptr(); // Lives as long as OPTIONS
+                    true
+                }
+                None => false,
+            }
+        }
+        GET_VARIABLE_UPDATE => {
+            *(data as *mut bool) = false;
+            true
+        }
+        _ => false,
+    }
+}
+
+unsafe extern "C" fn video(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    if data.is_null() {
+        return; // Frame duplicated
+    }
+    let mut frame = FRAME.lock().unwrap();
+    frame.0.clear();
+    for y in 0..height as usize {
+        let row = (data as *const u8).add(y * pitch) as *const u32;
+        frame
+            .0
+            .extend_from_slice(std::slice::from_raw_parts(row, width as usize));
+    }
+    frame.1 = width as usize;
+}
+
+unsafe extern "C" fn audio_batch(data: *const i16, frames: usize) -> usize {
+    let samples = std::slice::from_raw_parts(data, frames * 2);
+    let mut counts = SAMPLES.lock().unwrap();
+    counts.0 += frames;
+    counts.1 += samples.chunks(2).filter(|s| s[0] != 0).count();
+    frames
+}
+
+unsafe extern "C" fn input_poll() {}
+
+unsafe extern "C" fn input_state(
+    _port: c_uint,
+    _device: c_uint,
+    _index: c_uint,
+    _id: c_uint,
+) -> i16 {
+    0
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (Some(core), Some(rom)) = (args.get(1), args.get(2)) else {
+        eprintln!("usage: harness <core.so> <rom> [frames] [key=value ...]");
+        std::process::exit(2);
+    };
+    let frames: usize = args.get(3).and_then(|n| n.parse().ok()).unwrap_or(60);
+    *OPTIONS.lock().unwrap() = Some(
+        args.iter()
+            .skip(3)
+            .filter_map(|arg| arg.split_once('='))
+            .map(|(k, v)| (CString::new(k).unwrap(), CString::new(v).unwrap()))
+            .collect(),
+    );
+
+    let data = std::fs::read(rom).expect("read ROM");
+    let path = CString::new(rom.as_str()).unwrap();
+    let game = GameInfo {
+        path: path.as_ptr(),
+        data: data.as_ptr() as *const c_void,
+        size: data.len(),
+        meta: std::ptr::null(),
+    };
+
+    unsafe {
+        let lib = Library::new(core).expect("load core");
+        macro_rules! sym {
+            ($name:literal, $ty:ty) => {{
+                let symbol: Symbol<$ty> = lib.get($name.as_bytes()).expect($name);
+                symbol
+            }};
+        }
+
+        sym!(
+            "retro_set_environment",
+            unsafe extern "C" fn(unsafe extern "C" fn(c_uint, *mut c_void) -> bool)
+        )(environment);
+        sym!(
+            "retro_set_video_refresh",
+            unsafe extern "C" fn(unsafe extern "C" fn(*const c_void, c_uint, c_uint, usize))
+        )(video);
+        sym!(
+            "retro_set_audio_sample_batch",
+            unsafe extern "C" fn(unsafe extern "C" fn(*const i16, usize) -> usize)
+        )(audio_batch);
+        sym!(
+            "retro_set_input_poll",
+            unsafe extern "C" fn(unsafe extern "C" fn())
+        )(input_poll);
+        sym!(
+            "retro_set_input_state",
+            unsafe extern "C" fn(unsafe extern "C" fn(c_uint, c_uint, c_uint, c_uint) -> i16)
+        )(input_state);
+        sym!("retro_init", unsafe extern "C" fn())();
+
+        if !sym!(
+            "retro_load_game",
+            unsafe extern "C" fn(*const GameInfo) -> bool
+        )(&game)
+        {
+            eprintln!("retro_load_game failed");
+            std::process::exit(1);
+        }
+        let run = sym!("retro_run", unsafe extern "C" fn());
+        for _ in 0..frames {
+            run();
+        }
+
+        // A state saved now should play out the same way after it is loaded
+        let size = sym!("retro_serialize_size", unsafe extern "C" fn() -> usize)();
+        let mut state = vec![0u8; size];
+        let saved = sym!(
+            "retro_serialize",
+            unsafe extern "C" fn(*mut c_void, usize) -> bool
+        )(state.as_mut_ptr() as *mut c_void, size);
+        for _ in 0..REPLAY_FRAMES {
+            run();
+        }
+        let expected = FRAME.lock().unwrap().0.clone();
+        let loaded = sym!(
+            "retro_unserialize",
+            unsafe extern "C" fn(*const c_void, usize) -> bool
+        )(state.as_ptr() as *const c_void, size);
+        for _ in 0..REPLAY_FRAMES {
+            run();
+        }
+        let replayed = FRAME.lock().unwrap().0 == expected;
+        println!(
+            "save state: {} bytes, saved {}, loaded {}, replay {}",
+            size,
+            saved,
+            loaded,
+            if replayed { "matches" } else { "differs" }
+        );
+
+        let (total, sounding) = *SAMPLES.lock().unwrap();
+        println!("audio: {} sample frames, {} sounding", total, sounding);
+
+        sym!("retro_unload_game", unsafe extern "C" fn())();
+        sym!("retro_deinit", unsafe extern "C" fn())();
+    }
+
+    // The most common colour is taken as the background
+    let frame = FRAME.lock().unwrap();
+    let mut counts = HashMap::new();
+    for &pixel in &frame.0 {
+        *counts.entry(pixel).or_insert(0) += 1;
+    }
+    let background = counts.into_iter().max_by_key(|&(_, n)| n).map(|(c, _)| c);
+    for row in frame.0.chunks(frame.1.max(1)) {
+        let line: String = row
+            .iter()
+            .map(|&p| if Some(p) == background { '.' } else { '#' })
+            .collect();
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,616 @@
+//! libretro core wrapping the c8emu `Cpu`, for RetroArch and other libretro frontends.
+
+mod api;
+
+use api::*;
+use c8emu::cpu::state::STATE_SIZE;
+use c8emu::cpu::{Cpu, Quirks, DEFAULT_IPF, HEIGHT, WIDTH};
+use c8emu::palette::{Palette, Preset, Rgb};
+use c8emu::romdb::{RomDb, RomInfo};
+use c8emu::romfile::{self, RomImage};
+use log::{error, Level, LevelFilter, Log, Metadata, Record};
+use std::ffi::{c_char, c_uint, c_void, CStr, CString};
+use std::sync::{Mutex, MutexGuard};
+
+const FPS: f64 = 60.0;
+const SAMPLE_RATE: usize = 44100;
+const SAMPLES_PER_FRAME: usize = SAMPLE_RATE / 60;
+const TONE_HZ: f32 = 440.0;
+const VOLUME: i16 = 4096; // Square wave amplitude, an eighth of full scale
+
+const QUIRKS_OPTION: &CStr = c"c8emu_quirks";
+const IPS_OPTION: &CStr = c"c8emu_ips";
+const PALETTE_OPTION: &CStr = c"c8emu_palette";
+
+/// Keypad key for each RetroPad button, the d-pad and face buttons laid out like
+/// the 2/4/6/8 and 5 keys most games move and fire with.
+const BUTTONS: [(c_uint, usize, &CStr); 16] = [
+    (RETRO_DEVICE_ID_JOYPAD_UP, 0x2, c"Keypad 2"),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, 0x8, c"Keypad 8"),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, 0x4, c"Keypad 4"),
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, 0x6, c"Keypad 6"),
+    (RETRO_DEVICE_ID_JOYPAD_A, 0x5, c"Keypad 5"),
+    (RETRO_DEVICE_ID_JOYPAD_B, 0x0, c"Keypad 0"),
+    (RETRO_DEVICE_ID_JOYPAD_X, 0x1, c"Keypad 1"),
+    (RETRO_DEVICE_ID_JOYPAD_Y, 0x3, c"Keypad 3"),
+    (RETRO_DEVICE_ID_JOYPAD_L, 0x7, c"Keypad 7"),
+    (RETRO_DEVICE_ID_JOYPAD_R, 0x9, c"Keypad 9"),
+    (RETRO_DEVICE_ID_JOYPAD_L2, 0xA, c"Keypad A"),
+    (RETRO_DEVICE_ID_JOYPAD_R2, 0xB, c"Keypad B"),
+    (RETRO_DEVICE_ID_JOYPAD_L3, 0xC, c"Keypad C"),
+    (RETRO_DEVICE_ID_JOYPAD_R3, 0xD, c"Keypad D"),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, 0xE, c"Keypad E"),
+    (RETRO_DEVICE_ID_JOYPAD_START, 0xF, c"Keypad F"),
+];
+
+// Buttons the ROM database can reassign, by its names for them
+const DB_BUTTONS: [(&str, c_uint); 6] = [
+    ("up", RETRO_DEVICE_ID_JOYPAD_UP),
+    ("down", RETRO_DEVICE_ID_JOYPAD_DOWN),
+    ("left", RETRO_DEVICE_ID_JOYPAD_LEFT),
+    ("right", RETRO_DEVICE_ID_JOYPAD_RIGHT),
+    ("a", RETRO_DEVICE_ID_JOYPAD_A),
+    ("b", RETRO_DEVICE_ID_JOYPAD_B),
+];
+
+/// Callbacks registered by the frontend before the game is loaded. They are copied
+/// out before being called, so a frontend calling back into the core can't deadlock.
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video: Option<VideoRefreshFn>,
+    audio_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+    log: Option<LogPrintfFn>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video: None,
+    audio_batch: None,
+    input_poll: None,
+    input_state: None,
+    log: None,
+});
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+static LOGGER: FrontendLogger = FrontendLogger;
+
+// libretro calls in from one thread at a time, the locks only make that explicit
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn callbacks() -> Callbacks {
+    *lock(&CALLBACKS)
+}
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match callbacks().environment {
+        Some(environment) => unsafe { environment(cmd, data) },
+        None => false,
+    }
+}
+
+/// Sends `log` records to the frontend's log interface, where it shows them to users.
+struct FrontendLogger;
+
+impl Log for FrontendLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        let Some(log) = callbacks().log.filter(|_| self.enabled(record.metadata())) else {
+            return;
+        };
+        let level = match record.level() {
+            Level::Error => RETRO_LOG_ERROR,
+            Level::Warn => RETRO_LOG_WARN,
+            Level::Info => RETRO_LOG_INFO,
+            Level::Debug | Level::Trace => RETRO_LOG_DEBUG,
+        };
+        let message = format!("[c8emu] {}\n", record.args()).replace('\0', "");
+        let message = CString::new(message).unwrap_or_default();
+        unsafe { log(level, c"%s".as_ptr(), message.as_ptr()) };
+    }
+
+    fn flush(&self) {}
+}
+
+/// Current value of a core option, None if the frontend doesn't have one.
+fn option(key: &CStr) -> Option<String> {
+    let mut variable = Variable {
+        key: key.as_ptr(),
+        value: std::ptr::null(),
+    };
+    let found = environment(
+        RETRO_ENVIRONMENT_GET_VARIABLE,
+        &mut variable as *mut Variable as *mut c_void,
+    );
+    if !found || variable.value.is_null() {
+        return None;
+    }
+    let value = unsafe { CStr::from_ptr(variable.value) };
+    Some(value.to_string_lossy().into_owned())
+}
+
+/// The loaded game and everything needed to run it.
+struct Core {
+    cpu: Cpu,
+    rom: Vec<u8>,
+    name: String,
+    info: Option<RomInfo>, // From the cartridge or the ROM database
+    ipf: usize,
+    palette: Palette,
+    buttons: [usize; 16], // Keypad key for each entry in `BUTTONS`
+    faulted: bool,        // Stopped on a CPU fault until reset
+    video: Vec<u32>,
+    audio: Vec<i16>,
+    phase: f32, // Position in the current tone period, 0.0 - 1.0
+}
+
+impl Core {
+    fn load(rom: Vec<u8>, name: String) -> Option<Self> {
+        let image = romfile::decode(&name, rom.clone())
+            .map_err(|e| error!("{}", e))
+            .ok()?;
+        let info = image.info.clone();
+        let mut cpu = Cpu::new();
+        let loaded = cpu
+            .load_image(image, &name)
+            .map_err(|e| error!("{}", e))
+            .ok()?;
+        let info = info.or_else(|| loaded.info.map(|info| *info));
+
+        let mut buttons = BUTTONS.map(|(_, key, _)| key);
+        if let Some(info) = &info {
+            for (name, id) in DB_BUTTONS {
+                let slot = BUTTONS.iter().position(|&(b, _, _)| b == id);
+                if let (Some(&key), Some(slot)) = (info.keys.get(name), slot) {
+                    buttons[slot] = key as usize & 0xF;
+                }
+            }
+        }
+
+        let mut core = Self {
+            cpu,
+            rom,
+            name,
+            info,
+            ipf: DEFAULT_IPF,
+            palette: Preset::Classic.palette(),
+            buttons,
+            faulted: false,
+            video: vec![0; WIDTH * HEIGHT],
+            audio: vec![0; SAMPLES_PER_FRAME * 2],
+            phase: 0.0,
+        };
+        core.apply_options();
+        Some(core)
+    }
+
+    /// Restart the game from power on, with the options currently set.
+    fn reset(&mut self) {
+        let image = RomImage {
+            bytes: self.rom.clone(),
+            info: self.info.clone(),
+        };
+        if let Err(e) = self.cpu.load_image(image, &self.name) {
+            error!("{}", e);
+        }
+        self.faulted = false;
+        self.apply_options();
+    }
+
+    fn apply_options(&mut self) {
+        let quirks = match option(QUIRKS_OPTION).as_deref() {
+            None | Some("auto") => None,
+            Some(platform) => RomDb::get().platform_quirks(platform),
+        };
+        let info = self.info.as_ref();
+        self.cpu.set_quirks(
+            quirks.unwrap_or_else(|| info.map_or(Quirks::default(), |info| info.quirks)),
+        );
+
+        let ips = option(IPS_OPTION).and_then(|ips| ips.parse::<usize>().ok());
+        self.ipf = match ips {
+            Some(ips) => (ips / 60).max(1),
+            None => info.and_then(|info| info.tickrate).unwrap_or(DEFAULT_IPF),
+        };
+
+        let preset = option(PALETTE_OPTION).and_then(|name| Preset::from_name(&name));
+        self.palette = match (preset, info.and_then(|info| info.palette.clone())) {
+            (Some(preset), _) => preset.palette(),
+            (None, Some(palette)) => palette,
+            (None, None) => Preset::Classic.palette(),
+        };
+    }
+
+    fn run(&mut self) {
+        let callbacks = callbacks();
+        if let Some(input_poll) = callbacks.input_poll {
+            unsafe { input_poll() };
+        }
+        if let Some(input_state) = callbacks.input_state {
+            let mut mask = 0u16;
+            for (&(id, _, _), &key) in BUTTONS.iter().zip(&self.buttons) {
+                if unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, id) } != 0 {
+                    mask |= 1 << key;
+                }
+            }
+            self.cpu.set_keypad_mask(mask);
+        }
+
+        if !self.faulted {
+            if let Err(e) = self.cpu.run_frame(self.ipf) {
+                error!("CPU fault: {}", e);
+                self.faulted = true;
+            }
+        }
+
+        let color = |on: bool| {
+            let Rgb(r, g, b) = self.palette.color(on as usize);
+            (r as u32) << 16 | (g as u32) << 8 | b as u32
+        };
+        let display = self.cpu.get_display();
+        for (pixel, &on) in self.video.iter_mut().zip(display.iter().flatten()) {
+            *pixel = color(on);
+        }
+        if let Some(video) = callbacks.video {
+            let data = self.video.as_ptr() as *const c_void;
+            unsafe { video(data, WIDTH as c_uint, HEIGHT as c_uint, WIDTH * 4) };
+        }
+
+        // Square wave while the sound timer runs
+        let sound = self.cpu.sound_active() && !self.faulted;
+        for frame in self.audio.chunks_exact_mut(2) {
+            let sample = match sound {
+                true if self.phase < 0.5 => VOLUME,
+                true => -VOLUME,
+                false => 0,
+            };
+            frame.fill(sample);
+            self.phase = (self.phase + TONE_HZ / SAMPLE_RATE as f32).fract();
+        }
+        if let Some(audio_batch) = callbacks.audio_batch {
+            unsafe { audio_batch(self.audio.as_ptr(), SAMPLES_PER_FRAME) };
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    lock(&CALLBACKS).environment = Some(callback);
+
+    let mut interface = LogCallback { log: None };
+    let has_log = environment(
+        RETRO_ENVIRONMENT_GET_LOG_INTERFACE,
+        &mut interface as *mut LogCallback as *mut c_void,
+    );
+    if has_log && interface.log.is_some() {
+        lock(&CALLBACKS).log = interface.log;
+        // Fails if the process already has a logger, which then gets the messages
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(LevelFilter::Info);
+        }
+    }
+
+    let quirks = format!("Quirks; auto|{}\0", RomDb::get().platform_names().join("|"));
+    let palettes = format!(
+        "Palette; auto|{}\0",
+        Preset::ALL.map(Preset::name).join("|")
+    );
+    let mut variables = [
+        Variable {
+            key: QUIRKS_OPTION.as_ptr(),
+            value: quirks.as_ptr() as *const c_char,
+        },
+        Variable {
+            key: IPS_OPTION.as_ptr(),
+            value: c"Instructions per second; auto|300|500|660|700|1000|1500|2000|3000|5000|10000|30000|60000".as_ptr(),
+        },
+        Variable {
+            key: PALETTE_OPTION.as_ptr(),
+            value: palettes.as_ptr() as *const c_char,
+        },
+        Variable {
+            key: std::ptr::null(),
+            value: std::ptr::null(),
+        },
+    ];
+    environment(
+        RETRO_ENVIRONMENT_SET_VARIABLES,
+        variables.as_mut_ptr() as *mut c_void,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    lock(&CALLBACKS).video = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    lock(&CALLBACKS).audio_batch = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    lock(&CALLBACKS).input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    lock(&CALLBACKS).input_state = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *lock(&CORE) = None;
+}
+
+/// # Safety
+/// `info` must point to a `retro_system_info` to fill in.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: c"c8emu".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: c"ch8|c8|sc8|xo8|gif".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to a `retro_system_av_info` to fill in.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: WIDTH as c_uint,
+            base_height: HEIGHT as c_uint,
+            max_width: WIDTH as c_uint,
+            max_height: HEIGHT as c_uint,
+            aspect_ratio: WIDTH as f32 / HEIGHT as f32,
+        },
+        timing: SystemTiming {
+            fps: FPS,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = lock(&CORE).as_mut() {
+        core.reset();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    if let Some(core) = lock(&CORE).as_mut() {
+        if option_updated() {
+            core.apply_options();
+        }
+        core.run();
+    }
+}
+
+fn option_updated() -> bool {
+    let mut updated = false;
+    environment(
+        RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE,
+        &mut updated as *mut bool as *mut c_void,
+    ) && updated
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    STATE_SIZE
+}
+
+/// # Safety
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let mut core = lock(&CORE);
+    let Some(core) = core.as_mut() else {
+        return false;
+    };
+    if size < STATE_SIZE {
+        return false;
+    }
+    let state = core.cpu.save_state();
+    std::ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+    true
+}
+
+/// # Safety
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut core = lock(&CORE);
+    let Some(core) = core.as_mut() else {
+        return false;
+    };
+    let state = std::slice::from_raw_parts(data as *const u8, size.min(STATE_SIZE));
+    match core.cpu.load_state(state) {
+        Ok(()) => {
+            core.faulted = false;
+            true
+        }
+        Err(e) => {
+            error!("{}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+/// `game` must be null or point to a `retro_game_info` whose data is `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    let Some(game) = game.as_ref() else {
+        return false;
+    };
+    if game.data.is_null() {
+        return false;
+    }
+    let rom = std::slice::from_raw_parts(game.data as *const u8, game.size).to_vec();
+    // The path only tells cartridges apart from raw programs, by extension
+    let name = match game.path.is_null() {
+        true => String::from("rom.ch8"),
+        false => CStr::from_ptr(game.path).to_string_lossy().into_owned(),
+    };
+
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(
+        RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+        &mut format as *mut c_uint as *mut c_void,
+    ) {
+        error!("Frontend doesn't support XRGB8888 video");
+        return false;
+    }
+
+    let mut descriptors: Vec<InputDescriptor> = BUTTONS
+        .iter()
+        .map(|&(id, _, description)| InputDescriptor {
+            port: 0,
+            device: RETRO_DEVICE_JOYPAD,
+            index: 0,
+            id,
+            description: description.as_ptr(),
+        })
+        .collect();
+    descriptors.push(InputDescriptor {
+        port: 0,
+        device: 0,
+        index: 0,
+        id: 0,
+        description: std::ptr::null(),
+    });
+    environment(
+        RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS,
+        descriptors.as_mut_ptr() as *mut c_void,
+    );
+
+    let core = Core::load(rom, name);
+    let loaded = core.is_some();
+    *lock(&CORE) = core;
+    loaded
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const GameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *lock(&CORE) = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+/// Main memory, for cheats and achievements.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match (id, lock(&CORE).as_mut()) {
+        (RETRO_MEMORY_SYSTEM_RAM, Some(core)) => core.cpu.memory_mut().as_mut_ptr() as *mut c_void,
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match (id, lock(&CORE).as_ref()) {
+        (RETRO_MEMORY_SYSTEM_RAM, Some(core)) => core.cpu.get_memory().len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = include_bytes!("../../roms/test_opcode.ch8");
+
+    /// Accepts the pixel format and nothing else, like a frontend with no options set.
+    unsafe extern "C" fn environment(cmd: c_uint, _data: *mut c_void) -> bool {
+        cmd == RETRO_ENVIRONMENT_SET_PIXEL_FORMAT
+    }
+
+    fn state_hash() -> u64 {
+        lock(&CORE).as_ref().unwrap().cpu.state_hash()
+    }
+
+    #[test]
+    fn serialize_round_trips() {
+        retro_set_environment(environment);
+        let game = GameInfo {
+            path: c"test_opcode.ch8".as_ptr(),
+            data: ROM.as_ptr() as *const c_void,
+            size: ROM.len(),
+            meta: std::ptr::null(),
+        };
+        assert!(unsafe { retro_load_game(&game) });
+        for _ in 0..10 {
+            retro_run();
+        }
+
+        let mut state = vec![0u8; retro_serialize_size()];
+        let data = state.as_mut_ptr() as *mut c_void;
+        assert!(unsafe { retro_serialize(data, state.len()) });
+        let saved = state_hash();
+        for _ in 0..10 {
+            retro_run();
+        }
+        let later = state_hash();
+        assert_ne!(later, saved);
+
+        // Loading goes back to the save, and the same frames follow
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+        assert_eq!(state_hash(), saved);
+        for _ in 0..10 {
+            retro_run();
+        }
+        assert_eq!(state_hash(), later);
+
+        // A corrupted state is refused and leaves the game running as it was
+        let mut corrupt = state.clone();
+        corrupt[0] ^= 0xFF;
+        assert!(!unsafe { retro_unserialize(corrupt.as_ptr() as *const c_void, corrupt.len()) });
+        assert_eq!(state_hash(), later);
+        retro_unload_game();
+    }
+}
@@ -0,0 +1,176 @@
+use crate::cpu::Quirks;
+use crate::palette::Preset;
+use crate::romdb::RomDb;
+use std::num::{NonZeroU16, NonZeroUsize};
+use thiserror::Error;
+
+pub const USAGE: &str = "usage: c8emu [rom] [--quirks PLATFORM] [--ips N] [--scale N] \
+[--palette NAME] [--fullscreen] [--paused] [--remote PORT]
+       c8emu record|replay|screenshot|tracediff|tui ...";
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum ArgsError {
+    #[error("{}", USAGE)]
+    Usage,
+    #[error("Unknown platform '{name}', expected one of: {}", names.join(", "))]
+    QuirksError { name: String, names: Vec<String> },
+    #[error("Unknown palette '{name}', expected one of: {}", Preset::ALL.map(Preset::name).join(", "))]
+    PaletteError { name: String },
+    #[error("{option} applies to a ROM, give one to load")]
+    NoRomError { option: String },
+}
+
+/// Settings for the GUI given on the command line, applied to the ROM it names.
+#[derive(Debug, Default)]
+pub struct LaunchArgs {
+    pub rom: Option<String>,
+    pub quirks: Option<Quirks>, // Over the config file and ROM database
+    pub ips: Option<usize>,     // Instructions per second, over the ROM's tickrate
+    pub scale: Option<u16>,     // Screen pixels per CHIP-8 pixel
+    pub palette: Option<Preset>,
+    pub fullscreen: bool,
+    pub paused: bool,
+    pub remote: Option<u16>, // Port for remote control on localhost
+}
+
+impl LaunchArgs {
+    pub fn parse(args: &[String]) -> Result<Self, ArgsError> {
+        let mut launch = Self::default();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--quirks" => {
+                    let name = iter.next().ok_or(ArgsError::Usage)?;
+                    let db = RomDb::get();
+                    let quirks =
+                        db.platform_quirks(name)
+                            .ok_or_else(|| ArgsError::QuirksError {
+                                name: name.to_string(),
+                                names: db.platform_names().into_iter().map(String::from).collect(),
+                            })?;
+                    launch.quirks = Some(quirks);
+                }
+                "--ips" => launch.ips = Some(parse_next::<NonZeroUsize>(&mut iter)?.get()),
+                "--scale" => launch.scale = Some(parse_next::<NonZeroU16>(&mut iter)?.get()),
+                "--palette" => {
+                    let name = iter.next().ok_or(ArgsError::Usage)?;
+                    let preset =
+                        Preset::from_name(name).ok_or_else(|| ArgsError::PaletteError {
+                            name: name.to_string(),
+                        })?;
+                    launch.palette = Some(preset);
+                }
+                "--fullscreen" => launch.fullscreen = true,
+                "--paused" => launch.paused = true,
+                "--remote" => launch.remote = Some(parse_next::<NonZeroU16>(&mut iter)?.get()),
+                _ if arg.starts_with('-') || launch.rom.is_some() => return Err(ArgsError::Usage),
+                _ => launch.rom = Some(arg.to_string()),
+            }
+        }
+
+        if launch.rom.is_none() {
+            let needs_rom = [
+                ("--quirks", launch.quirks.is_some()),
+                ("--ips", launch.ips.is_some()),
+                ("--paused", launch.paused),
+            ];
+            if let Some((option, _)) = needs_rom.iter().find(|(_, given)| *given) {
+                return Err(ArgsError::NoRomError {
+                    option: option.to_string(),
+                });
+            }
+        }
+        Ok(launch)
+    }
+
+    /// Instructions per frame for the `--ips` setting, at 60 frames per second.
+    pub fn ipf(&self) -> Option<usize> {
+        self.ips
+            .map(|ips| (ips as f64 / 60.0).round().max(1.0) as usize)
+    }
+}
+
+fn parse_next<'a, T: std::str::FromStr>(
+    iter: &mut impl Iterator<Item = &'a String>,
+) -> Result<T, ArgsError> {
+    iter.next()
+        .and_then(|n| n.parse().ok())
+        .ok_or(ArgsError::Usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<LaunchArgs, ArgsError> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        LaunchArgs::parse(&args)
+    }
+
+    #[test]
+    fn parses_launch_options() {
+        let launch = parse(&[
+            "pong.ch8",
+            "--quirks",
+            "vip",
+            "--ips",
+            "1000",
+            "--scale",
+            "10",
+            "--palette",
+            "amber",
+            "--fullscreen",
+            "--paused",
+            "--remote",
+            "8080",
+        ])
+        .unwrap();
+        assert_eq!(launch.rom.as_deref(), Some("pong.ch8"));
+        assert_eq!(launch.quirks, RomDb::get().platform_quirks("originalChip8"));
+        assert_eq!((launch.ips, launch.ipf()), (Some(1000), Some(17)));
+        assert_eq!(launch.scale, Some(10));
+        assert_eq!(launch.palette, Some(Preset::Amber));
+        assert!(launch.fullscreen && launch.paused);
+        assert_eq!(launch.remote, Some(8080));
+
+        let launch = parse(&[]).unwrap();
+        assert!(launch.rom.is_none() && launch.quirks.is_none());
+        // Display options apply to the window, so no ROM is needed
+        let launch = parse(&["--scale", "4", "--palette", "lcd", "--fullscreen"]).unwrap();
+        assert_eq!((launch.scale, launch.palette), (Some(4), Some(Preset::Lcd)));
+        assert_eq!(parse(&["x.ch8", "--ips", "1"]).unwrap().ipf(), Some(1));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        for (args, expected) in [
+            (&["--quirks", "vip"][..], "NoRomError"),
+            (&["--ips", "600"], "NoRomError"),
+            (&["--paused"], "NoRomError"),
+            (&["x.ch8", "--quirks", "amiga"], "QuirksError"),
+            (&["x.ch8", "--palette", "pico16"], "PaletteError"),
+            (&["x.ch8", "--ips", "0"], "Usage"),
+            (&["x.ch8", "--ips", "fast"], "Usage"),
+            (&["x.ch8", "--scale", "0"], "Usage"),
+            (&["x.ch8", "--scale", "70000"], "Usage"),
+            (&["x.ch8", "--remote", "0"], "Usage"),
+            (&["x.ch8", "--turbo"], "Usage"),
+            (&["x.ch8", "y.ch8"], "Usage"),
+            (&["x.ch8", "--quirks"], "Usage"),
+            (&["x.ch8", "--ips"], "Usage"),
+            (&["x.ch8", "--scale"], "Usage"),
+            (&["x.ch8", "--palette"], "Usage"),
+            (&["x.ch8", "--remote"], "Usage"),
+        ] {
+            let err = parse(args).unwrap_err();
+            assert!(
+                format!("{:?}", err).starts_with(expected),
+                "{:?} gave {:?}",
+                args,
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use crate::config::{AudioConfig, AudioOutput};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+use std::fs::File;
+use std::io::BufWriter;
+use thiserror::Error;
+
+const FRAME_HZ: u32 = 60; // Sound timer rate, one `AudioBackend::frame` per tick
+const RAMP_SECS: f32 = 0.005; // Fade in/out time to avoid clicks on start and stop
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum AudioError {
+    #[error("Failed to create WAV file '{path}': {err}")]
+    WavCreateError { path: String, err: hound::Error },
+    #[error("Failed to write WAV file: {err}")]
+    WavWriteError { err: hound::Error },
+    #[error("Host audio unavailable: {msg}")]
+    HostError { msg: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tone {
+    pub frequency: f32, // Hz
+    pub volume: f32,    // 0.0 - 1.0
+    pub waveform: Waveform,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            frequency: 440.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+/// Tone generator that ramps its gain towards on/off, so the output never jumps.
+pub struct Beeper {
+    tone: Tone,
+    sample_rate: u32,
+    phase: f32, // 0.0 - 1.0 through the current cycle
+    gain: f32,  // Current envelope level, 0.0 - 1.0
+}
+
+impl Beeper {
+    pub fn new(tone: Tone, sample_rate: u32) -> Self {
+        Self {
+            tone,
+            sample_rate,
+            phase: 0.0,
+            gain: 0.0,
+        }
+    }
+
+    pub fn next_sample(&mut self, active: bool) -> f32 {
+        let step = 1.0 / (RAMP_SECS * self.sample_rate as f32);
+        self.gain = if active {
+            (self.gain + step).min(1.0)
+        } else {
+            (self.gain - step).max(0.0)
+        };
+
+        if self.gain == 0.0 {
+            self.phase = 0.0; // Restart each beep on a zero crossing
+            return 0.0;
+        }
+
+        let wave = match self.tone.waveform {
+            Waveform::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * self.phase - 1.0,
+            Waveform::Sine => (self.phase * TAU).sin(),
+        };
+
+        self.phase = (self.phase + self.tone.frequency / self.sample_rate as f32).fract();
+        wave * self.gain * self.tone.volume.clamp(0.0, 1.0)
+    }
+
+    /// Samples for one 60 Hz frame.
+    pub fn frame(&mut self, active: bool) -> Vec<f32> {
+        (0..self.sample_rate / FRAME_HZ)
+            .map(|_| self.next_sample(active))
+            .collect()
+    }
+}
+
+/// Sound output driven once per 60 Hz frame with the state of the sound timer.
+pub trait AudioBackend {
+    fn frame(&mut self, active: bool);
+}
+
+/// Discards all sound.
+pub struct NullAudio;
+
+impl AudioBackend for NullAudio {
+    fn frame(&mut self, _active: bool) {}
+}
+
+/// Renders sound to a 16-bit mono WAV file, for listening to output on headless machines.
+pub struct WavAudio {
+    beeper: Beeper,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+}
+
+impl WavAudio {
+    pub const SAMPLE_RATE: u32 = 44100;
+
+    pub fn create(path: &str, tone: Tone) -> Result<Self, AudioError> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: Self::SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let writer =
+            hound::WavWriter::create(path, spec).map_err(|e| AudioError::WavCreateError {
+                path: path.to_string(),
+                err: e,
+            })?;
+        info!("Writing audio to '{}'", path);
+
+        Ok(Self {
+            beeper: Beeper::new(tone, Self::SAMPLE_RATE),
+            writer: Some(writer),
+        })
+    }
+
+    pub fn finalize(&mut self) -> Result<(), AudioError> {
+        match self.writer.take() {
+            Some(writer) => writer
+                .finalize()
+                .map_err(|e| AudioError::WavWriteError { err: e }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AudioBackend for WavAudio {
+    fn frame(&mut self, active: bool) {
+        let samples = self.beeper.frame(active);
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        for sample in samples {
+            if let Err(e) = writer.write_sample((sample * i16::MAX as f32) as i16) {
+                error!("{}", AudioError::WavWriteError { err: e });
+                self.writer = None;
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for WavAudio {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Open the configured backend, falling back to silence if it can't be started.
+pub fn open(config: &AudioConfig) -> Box<dyn AudioBackend> {
+    let backend: Result<Box<dyn AudioBackend>, AudioError> = match config.output {
+        AudioOutput::Null => Ok(Box::new(NullAudio)),
+        AudioOutput::Wav => WavAudio::create(&config.wav_path, config.tone)
+            .map(|wav| Box::new(wav) as Box<dyn AudioBackend>),
+        #[cfg(feature = "host-audio")]
+        AudioOutput::Host => {
+            HostAudio::open(config.tone).map(|host| Box::new(host) as Box<dyn AudioBackend>)
+        }
+        #[cfg(not(feature = "host-audio"))]
+        AudioOutput::Host => Err(AudioError::HostError {
+            msg: String::from("built without the `host-audio` feature"),
+        }),
+    };
+
+    backend.unwrap_or_else(|e| {
+        error!("{}", e);
+        Box::new(NullAudio)
+    })
+}
+
+#[cfg(feature = "host-audio")]
+pub use host::HostAudio;
+
+#[cfg(feature = "host-audio")]
+mod host {
+    use super::{AudioBackend, AudioError, Beeper, Tone};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{FromSample, SizedSample};
+    use log::error;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Plays sound on the default output device. The tone is generated in the device
+    /// callback, so only the on/off state crosses threads each frame.
+    pub struct HostAudio {
+        active: Arc<AtomicBool>,
+        _stream: cpal::Stream,
+    }
+
+    impl HostAudio {
+        pub fn open(tone: Tone) -> Result<Self, AudioError> {
+            let err = |msg: String| AudioError::HostError { msg };
+
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| err(String::from("no output device")))?;
+            let supported = device
+                .default_output_config()
+                .map_err(|e| err(e.to_string()))?;
+
+            let active = Arc::new(AtomicBool::new(false));
+            let config = supported.config();
+
+            let stream = match supported.sample_format() {
+                cpal::SampleFormat::F32 => Self::build::<f32>(&device, &config, tone, &active),
+                cpal::SampleFormat::I16 => Self::build::<i16>(&device, &config, tone, &active),
+                cpal::SampleFormat::U16 => Self::build::<u16>(&device, &config, tone, &active),
+                format => return Err(err(format!("unsupported sample format {}", format))),
+            }
+            .map_err(|e| err(e.to_string()))?;
+            stream.play().map_err(|e| err(e.to_string()))?;
+
+            Ok(Self {
+                active,
+                _stream: stream,
+            })
+        }
+
+        fn build<T: SizedSample + FromSample<f32>>(
+            device: &cpal::Device,
+            config: &cpal::StreamConfig,
+            tone: Tone,
+            active: &Arc<AtomicBool>,
+        ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+            let channels = config.channels as usize;
+            let mut beeper = Beeper::new(tone, config.sample_rate.0);
+            let active = Arc::clone(active);
+
+            device.build_output_stream(
+                config,
+                move |data: &mut [T], _| {
+                    let on = active.load(Ordering::Relaxed);
+                    for frame in data.chunks_mut(channels) {
+                        frame.fill(T::from_sample(beeper.next_sample(on)));
+                    }
+                },
+                |e| error!("Audio stream error: {}", e),
+                None,
+            )
+        }
+    }
+
+    impl AudioBackend for HostAudio {
+        fn frame(&mut self, active: bool) {
+            self.active.store(active, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Cpu, Register};
+
+    #[test]
+    fn wav_follows_sound_timer_with_ramps() {
+        let path = std::env::temp_dir().join(format!("c8emu-audio-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // Sound for 10 frames, then run on in silence
+        let mut cpu = Cpu::new();
+        cpu.set_register(Register::St, 10);
+        let mut wav = WavAudio::create(path, Tone::default()).unwrap();
+        for _ in 0..20 {
+            wav.frame(cpu.sound_active());
+            cpu.tick_timers();
+        }
+        wav.finalize().unwrap();
+
+        let samples: Vec<i16> = hound::WavReader::open(path)
+            .unwrap()
+            .samples::<i16>()
+            .map(Result::unwrap)
+            .collect();
+        std::fs::remove_file(path).unwrap();
+
+        let per_frame = (WavAudio::SAMPLE_RATE / FRAME_HZ) as usize;
+        let ramp = (RAMP_SECS * WavAudio::SAMPLE_RATE as f32).ceil() as usize;
+        let full = (Tone::default().volume * i16::MAX as f32) as i16;
+        let end = 10 * per_frame;
+        assert_eq!(samples.len(), 20 * per_frame);
+
+        // Fade in over 5 ms, then a square wave at full volume
+        let rise: Vec<i16> = samples[..ramp].iter().map(|s| s.abs()).collect();
+        assert!(rise[0] > 0 && rise[0] < full / 100);
+        assert!(rise.windows(2).all(|w| w[0] <= w[1]));
+        assert!(samples[ramp + 1..end].iter().all(|s| s.abs() == full));
+
+        // Fade out over 5 ms once the timer reaches 0, then silence
+        let fall: Vec<i16> = samples[end..end + ramp].iter().map(|s| s.abs()).collect();
+        assert!(fall[0] < full && fall[0] > full - full / 100);
+        assert!(fall.windows(2).all(|w| w[0] >= w[1]));
+        assert!(samples[end + ramp + 1..].iter().all(|&s| s == 0));
+    }
+}
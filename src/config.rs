@@ -0,0 +1,337 @@
+use crate::audio::Tone;
+use crate::cpu::Quirks;
+use crate::keymap::{KeyMap, DEFAULT_KEY_MAP};
+use crate::palette::{Palette, Preset, Rgb};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const CONFIG_FILE: &str = "config.toml";
+const MAX_RECENT: usize = 10; // ROMs kept in the recently used list
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum ConfigError {
+    #[error("Failed to read config file '{path}': {err}")]
+    ReadError { path: PathBuf, err: std::io::Error },
+    #[error("Malformed config file '{path}': {err}")]
+    ParseError { path: PathBuf, err: toml::de::Error },
+    #[error("Failed to write config file '{path}': {err}")]
+    WriteError { path: PathBuf, err: std::io::Error },
+    #[error("Failed to serialize config: {err}")]
+    SerializeError { err: toml::ser::Error },
+    #[error("No config directory on this platform")]
+    NoPathError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioOutput {
+    Host, // Default output device (`host-audio` feature)
+    Wav,  // Write to `wav_path`
+    Null, // No sound
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub output: AudioOutput,
+    pub wav_path: String,
+    #[serde(flatten)]
+    pub tone: Tone,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            output: if cfg!(feature = "host-audio") {
+                AudioOutput::Host
+            } else {
+                AudioOutput::Null
+            },
+            wav_path: String::from("c8emu.wav"),
+            tone: Tone::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub palette: Preset,
+    pub colors: Vec<Rgb>, // Custom palette, replaces the preset when set
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            palette: Preset::Classic,
+            colors: Vec::new(),
+        }
+    }
+}
+
+impl DisplayConfig {
+    pub fn palette(&self) -> Palette {
+        if self.colors.len() >= 2 {
+            Palette {
+                colors: self.colors.clone(),
+            }
+        } else {
+            self.palette.palette()
+        }
+    }
+
+    /// The selected preset, or None when custom colours replace it.
+    pub fn preset(&self) -> Option<Preset> {
+        (self.colors.len() < 2).then_some(self.palette)
+    }
+}
+
+/// Main window size, saved when the window closes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 1024.0,
+            height: 768.0,
+        }
+    }
+}
+
+/// Settings for a single ROM, overriding the top-level values when present.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RomConfig {
+    pub key_map: Option<String>,
+    pub quirks: Option<Quirks>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub key_map: String,
+    pub speed: u32,              // Percent of normal speed
+    pub recent: Vec<String>,     // Recently loaded ROMs, newest first
+    pub library: Option<String>, // Directory shown in the ROM library
+    pub quirks: Quirks,
+    pub audio: AudioConfig,
+    pub display: DisplayConfig,
+    pub window: WindowConfig,
+    pub roms: HashMap<String, RomConfig>, // Keyed by SHA-1, or by file name in older files
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            key_map: String::from(DEFAULT_KEY_MAP),
+            speed: 100,
+            recent: Vec::new(),
+            library: None,
+            quirks: Quirks::default(),
+            audio: AudioConfig::default(),
+            display: DisplayConfig::default(),
+            window: WindowConfig::default(),
+            roms: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/c8emu/config.toml` (or the platform equivalent).
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("c8emu").join(CONFIG_FILE))
+    }
+
+    /// Load the config file, falling back to defaults when it doesn't exist.
+    pub fn load() -> Result<Self, ConfigError> {
+        match Self::path() {
+            Some(path) if path.exists() => Self::load_from(&path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::ReadError {
+            path: path.to_path_buf(),
+            err: e,
+        })?;
+        let config = toml::from_str(&text).map_err(|e| ConfigError::ParseError {
+            path: path.to_path_buf(),
+            err: e,
+        })?;
+        info!("Loaded config from '{}'", path.display());
+        Ok(config)
+    }
+
+    /// Write the config back to `Config::path`, creating the directory if needed.
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let path = Self::path().ok_or(ConfigError::NoPathError)?;
+        self.save_to(&path)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<(), ConfigError> {
+        let text =
+            toml::to_string_pretty(self).map_err(|e| ConfigError::SerializeError { err: e })?;
+        let write = |path: &Path| -> std::io::Result<()> {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, text)
+        };
+        write(path).map_err(|e| ConfigError::WriteError {
+            path: path.to_path_buf(),
+            err: e,
+        })?;
+        info!("Saved config to '{}'", path.display());
+        Ok(())
+    }
+
+    /// Move a ROM to the front of the recently used list.
+    pub fn add_recent(&mut self, rom_path: &str) {
+        self.recent.retain(|path| path != rom_path);
+        self.recent.insert(0, rom_path.to_string());
+        self.recent.truncate(MAX_RECENT);
+    }
+
+    /// Override section for a ROM, found by the SHA-1 of its program or, in config
+    /// files from before sections were keyed by hash, by its file name.
+    pub fn rom(&self, sha1: &str, rom_path: &str) -> Option<&RomConfig> {
+        self.roms
+            .get(sha1)
+            .or_else(|| self.roms.get(file_name(rom_path)?))
+    }
+
+    /// Override section for a ROM to change, moving one keyed by file name over to
+    /// its SHA-1.
+    pub fn rom_mut(&mut self, sha1: &str, rom_path: &str) -> &mut RomConfig {
+        let legacy = if self.roms.contains_key(sha1) {
+            None
+        } else {
+            file_name(rom_path).and_then(|name| self.roms.remove(name))
+        };
+        self.roms
+            .entry(sha1.to_string())
+            .or_insert_with(|| legacy.unwrap_or_default())
+    }
+
+    /// Key map for a ROM, using its override section if it has one.
+    pub fn key_map(&self, rom: Option<&RomConfig>) -> KeyMap {
+        let spec = rom
+            .and_then(|rom| rom.key_map.as_deref())
+            .unwrap_or(&self.key_map);
+
+        KeyMap::parse(spec).unwrap_or_else(|e| {
+            error!("Invalid key map '{}': {}", spec, e);
+            KeyMap::default()
+        })
+    }
+}
+
+fn file_name(rom_path: &str) -> Option<&str> {
+    Path::new(rom_path).file_name()?.to_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scratch path for a test's config file.
+    fn scratch(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("c8emu-{}-{}.toml", name, std::process::id()))
+    }
+
+    fn load_text(name: &str, text: &str) -> Result<Config, ConfigError> {
+        let path = scratch(name);
+        fs::write(&path, text).unwrap();
+        let config = Config::load_from(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn malformed_files_name_the_file() {
+        for (name, text, detail) in [
+            ("malformed", "speed = [100", "speed"),
+            ("unknown-key", "speed = 100\nturbo = true\n", "turbo"),
+            ("bad-palette", "[display]\npalette = \"pico16\"\n", "pico16"),
+        ] {
+            let err = load_text(name, text).unwrap_err();
+            assert!(matches!(err, ConfigError::ParseError { .. }), "{}", name);
+            let msg = err.to_string();
+            assert!(msg.contains(scratch(name).to_str().unwrap()), "{}", msg);
+            assert!(msg.contains(detail), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn saved_files_load_back() {
+        let mut config = Config {
+            speed: 250,
+            library: Some(String::from("/roms")),
+            quirks: Quirks {
+                shift: true,
+                ..Quirks::default()
+            },
+            ..Config::default()
+        };
+        config.display.palette = Preset::Amber;
+        config.add_recent("pong.ch8");
+        config.rom_mut("0123abcd", "tetris.ch8").key_map = Some(String::from("1234"));
+
+        let path = scratch("round-trip");
+        config.save_to(&path).unwrap();
+        let loaded = Config::load_from(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.speed, 250);
+        assert_eq!(loaded.library.as_deref(), Some("/roms"));
+        assert_eq!(loaded.quirks, config.quirks);
+        assert_eq!(loaded.display.palette, Preset::Amber);
+        assert_eq!(loaded.recent, ["pong.ch8"]);
+        let rom = loaded.rom("0123abcd", "elsewhere.ch8").unwrap();
+        assert_eq!(rom.key_map.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn rom_sections_are_found_by_hash_or_legacy_name() {
+        let mut config = load_text(
+            "roms",
+            "[roms.0123abcd]\n\
+             key_map = \"1234\"\n\
+             [roms.\"pong.ch8\"]\n\
+             key_map = \"5678\"\n",
+        )
+        .unwrap();
+
+        let by_hash = config.rom("0123abcd", "/roms/renamed.ch8").unwrap();
+        assert_eq!(by_hash.key_map.as_deref(), Some("1234"));
+        let by_name = config.rom("ffff0000", "/roms/pong.ch8").unwrap();
+        assert_eq!(by_name.key_map.as_deref(), Some("5678"));
+        assert!(config.rom("ffff0000", "/roms/other.ch8").is_none());
+
+        // Changing a legacy section moves it over to the hash
+        config.rom_mut("ffff0000", "/roms/pong.ch8").quirks = Some(Quirks::default());
+        assert!(!config.roms.contains_key("pong.ch8"));
+        let moved = config.rom("ffff0000", "/elsewhere/x.ch8").unwrap();
+        assert_eq!(moved.key_map.as_deref(), Some("5678"));
+        assert_eq!(moved.quirks, Some(Quirks::default()));
+
+        // A hashed section wins over a legacy one with the same file name
+        config
+            .roms
+            .insert(String::from("tetris.ch8"), RomConfig::default());
+        config.rom_mut("0123abcd", "/roms/tetris.ch8");
+        assert!(config.roms.contains_key("tetris.ch8"));
+    }
+}
@@ -1,21 +1,64 @@
-use log::{debug, error, info, warn};
+pub mod disasm;
+pub mod rng;
+pub mod state;
+pub mod trace;
+
+use crate::cpu::rng::SplitMix64;
+use crate::cpu::trace::{Registers, TraceRecord, Tracer};
+use crate::romdb::{self, RomDb, RomInfo};
+use crate::romfile::{self, RomFileError, RomImage};
+use log::{debug, info, trace, warn};
 use rand::Rng;
-use std::fs::File;
-use std::io::Read;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 const BASE: usize = 0x200; // RAM (512) Base Program Memory
 const END: usize = 0x1000; // RAM (4096) Memory End
+const STACK_SIZE: usize = 16; // Maximum nested subroutine calls
+const FONT_BASE: usize = 0x050; // Built-in hex digit sprites, 5 bytes each
+pub const WIDTH: usize = 64; // Display width (pixels)
+pub const HEIGHT: usize = 32; // Display height (pixels)
+pub const DEFAULT_IPF: usize = 11; // Instructions per frame, ~660 per second
+
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum CpuError {
     // Load ROM Errors
-    #[error("Failed to open CHIP-8 ROM file: {err}")]
-    RomOpenError { err: std::io::Error },
-    #[error("Failed to read CHIP-8 ROM file: {err}")]
-    RomReadError { err: std::io::Error },
+    #[error("{err}")]
+    RomFileError { err: RomFileError },
     #[error("CHIP-8 ROM too large for memory. Expected <= {max}, got {actual} bytes")]
     RomSizeError { max: usize, actual: usize },
+    #[error("Invalid save state: {msg}")]
+    StateError { msg: String },
+    // Trace Errors
+    #[error("Failed to open trace output: {err}")]
+    TraceOpenError { err: std::io::Error },
+    // Execution Faults
+    #[error("Stack overflow at {pc:03X}: more than {max} nested calls")]
+    StackOverflow { pc: u16, max: usize },
+    #[error("Stack underflow at {pc:03X}: RET with empty stack")]
+    StackUnderflow { pc: u16 },
+    #[error("Program counter out of range: {pc:03X}")]
+    PcOutOfRange { pc: u16 },
 }
 
 pub struct Cpu {
@@ -30,15 +73,78 @@ pub struct Cpu {
     st: u8,                    // Sound Timer
     keypad: [bool; 16],        // Input Keypad
     display: [[bool; 64]; 32], // Display Buffer
+    display_changed: bool,     // Display written since the frame started
+    cycles: u64,               // Instructions executed since reset
+    frames: u64,               // Frames run since reset
+    quirks: Quirks,            // Behaviour differences between interpreters
+    default_quirks: Quirks,    // Quirks for ROMs the database doesn't know
+    seed: u64,                 // RNG seed, fixed for deterministic replays
+    rng: SplitMix64,           // Source for RND
+    rom_hash: u64,             // Hash of the loaded ROM
+    vblank_wait: bool,         // DRW with the vblank quirk, ends the frame
+    key_wait: Option<u8>,      // Key pressed during Fx0A, waiting for its release
+    tracer: Option<Tracer>,    // Optional per-instruction trace output
 }
 
+/// Opcode behaviours that differ between CHIP-8 interpreters. The defaults follow
+/// the COSMAC VIP, apart from the VF reset in `logic` and the display wait in `vblank`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quirks {
+    pub shift: bool,       // 8xy6/8xyE shift Vx in place, ignoring Vy
+    pub load_store: bool,  // Fx55/Fx65 leave I unchanged
+    pub increment_x: bool, // Fx55/Fx65 advance I by X rather than X + 1
+    pub jump: bool,        // Bxnn jumps to xnn + Vx instead of nnn + V0
+    pub logic: bool,       // 8xy1/8xy2/8xy3 reset VF to 0
+    pub wrap: bool,        // Sprites wrap around the screen edges instead of clipping
+    pub vblank: bool,      // DRW waits for the next frame
+}
+
+pub struct FrameResult {
+    pub display_changed: bool, // Display needs redrawing
+    pub instructions: usize,   // Fewer than asked for when DRW waits for vblank
+}
+
+#[derive(Debug)]
 pub struct RomLoadResult {
     pub bytes_read: usize,
+    pub sha1: String,
+    pub info: Option<Box<RomInfo>>, // ROM database entry, if there is one
+}
+
+/// A register written from outside the program, by remote control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Dt,
+    St,
+}
+
+/// Point-in-time copy of the CPU registers, for inspection by frontends.
+#[derive(Debug, Clone, Default)]
+pub struct CpuState {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+    pub stack: Vec<u16>,
+    pub keypad: [bool; 16],
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpu {
     pub fn new() -> Self {
-        Cpu {
+        let seed = rand::thread_rng().gen();
+        let mut cpu = Cpu {
             memory: [0; END],
             rom_size: 0,
             v: [0; 16],
@@ -50,16 +156,41 @@ impl Cpu {
             st: 0,
             keypad: [false; 16],
             display: [[false; 64]; 32],
-        }
+            display_changed: false,
+            cycles: 0,
+            frames: 0,
+            quirks: Quirks::default(),
+            default_quirks: Quirks::default(),
+            seed,
+            rng: SplitMix64::new(seed),
+            rom_hash: 0,
+            vblank_wait: false,
+            key_wait: None,
+            tracer: None,
+        };
+        cpu.memory[FONT_BASE..FONT_BASE + FONT.len()].copy_from_slice(&FONT);
+        cpu
+    }
+
+    /// Return to power-on state, keeping the tracer and quirks. The RNG gets a new seed.
+    pub fn reset(&mut self) {
+        let tracer = self.tracer.take();
+        let (quirks, default_quirks) = (self.quirks, self.default_quirks);
+        *self = Cpu::new();
+        self.tracer = tracer;
+        self.quirks = quirks;
+        self.default_quirks = default_quirks;
     }
 
     pub fn load_rom(&mut self, rom_file: &str) -> Result<RomLoadResult, CpuError> {
-        let mut f: File = File::open(rom_file).map_err(|e| CpuError::RomOpenError { err: e })?;
+        let image = romfile::read(rom_file).map_err(|e| CpuError::RomFileError { err: e })?;
+        self.load_image(image, rom_file)
+    }
 
-        let mut buf: Vec<u8> = Vec::new();
-        let bytes_read: usize = f
-            .read_to_end(&mut buf)
-            .map_err(|e| CpuError::RomReadError { err: e })?;
+    /// Load a program already read into memory, `name` is only used for logging.
+    pub fn load_image(&mut self, image: RomImage, name: &str) -> Result<RomLoadResult, CpuError> {
+        let buf = image.bytes;
+        let bytes_read = buf.len();
 
         if bytes_read > END - BASE {
             return Err(CpuError::RomSizeError {
@@ -68,25 +199,136 @@ impl Cpu {
             });
         }
 
+        self.reset();
         self.memory[BASE..BASE + bytes_read].copy_from_slice(&buf);
         self.rom_size = bytes_read;
+        self.rom_hash = hash(&buf);
+
+        info!("Read {:?} bytes from CHIP-8 ROM '{}'", bytes_read, name);
+
+        // Known ROMs get the quirks of the platform they were written for, unless
+        // the file brought its own settings, and the rest get the defaults
+        let sha1 = romdb::sha1(&buf);
+        let info = image
+            .info
+            .or_else(|| RomDb::get().lookup(&sha1))
+            .map(Box::new);
+        if let Some(info) = &info {
+            info!("Identified '{}' for {}", info.title, info.platform);
+        }
+        self.quirks = info
+            .as_ref()
+            .map_or(self.default_quirks, |info| info.quirks);
+
+        Ok(RomLoadResult {
+            bytes_read,
+            sha1,
+            info,
+        })
+    }
 
-        info!("Read {:?} bytes from CHIP-8 ROM '{}'", bytes_read, rom_file);
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
 
-        Ok(RomLoadResult { bytes_read })
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
     }
 
-    fn next_instr(&self) -> u16 {
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn default_quirks(&self) -> Quirks {
+        self.default_quirks
+    }
+
+    /// Set the quirks used now and for every ROM loaded that isn't in the database.
+    pub fn set_default_quirks(&mut self, quirks: Quirks) {
+        self.default_quirks = quirks;
+        self.quirks = quirks;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restart the RNG from `seed`, so RND returns the same sequence every run.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = SplitMix64::new(seed);
+    }
+
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    /// Frames run since the ROM was loaded.
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    fn next_instr(&self) -> Result<u16, CpuError> {
+        if self.pc as usize + 1 >= END {
+            return Err(CpuError::PcOutOfRange { pc: self.pc });
+        }
         let b1: u8 = self.memory[self.pc as usize];
         let b2: u8 = self.memory[(self.pc + 1) as usize];
-        ((b1 as u16) << 8) | b2 as u16
+        Ok(((b1 as u16) << 8) | b2 as u16)
     }
 
-    pub fn cpu_exec(&mut self) {
-        let cmd: u16 = self.next_instr();
+    pub fn cpu_exec(&mut self) -> Result<(), CpuError> {
+        let pc = self.pc;
+        let before = self.tracer.as_ref().map(|_| Registers::capture(self));
+
+        let result = self
+            .next_instr()
+            .and_then(|cmd| self.execute(cmd).map(|_| cmd));
+        self.cycles += 1;
+
+        let after = before.map(|_| Registers::capture(self));
+        if let (Some(tracer), Some(before), Some(after)) = (self.tracer.as_mut(), before, after) {
+            match &result {
+                Ok(opcode) => tracer.record(TraceRecord {
+                    cycle: self.cycles,
+                    pc,
+                    opcode: *opcode,
+                    prev: before,
+                    before,
+                    after,
+                }),
+                Err(e) => tracer.fault(self.cycles, pc, e),
+            }
+        }
+
+        result.map(|_| ())
+    }
+
+    /// Run one 60 Hz frame: up to `ipf` instructions, fewer when DRW waits for vblank,
+    /// followed by a single timer tick.
+    pub fn run_frame(&mut self, ipf: usize) -> Result<FrameResult, CpuError> {
+        self.vblank_wait = false;
+        self.display_changed = false;
+        let mut instructions = 0;
+        while instructions < ipf {
+            self.cpu_exec()?;
+            instructions += 1;
+            if self.vblank_wait {
+                break;
+            }
+        }
+        self.tick_timers();
+        self.frames += 1;
+        Ok(FrameResult {
+            display_changed: self.display_changed,
+            instructions,
+        })
+    }
+
+    fn execute(&mut self, cmd: u16) -> Result<(), CpuError> {
         let ind: u16 = (cmd >> 8) >> 4; // 4-bit instruction indicator (0xF000)
 
-        debug!("INSTR: {:X}, IND: {:X}", cmd, ind);
+        trace!("INSTR: {:X}, IND: {:X}", cmd, ind);
 
         match ind {
             0x0 => {
@@ -94,18 +336,22 @@ impl Cpu {
                     0x00E0 => {
                         // CLS - Clear display
                         self.display = [[false; 64]; 32];
+                        self.display_changed = true;
+                        self.pc += 2;
                     }
                     0x00EE => {
                         // RET - Return from a subroutine
                         self.pc = match self.stack.pop() {
-                            Some(addr) => addr,
-                            None => return,
+                            Some(addr) => addr + 2, // Resume after the CALL
+                            None => return Err(CpuError::StackUnderflow { pc: self.pc }),
                         };
+                        self.sp = self.stack.len() as u8;
                         debug!("RET {:X}", self.pc);
                     }
                     _ => {
                         // 0NNN - Execute subroutine at address NNN
                         warn!("SYSTEM JMP to {:X} - Not Implemented!", 0x0FFF & cmd);
+                        self.pc += 2;
                     }
                 }
             }
@@ -116,12 +362,20 @@ impl Cpu {
             }
             0x2 => {
                 // CALL addr - Call subroutine at nnn (2nnn)
+                if self.stack.len() >= STACK_SIZE {
+                    return Err(CpuError::StackOverflow {
+                        pc: self.pc,
+                        max: STACK_SIZE,
+                    });
+                }
                 self.stack.push(self.pc);
+                self.sp = self.stack.len() as u8;
                 self.pc = 0x0FFF & cmd;
                 debug!("CALL {:X}", self.pc);
             }
             0x3 => {
                 // SE Vx, byte -- 3xkk, skip next instr if Vx = kk
+                self.pc += 2;
                 if self.v[((0x0F00 & cmd) >> 8) as usize] == (0x00FF & cmd) as u8 {
                     self.pc += 2;
                 }
@@ -129,6 +383,7 @@ impl Cpu {
             }
             0x4 => {
                 // SNE Vx, byte -- 4xkk, skip next instr if Vx != kk
+                self.pc += 2;
                 if self.v[((0x0F00 & cmd) >> 8) as usize] != (0x00FF & cmd) as u8 {
                     self.pc += 2;
                 }
@@ -136,6 +391,7 @@ impl Cpu {
             }
             0x5 => {
                 // SE Vx, Vy -- 5xy0, skip next instr if Vx = Vy
+                self.pc += 2;
                 if self.v[((0x0F00 & cmd) >> 8) as usize] == self.v[((0x00F0 & cmd) >> 4) as usize]
                 {
                     self.pc += 2;
@@ -150,7 +406,8 @@ impl Cpu {
             }
             0x7 => {
                 // ADD Vx, byte -- 7xkk, Vx += kk
-                self.v[((0x0F00 & cmd) >> 8) as usize] += (0x00FF & cmd) as u8;
+                let x = ((0x0F00 & cmd) >> 8) as usize;
+                self.v[x] = self.v[x].wrapping_add((0x00FF & cmd) as u8);
                 self.pc += 2;
                 debug!("V{:X} += {:X}", ((0x0F00 & cmd) >> 8), (0x00FF & cmd));
             }
@@ -166,6 +423,7 @@ impl Cpu {
                     // 8xy1 - OR Vx, Vy, Set Vx = Vx OR Vy, Vx |= Vy
                     self.v[((0x0F00 & cmd) >> 8) as usize] |=
                         self.v[((0x00F0 & cmd) >> 4) as usize];
+                    self.logic_quirk();
                     self.pc += 2;
                     debug!("V{:X} |= V{:X}", (0x0F00 & cmd) >> 8, (0x00F0 & cmd) >> 4);
                 }
@@ -173,6 +431,7 @@ impl Cpu {
                     // 8xy2 - AND Vx, Vy, Set Vx = Vx AND Vy
                     self.v[((0x0F00 & cmd) >> 8) as usize] &=
                         self.v[((0x00F0 & cmd) >> 4) as usize];
+                    self.logic_quirk();
                     self.pc += 2;
                     debug!("V{:X} &= V{:X}", (0x0F00 & cmd) >> 8, (0x00F0 & cmd) >> 4);
                 }
@@ -180,6 +439,7 @@ impl Cpu {
                     // 8xy3 - XOR Vx, Vy, Set Vx = Vx XOR Vy
                     self.v[((0x0F00 & cmd) >> 8) as usize] ^=
                         self.v[((0x00F0 & cmd) >> 4) as usize];
+                    self.logic_quirk();
                     self.pc += 2;
                     debug!("V{:X} ^= V{:X}", (0x0F00 & cmd) >> 8, (0x00F0 & cmd) >> 4);
                 }
@@ -190,16 +450,11 @@ impl Cpu {
                     let x = ((0x0F00 & cmd) >> 8) as usize;
                     let y = ((0x00F0 & cmd) >> 4) as usize;
 
-                    let sum = self.v[x] + self.v[y];
-
-                    if sum > u8::MAX {
-                        self.v[0xF] = 1
-                    } else {
-                        self.v[0xF] = 0
-                    }
+                    let (sum, carry) = self.v[x].overflowing_add(self.v[y]);
 
+                    self.v[x] = sum;
+                    self.v[0xF] = carry as u8;
                     self.pc += 2;
-                    self.v[x] = sum & 0xFF;
 
                     debug!("V{:X} += V{:X}, Carry Flag VF: {:X}", x, y, self.v[0xF]);
                 }
@@ -209,28 +464,26 @@ impl Cpu {
                     let x = ((0x0F00 & cmd) >> 8) as usize;
                     let y = ((0x00F0 & cmd) >> 4) as usize;
 
-                    let sub = self.v[x] - self.v[y];
+                    let sub = self.v[x].wrapping_sub(self.v[y]);
+                    let not_borrow = (self.v[x] >= self.v[y]) as u8;
 
-                    if self.v[x] >= self.v[y] {
-                        self.v[0xF] = 1;
-                    } else {
-                        self.v[0xF] = 0;
-                    }
-
-                    self.pc += 2;
+                    // VF written last so the flag wins when x = F
                     self.v[x] = sub;
+                    self.v[0xF] = not_borrow;
+                    self.pc += 2;
 
                     debug!("V{:X} -= V{:X}, Carry Flag VF: {:X}", x, y, self.v[0xF]);
                 }
                 0x6 => {
-                    // 8xy6 - Set Vx = Vy SHR 1
+                    // 8xy6 - Set Vx = Vy SHR 1 (Vx SHR 1 with the shift quirk)
                     // If the least-significant bit is 1, then VF is set to 1, otherwise 0.
-                    // Note: Make configurable for Vx = Vx >> 1 ??
                     let x = ((0x0F00 & cmd) >> 8) as usize;
                     let y = ((0x00F0 & cmd) >> 4) as usize;
+                    let src = if self.quirks.shift { x } else { y };
 
-                    self.v[0xF] = self.v[y] & 1;
-                    self.v[x] = self.v[y] >> 1;
+                    let flag = self.v[src] & 1;
+                    self.v[x] = self.v[src] >> 1;
+                    self.v[0xF] = flag;
                     self.pc += 2;
 
                     debug!("V{:X} = V{:X} >> 1, Carry Flag VF: {:X}", x, y, self.v[0xF]);
@@ -241,16 +494,12 @@ impl Cpu {
                     let x = ((0x0F00 & cmd) >> 8) as usize;
                     let y = ((0x00F0 & cmd) >> 4) as usize;
 
-                    let sub = self.v[y] - self.v[x];
+                    let sub = self.v[y].wrapping_sub(self.v[x]);
+                    let not_borrow = (self.v[y] >= self.v[x]) as u8;
 
-                    if self.v[y] >= self.v[x] {
-                        self.v[0xF] = 1;
-                    } else {
-                        self.v[0xF] = 0;
-                    }
-
-                    self.pc += 2;
                     self.v[x] = sub;
+                    self.v[0xF] = not_borrow;
+                    self.pc += 2;
 
                     debug!(
                         "V{:X} = V{:X} - V{:X}, Carry Flag VF: {:X}",
@@ -258,22 +507,28 @@ impl Cpu {
                     );
                 }
                 0xE => {
-                    // 8xyE - Set Vx = Vy SHL 1.
+                    // 8xyE - Set Vx = Vy SHL 1 (Vx SHL 1 with the shift quirk)
                     // If the most-significant bit is 1, then VF is set to 1, otherwise to 0.
-                    // Note: Make configurable for Vx = Vx << 1 ??
                     let x = ((0x0F00 & cmd) >> 8) as usize;
                     let y = ((0x00F0 & cmd) >> 4) as usize;
+                    let src = if self.quirks.shift { x } else { y };
 
-                    self.v[0xF] = (self.v[y] >> 7) & 1;
-                    self.v[x] = self.v[y] << 1;
+                    let flag = (self.v[src] >> 7) & 1;
+                    self.v[x] = self.v[src] << 1;
+                    self.v[0xF] = flag;
                     self.pc += 2;
 
                     debug!("V{:X} = V{:X} << 1, Carry Flag VF: {:X}", x, y, self.v[0xF]);
                 }
-                _ => (), // Misc 8NNN Instruction
+                _ => {
+                    // Misc 8NNN Instruction
+                    warn!("Unknown instruction {:04X}", cmd);
+                    self.pc += 2;
+                }
             },
             0x9 => {
                 // 9xy0 - SNE Vx, Vy, Skip next instruction if Vx != Vy.
+                self.pc += 2;
                 if self.v[((0x0F00 & cmd) >> 8) as usize] != self.v[((0x00F0 & cmd) >> 4) as usize]
                 {
                     self.pc += 2;
@@ -287,13 +542,18 @@ impl Cpu {
                 debug!("I = {:X}", 0x0FFF & cmd);
             }
             0xB => {
-                // Bnnn, JMP [NNN + V0]
-                self.pc = (0x0FFF & cmd) + (self.v[0x0] as u16);
-                debug!("JMP [{:X} + V0]", 0x0FFF & cmd)
+                // Bnnn, JMP [NNN + V0] (Bxnn, JMP [XNN + Vx] with the jump quirk)
+                let x = if self.quirks.jump {
+                    ((0x0F00 & cmd) >> 8) as usize
+                } else {
+                    0
+                };
+                self.pc = (0x0FFF & cmd) + (self.v[x] as u16);
+                debug!("JMP [{:X} + V{:X}]", 0x0FFF & cmd, x)
             }
             0xC => {
                 // Cxnn, LD VX, rand() & nn
-                let rand: u8 = rand::thread_rng().gen();
+                let rand = self.rng.next_u8();
                 self.v[((0x0F00 & cmd) >> 8) as usize] = rand & (0x00FF & cmd) as u8;
                 self.pc += 2;
                 debug!("V{:X} = rand() & {:X}", (0x0F00 & cmd) >> 8, 0x00FF & cmd);
@@ -301,17 +561,71 @@ impl Cpu {
             0xD => {
                 // Dxyn, DRAW pos_x: Vx, pos_y: Vy, dat_bytes: n, sprite_addr: I
                 // If any set pixels are unset, VF = 1; else VF = 0
+                let x = ((0x0F00 & cmd) >> 8) as usize;
+                let y = ((0x00F0 & cmd) >> 4) as usize;
+                let n = (0x000F & cmd) as usize;
+
+                // Start position wraps, sprites are clipped at the screen edges
+                // unless the wrap quirk is set
+                let pos_x = self.v[x] as usize % WIDTH;
+                let pos_y = self.v[y] as usize % HEIGHT;
+                let wrap = self.quirks.wrap;
+                self.v[0xF] = 0;
+
+                for row in 0..n {
+                    let py = pos_y + row;
+                    if py >= HEIGHT && !wrap {
+                        break;
+                    }
+                    let py = py % HEIGHT;
+                    let sprite = self.memory[(self.i as usize + row) % END];
+                    for col in 0..8 {
+                        let px = pos_x + col;
+                        if px >= WIDTH && !wrap {
+                            break;
+                        }
+                        let px = px % WIDTH;
+                        if sprite & (0x80 >> col) != 0 {
+                            if self.display[py][px] {
+                                self.v[0xF] = 1;
+                            }
+                            self.display[py][px] ^= true;
+                        }
+                    }
+                }
+                self.display_changed = true;
+                self.vblank_wait = self.quirks.vblank;
+                self.pc += 2;
+                debug!(
+                    "DRW V{:X}, V{:X}, {:X}, Collision VF: {:X}",
+                    x, y, n, self.v[0xF]
+                );
             }
             0xE => match cmd & 0x00FF {
                 0x9E => {
                     // Ex9E, SKP Vx
                     // Skip next instr if key with value of Vx is pressed
+                    let x: usize = ((0x0F00 & cmd) >> 8) as usize;
+                    self.pc += 2;
+                    if self.keypad[(self.v[x] & 0xF) as usize] {
+                        self.pc += 2;
+                    }
+                    debug!("SKP V{:X} (key {:X})", x, self.v[x]);
                 }
                 0xA1 => {
                     // ExA1, SKNP Vx
                     // Skip next instr if key with value of Vx is *not* pressed
+                    let x: usize = ((0x0F00 & cmd) >> 8) as usize;
+                    self.pc += 2;
+                    if !self.keypad[(self.v[x] & 0xF) as usize] {
+                        self.pc += 2;
+                    }
+                    debug!("SKNP V{:X} (key {:X})", x, self.v[x]);
+                }
+                _ => {
+                    warn!("Unknown instruction {:04X}", cmd);
+                    self.pc += 2;
                 }
-                _ => (),
             },
             0xF => match cmd & 0x00FF {
                 0x07 => {
@@ -325,8 +639,25 @@ impl Cpu {
                 0x0A => {
                     // Fx0A
                     // WAIT_KEY Vx, Wait for a keypress and store result in Vx
-                    // Blocks execution until keypress; after keypress, running resumes
+                    // Blocks execution until a key is pressed and released again, as on
+                    // the VIP, so a key held over from the last wait doesn't count twice
                     let x: usize = ((0x0F00 & cmd) >> 8) as usize;
+                    match self.key_wait {
+                        Some(key) if !self.keypad[key as usize] => {
+                            self.key_wait = None;
+                            self.v[x] = key;
+                            self.pc += 2;
+                            debug!("V{:X} = key {:X}", x, key);
+                        }
+                        Some(_) => {}
+                        None => {
+                            self.key_wait = self
+                                .keypad
+                                .iter()
+                                .position(|&pressed| pressed)
+                                .map(|key| key as u8);
+                        }
+                    }
                 }
                 0x15 => {
                     // Fx15
@@ -349,7 +680,7 @@ impl Cpu {
                     // ADD I, VX, I = I + VX
                     // Set VF = 1 if overflows past 0xFFF? (set configurable?)
                     let x: usize = ((0x0F00 & cmd) >> 8) as usize;
-                    self.i += self.v[x] as u16;
+                    self.i = (self.i + self.v[x] as u16) & 0xFFF;
                     self.pc += 2;
                     debug!("I += V{:X} (Vx val: {:X})", x, self.v[x]);
                 }
@@ -358,36 +689,326 @@ impl Cpu {
                     // I = font_table[Vx]
                     // Set I to the memory address of the 5-byte font sprite for the hexadecimal digit stored in Vx.
                     let x: usize = ((0x0F00 & cmd) >> 8) as usize;
+                    self.i = (FONT_BASE + (self.v[x] & 0xF) as usize * 5) as u16;
+                    self.pc += 2;
+                    debug!("I = font[V{:X}] (Vx val: {:X})", x, self.v[x]);
                 }
                 0x33 => {
                     // Fx33
                     // Store binary-coded decimal equivalent of value in Vx at addresses: I, I+1, and I+2
                     // I = hundreds digit; I+1 = tens digit; I+2 = ones digit
                     let x: usize = ((0x0F00 & cmd) >> 8) as usize;
+                    let i = self.i as usize;
+                    self.memory[i % END] = self.v[x] / 100;
+                    self.memory[(i + 1) % END] = (self.v[x] / 10) % 10;
+                    self.memory[(i + 2) % END] = self.v[x] % 10;
+                    self.pc += 2;
+                    debug!("BCD V{:X} (Vx val: {}) at I", x, self.v[x]);
                 }
                 0x55 => {
                     // Fx55
                     // Store values of registers V0 to VX (inclusive) in memory starting at address I
                     // After operation, I = I + X + 1 (points to next address after last accessed memory loc)
+                    // unless the load/store or increment quirks are set
                     let x: usize = ((0x0F00 & cmd) >> 8) as usize;
+                    for r in 0..=x {
+                        self.memory[(self.i as usize + r) % END] = self.v[r];
+                    }
+                    self.advance_i(x);
+                    self.pc += 2;
+                    debug!("[I] = V0..V{:X}", x);
                 }
                 0x65 => {
                     // Fx65
                     // Fill registers V0 to VX (inclusive) with the values stored in memory starting at address I
                     // After operation, I = I + X + 1 (points to next address after last accessed memory loc)
+                    // unless the load/store or increment quirks are set
                     let x: usize = ((0x0F00 & cmd) >> 8) as usize;
+                    for r in 0..=x {
+                        self.v[r] = self.memory[(self.i as usize + r) % END];
+                    }
+                    self.advance_i(x);
+                    self.pc += 2;
+                    debug!("V0..V{:X} = [I]", x);
+                }
+                _ => {
+                    warn!("Unknown instruction {:04X}", cmd);
+                    self.pc += 2;
                 }
-                _ => (),
             },
             _ => (), // Misc Instruction
         }
+        Ok(())
+    }
+
+    /// Move I past the registers stored or loaded by Fx55/Fx65.
+    fn advance_i(&mut self, x: usize) {
+        if self.quirks.load_store {
+            return;
+        }
+        let step = if self.quirks.increment_x { x } else { x + 1 };
+        self.i = (self.i + step as u16) & 0xFFF;
     }
 
-    pub fn set_display(&mut self, x: usize, y: usize, value: bool) {
-        self.display[y][x] = value;
+    /// VF reset after the logic operations, as on the COSMAC VIP.
+    fn logic_quirk(&mut self) {
+        if self.quirks.logic {
+            self.v[0xF] = 0;
+        }
     }
 
     pub fn get_display(&self) -> [[bool; 64]; 32] {
-        return self.display;
+        self.display
+    }
+
+    pub fn get_state(&self) -> CpuState {
+        CpuState {
+            v: self.v,
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            dt: self.dt,
+            st: self.st,
+            stack: self.stack.clone(),
+            keypad: self.keypad,
+        }
+    }
+
+    /// Set a register, keeping only the bits it holds: 8 for V and the timers,
+    /// 12 for I and PC.
+    pub fn set_register(&mut self, register: Register, value: u16) {
+        match register {
+            Register::V(x) => self.v[x & 0xF] = value as u8,
+            Register::I => self.i = value & 0xFFF,
+            Register::Pc => {
+                self.pc = value & 0xFFF;
+                self.key_wait = None;
+            }
+            Register::Dt => self.dt = value as u8,
+            Register::St => self.st = value as u8,
+        }
+    }
+
+    /// Decrement the delay and sound timers, called at 60 Hz.
+    pub fn tick_timers(&mut self) {
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
+    }
+
+    /// The buzzer sounds while the sound timer is non-zero.
+    pub fn sound_active(&self) -> bool {
+        self.st > 0
+    }
+
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        self.keypad[key & 0xF] = pressed;
+    }
+
+    /// Keypad state as a bit mask, bit N set while key N is pressed.
+    pub fn keypad_mask(&self) -> u16 {
+        self.keypad
+            .iter()
+            .enumerate()
+            .fold(0, |mask, (key, &pressed)| mask | ((pressed as u16) << key))
+    }
+
+    pub fn set_keypad_mask(&mut self, mask: u16) {
+        for (key, pressed) in self.keypad.iter_mut().enumerate() {
+            *pressed = mask & (1 << key) != 0;
+        }
+    }
+
+    /// Hash of the full machine state, for checking that replays stay in sync.
+    pub fn state_hash(&self) -> u64 {
+        let mut bytes = self.memory.to_vec();
+        bytes.extend_from_slice(&self.v);
+        bytes.extend_from_slice(&self.i.to_le_bytes());
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        bytes.extend(self.stack.iter().flat_map(|addr| addr.to_le_bytes()));
+        bytes.extend_from_slice(&[self.sp, self.dt, self.st]);
+        bytes.extend(self.display.iter().flatten().map(|&on| on as u8));
+        hash(&bytes)
+    }
+
+    pub fn get_memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Memory for frontends that write to it in place, such as libretro cheats.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn set_memory(&mut self, addr: usize, value: u8) {
+        match self.memory.get_mut(addr) {
+            Some(byte) => *byte = value,
+            None => warn!("Memory write out of range: {:X}", addr),
+        }
+    }
+}
+
+/// 64-bit FNV-1a, a stable hash for ROM and state fingerprints.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn image(bytes: &[u8], quirks: Option<Quirks>) -> RomImage {
+        RomImage {
+            bytes: bytes.to_vec(),
+            info: quirks.map(|quirks| RomInfo {
+                title: String::from("Known"),
+                description: None,
+                release: None,
+                authors: Vec::new(),
+                platform: String::from("Test"),
+                quirks,
+                tickrate: None,
+                keys: HashMap::new(),
+                palette: None,
+            }),
+        }
+    }
+
+    /// A machine with `program` at 0x200, run for `steps` instructions.
+    fn run(program: &[u16], steps: usize) -> Cpu {
+        let mut cpu = Cpu::new();
+        for (n, word) in program.iter().enumerate() {
+            cpu.set_memory(BASE + n * 2, (word >> 8) as u8);
+            cpu.set_memory(BASE + n * 2 + 1, *word as u8);
+        }
+        for _ in 0..steps {
+            cpu.cpu_exec().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn call_and_ret_resume_after_the_call() {
+        let program = [0x2206, 0x6001, 0x1204, 0x00EE];
+        let cpu = run(&program, 1);
+        assert_eq!(
+            (cpu.pc, cpu.stack.as_slice(), cpu.sp),
+            (0x206, &[0x200][..], 1)
+        );
+        let cpu = run(&program, 3);
+        assert_eq!((cpu.pc, cpu.v[0], cpu.sp), (0x204, 1, 0));
+
+        let mut cpu = run(&[0x00EE], 0);
+        assert!(matches!(
+            cpu.cpu_exec(),
+            Err(CpuError::StackUnderflow { pc: 0x200 })
+        ));
+    }
+
+    #[test]
+    fn skips_advance_past_the_next_instruction() {
+        let program = [
+            0x6005, 0x6105, // V0 = V1 = 5
+            0x3005, 0x62FF, // SE V0, 5 skips
+            0x4005, 0x6301, // SNE V0, 5 runs on
+            0x5010, 0x64FF, // SE V0, V1 skips
+            0x9010, 0x6501, // SNE V0, V1 runs on
+            0x00E0,
+        ];
+        let cpu = run(&program, 9);
+        assert_eq!(cpu.v[2..6], [0, 1, 0, 1]);
+        assert_eq!(cpu.pc, 0x216);
+    }
+
+    #[test]
+    fn arithmetic_wraps_and_sets_vf_last() {
+        let cpu = run(&[0x6002, 0x70FF], 2);
+        assert_eq!(cpu.v[0], 0x01);
+
+        let cpu = run(&[0x6001, 0x6102, 0x8015], 3);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0xFF, 0));
+        let cpu = run(&[0x6001, 0x6102, 0x8017], 3);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0x01, 1));
+        let cpu = run(&[0x6081, 0x800E], 2);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0x02, 1));
+        // The flag wins when VF is the destination
+        let cpu = run(&[0x6F05, 0x6103, 0x8F15], 3);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn draws_font_sprites_with_collision_and_clipping() {
+        let program = [
+            0x6000, 0xF029, // I = font 0
+            0xD005, 0xD005, // Draw twice, erasing it
+            0x603E, 0xD015, // Draw at x = 62
+        ];
+        let cpu = run(&program, 3);
+        assert_eq!(cpu.i as usize, FONT_BASE);
+        assert_eq!(cpu.display[0][..5], [true, true, true, true, false]);
+        assert_eq!(cpu.v[0xF], 0);
+        let cpu = run(&program, 4);
+        assert!(cpu.display.iter().flatten().all(|&on| !on));
+        assert_eq!(cpu.v[0xF], 1);
+
+        let cpu = run(&program, 6);
+        assert_eq!(cpu.display[0][62..], [true, true]);
+        assert_eq!(cpu.display[0][..2], [false, false]);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn stores_bcd_and_registers() {
+        let program = [
+            0x60FE, 0xA300, 0xF033, // BCD 254 at 0x300
+            0xA300, 0xF265, // V0..V2 = [0x300]
+            0xA310, 0xF255, // [0x310] = V0..V2
+        ];
+        let cpu = run(&program, 7);
+        assert_eq!(cpu.memory[0x300..0x303], [2, 5, 4]);
+        assert_eq!(cpu.v[..3], [2, 5, 4]);
+        assert_eq!(cpu.memory[0x310..0x313], [2, 5, 4]);
+        assert_eq!(cpu.i, 0x313);
+    }
+
+    #[test]
+    fn key_skips_follow_the_keypad() {
+        let program = [0x6007, 0xE09E, 0x6101, 0xE0A1, 0x6201];
+        let mut cpu = run(&program, 0);
+        cpu.set_key(7, true);
+        for _ in 0..4 {
+            cpu.cpu_exec().unwrap();
+        }
+        assert_eq!((cpu.v[1], cpu.v[2], cpu.pc), (0, 1, 0x20A));
+
+        let cpu = run(&program, 4);
+        assert_eq!((cpu.v[1], cpu.v[2], cpu.pc), (1, 0, 0x20A));
+    }
+
+    #[test]
+    fn unknown_roms_get_the_default_quirks() {
+        let defaults = Quirks {
+            logic: true,
+            ..Quirks::default()
+        };
+        let known = Quirks {
+            shift: true,
+            jump: true,
+            ..Quirks::default()
+        };
+        let mut cpu = Cpu::new();
+        cpu.set_default_quirks(defaults);
+
+        cpu.load_image(image(&[0x12, 0x00], Some(known)), "known")
+            .unwrap();
+        assert_eq!(cpu.quirks(), known);
+        cpu.reset();
+        assert_eq!(cpu.quirks(), known);
+
+        cpu.load_image(image(&[0x12, 0x02, 0x12, 0x02], None), "unknown")
+            .unwrap();
+        assert_eq!(cpu.quirks(), defaults);
     }
 }
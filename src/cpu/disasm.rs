@@ -0,0 +1,59 @@
+// CHIP-8 opcode disassembler, mnemonics follow the comments in `Cpu::cpu_exec`
+
+pub fn disassemble(cmd: u16) -> String {
+    let x = (0x0F00 & cmd) >> 8;
+    let y = (0x00F0 & cmd) >> 4;
+    let n = 0x000F & cmd;
+    let kk = 0x00FF & cmd;
+    let nnn = 0x0FFF & cmd;
+
+    match (cmd >> 8) >> 4 {
+        0x0 => match cmd {
+            0x00E0 => String::from("CLS"),
+            0x00EE => String::from("RET"),
+            _ => format!("SYS {:03X}", nnn),
+        },
+        0x1 => format!("JP {:03X}", nnn),
+        0x2 => format!("CALL {:03X}", nnn),
+        0x3 => format!("SE V{:X}, {:02X}", x, kk),
+        0x4 => format!("SNE V{:X}, {:02X}", x, kk),
+        0x5 if n == 0x0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6 => format!("LD V{:X}, {:02X}", x, kk),
+        0x7 => format!("ADD V{:X}, {:02X}", x, kk),
+        0x8 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => format!("DW {:04X}", cmd),
+        },
+        0x9 if n == 0x0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA => format!("LD I, {:03X}", nnn),
+        0xB => format!("JP V0, {:03X}", nnn),
+        0xC => format!("RND V{:X}, {:02X}", x, kk),
+        0xD => format!("DRW V{:X}, V{:X}, {:X}", x, y, n),
+        0xE => match kk {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => format!("DW {:04X}", cmd),
+        },
+        0xF => match kk {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            _ => format!("DW {:04X}", cmd),
+        },
+        _ => format!("DW {:04X}", cmd),
+    }
+}
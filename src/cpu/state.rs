@@ -0,0 +1,251 @@
+// Save states, the whole machine in a fixed-size binary layout so frontends can
+// allocate for them up front
+//
+// "C8ST" + version byte, then (multi-byte values LE):
+//   memory: [u8; 4096], v: [u8; 16], i: u16, pc: u16, sp, dt, st: u8,
+//   stack depth: u8, stack: [u16; 16], keypad mask: u16, display: [u8; 256]
+//   (one bit per pixel, rows of 8 bytes, MSB leftmost), cycles: u64, frames: u64,
+//   quirks: u8 (bit per flag), seed: u64, rng: u64, rom size: u16, rom hash: u64,
+//   vblank wait: u8, key wait: u8 (key pressed during Fx0A, 0xFF for none)
+
+use super::rng::SplitMix64;
+use super::{Cpu, CpuError, Quirks, END, HEIGHT, STACK_SIZE, WIDTH};
+
+const MAGIC: &[u8; 4] = b"C8ST";
+const VERSION: u8 = 1;
+const REGISTERS: usize = 16 + 2 + 2 + 4; // V, I, PC, then SP, DT, ST and stack depth
+const TRAILER: usize = 8 + 8 + 1 + 8 + 8 + 2 + 8 + 1 + 1; // Cycles through key wait
+pub const STATE_SIZE: usize =
+    MAGIC.len() + 1 + END + REGISTERS + STACK_SIZE * 2 + 2 + WIDTH * HEIGHT / 8 + TRAILER;
+
+impl Cpu {
+    /// Capture the machine state, leaving the machine untouched.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(STATE_SIZE);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.memory);
+        out.extend_from_slice(&self.v);
+        out.extend_from_slice(&self.i.to_le_bytes());
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&[self.sp, self.dt, self.st, self.stack.len() as u8]);
+        for slot in 0..STACK_SIZE {
+            let addr = self.stack.get(slot).copied().unwrap_or(0);
+            out.extend_from_slice(&addr.to_le_bytes());
+        }
+        out.extend_from_slice(&self.keypad_mask().to_le_bytes());
+        for row in &self.display {
+            out.extend(
+                row.chunks(8)
+                    .map(|bits| bits.iter().fold(0u8, |byte, &on| (byte << 1) | on as u8)),
+            );
+        }
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.extend_from_slice(&self.frames.to_le_bytes());
+        out.push(quirk_bits(self.quirks));
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.extend_from_slice(&self.rng.state().to_le_bytes());
+        out.extend_from_slice(&(self.rom_size as u16).to_le_bytes());
+        out.extend_from_slice(&self.rom_hash.to_le_bytes());
+        out.push(self.vblank_wait as u8);
+        out.push(self.key_wait.unwrap_or(0xFF));
+        out
+    }
+
+    /// Restore a state from `save_state`, leaving the machine untouched if it is invalid.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), CpuError> {
+        if state.len() != STATE_SIZE {
+            return Err(CpuError::StateError {
+                msg: format!("expected {} bytes, got {}", STATE_SIZE, state.len()),
+            });
+        }
+        let mut reader = Reader(state);
+        if reader.take(4) != MAGIC || reader.u8() != VERSION {
+            return Err(CpuError::StateError {
+                msg: String::from("not a c8emu save state, or from another version"),
+            });
+        }
+
+        let mut cpu = Cpu::new();
+        cpu.memory.copy_from_slice(reader.take(END));
+        cpu.v.copy_from_slice(reader.take(16));
+        cpu.i = reader.u16();
+        cpu.pc = reader.u16();
+        cpu.sp = reader.u8();
+        cpu.dt = reader.u8();
+        cpu.st = reader.u8();
+        let depth = reader.u8() as usize;
+        let stack: Vec<u16> = (0..STACK_SIZE).map(|_| reader.u16()).collect();
+        if depth > STACK_SIZE || cpu.sp as usize != depth {
+            return Err(CpuError::StateError {
+                msg: format!("stack pointer {} and depth {} don't match", cpu.sp, depth),
+            });
+        }
+        cpu.stack = stack[..depth].to_vec();
+        // Addresses are 12-bit, anything more would overflow RET or fetch
+        let addrs = [("PC", cpu.pc), ("I", cpu.i)];
+        let stack_addrs = cpu.stack.iter().map(|&addr| ("stack entry", addr));
+        if let Some((name, addr)) = addrs
+            .into_iter()
+            .chain(stack_addrs)
+            .find(|&(_, a)| a > 0xFFF)
+        {
+            return Err(CpuError::StateError {
+                msg: format!("{} {:04X} is outside memory", name, addr),
+            });
+        }
+        cpu.set_keypad_mask(reader.u16());
+        for row in cpu.display.iter_mut() {
+            let bytes = reader.take(WIDTH / 8);
+            for (x, on) in row.iter_mut().enumerate() {
+                *on = bytes[x / 8] & (0x80 >> (x % 8)) != 0;
+            }
+        }
+        cpu.cycles = reader.u64();
+        cpu.frames = reader.u64();
+        cpu.quirks = quirks_from_bits(reader.u8());
+        cpu.seed = reader.u64();
+        cpu.rng = SplitMix64::new(reader.u64());
+        cpu.rom_size = reader.u16() as usize;
+        cpu.rom_hash = reader.u64();
+        cpu.vblank_wait = reader.u8() != 0;
+        cpu.key_wait = Some(reader.u8()).filter(|&key| key < 16);
+
+        cpu.tracer = self.tracer.take();
+        cpu.default_quirks = self.default_quirks;
+        *self = cpu;
+        Ok(())
+    }
+}
+
+fn quirk_bits(quirks: Quirks) -> u8 {
+    [
+        quirks.shift,
+        quirks.load_store,
+        quirks.increment_x,
+        quirks.jump,
+        quirks.logic,
+        quirks.wrap,
+        quirks.vblank,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (n, &on)| bits | ((on as u8) << n))
+}
+
+fn quirks_from_bits(bits: u8) -> Quirks {
+    let on = |n: u8| bits & (1 << n) != 0;
+    Quirks {
+        shift: on(0),
+        load_store: on(1),
+        increment_x: on(2),
+        jump: on(3),
+        logic: on(4),
+        wrap: on(5),
+        vblank: on(6),
+    }
+}
+
+/// Reads fields in order from a state already checked to be `STATE_SIZE` long.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        head
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    fn u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8));
+        u64::from_le_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A machine running `RND V0, 0xFF` then `JP 0x200` forever.
+    fn rnd_loop() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_seed(1);
+        for (addr, byte) in [0xC0, 0xFF, 0x12, 0x00].into_iter().enumerate() {
+            cpu.set_memory(0x200 + addr, byte);
+        }
+        cpu
+    }
+
+    fn rolls(cpu: &mut Cpu, count: usize) -> Vec<u8> {
+        (0..count)
+            .map(|_| {
+                cpu.run_frame(2).unwrap();
+                cpu.get_state().v[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn saving_leaves_the_run_unchanged() {
+        let mut plain = rnd_loop();
+        let expected = rolls(&mut plain, 6);
+
+        let mut saved = rnd_loop();
+        let mut got = rolls(&mut saved, 3);
+        let state = saved.save_state();
+        got.extend(rolls(&mut saved, 3));
+        assert_eq!(got, expected);
+
+        // Loading picks up from the save, RND included
+        let mut loaded = Cpu::new();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(state.len(), STATE_SIZE);
+        assert_eq!(rolls(&mut loaded, 3), expected[3..]);
+    }
+
+    #[test]
+    fn rejects_corrupted_states() {
+        let mut cpu = rnd_loop();
+        cpu.run_frame(1).unwrap();
+        let state = cpu.save_state();
+        let registers = MAGIC.len() + 1 + END + 16;
+
+        let corrupt = |offset: usize, bytes: &[u8]| {
+            let mut state = state.clone();
+            state[offset..offset + bytes.len()].copy_from_slice(bytes);
+            state
+        };
+        let cases = [
+            corrupt(registers, &[0x00, 0x10]),                 // I = 0x1000
+            corrupt(registers + 2, &[0xFF, 0xFF]),             // PC = 0xFFFF
+            corrupt(registers + 4, &[1]),                      // SP without a stack entry
+            corrupt(registers + 7, &[17]),                     // Depth past the stack
+            corrupt(registers + 4, &[1, 0, 0, 1, 0xFF, 0xFF]), // Stack entry 0xFFFF
+        ];
+        for (n, bad) in cases.iter().enumerate() {
+            let mut loaded = rnd_loop();
+            let before = loaded.save_state();
+            assert!(
+                matches!(loaded.load_state(bad), Err(CpuError::StateError { .. })),
+                "case {}",
+                n
+            );
+            assert_eq!(
+                loaded.save_state(),
+                before,
+                "case {} changed the machine",
+                n
+            );
+        }
+        assert!(Cpu::new().load_state(&state).is_ok());
+    }
+}
@@ -0,0 +1,420 @@
+// Per-instruction execution tracer, enabled with the C8EMU_TRACE* environment variables
+//
+// Text format, one line per instruction:
+//   <cycle> <pc> <opcode> <disassembly> <reg>=<value> ... <reg>:<old>-><new> ...
+// `reg=value` fields are registers changed since the previous record (timer ticks at
+// the end of a frame, writes from the debugger or remote), giving the value before
+// this instruction. `reg:old->new` fields are the changes made by the instruction.
+// Binary format, "C8TR" + version byte, then per instruction:
+//   cycle: u64, pc: u16, opcode: u16, mask: u32 (all LE), followed by the new
+//   value of each register whose bit is set in mask (V0..VF: u8, I: u16, DT, ST, SP: u8).
+//   Bit 30 of the mask means a second mask and register values for the changes since
+//   the previous record come first, in the same layout.
+//   Bit 31 of the mask marks a fault record with no register values.
+
+use super::disasm::disassemble;
+use super::{Cpu, CpuError};
+use log::{error, warn};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+pub const BIN_MAGIC: &[u8; 4] = b"C8TR";
+pub const BIN_VERSION: u8 = 2;
+const MASK_I: u32 = 1 << 16;
+const MASK_DT: u32 = 1 << 17;
+const MASK_ST: u32 = 1 << 18;
+const MASK_SP: u32 = 1 << 19;
+pub const MASK_PRE: u32 = 1 << 30;
+pub const MASK_FAULT: u32 = 1 << 31;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+/// Register file captured either side of an instruction, used to compute deltas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub dt: u8,
+    pub st: u8,
+    pub sp: u8,
+}
+
+impl Registers {
+    pub(super) fn capture(cpu: &Cpu) -> Self {
+        Self {
+            v: cpu.v,
+            i: cpu.i,
+            dt: cpu.dt,
+            st: cpu.st,
+            sp: cpu.sp,
+        }
+    }
+
+    /// Bit mask of the registers that differ between `self` and `other`.
+    fn changes(&self, other: &Registers) -> u32 {
+        let mut mask = 0;
+        for r in 0..16 {
+            if self.v[r] != other.v[r] {
+                mask |= 1 << r;
+            }
+        }
+        for (bit, changed) in [
+            (MASK_I, self.i != other.i),
+            (MASK_DT, self.dt != other.dt),
+            (MASK_ST, self.st != other.st),
+            (MASK_SP, self.sp != other.sp),
+        ] {
+            if changed {
+                mask |= bit;
+            }
+        }
+        mask
+    }
+
+    /// Append the registers selected by `mask` in binary trace layout.
+    fn encode(&self, mask: u32, out: &mut Vec<u8>) {
+        for r in 0..16 {
+            if mask & (1 << r) != 0 {
+                out.push(self.v[r]);
+            }
+        }
+        if mask & MASK_I != 0 {
+            out.extend_from_slice(&self.i.to_le_bytes());
+        }
+        if mask & MASK_DT != 0 {
+            out.push(self.dt);
+        }
+        if mask & MASK_ST != 0 {
+            out.push(self.st);
+        }
+        if mask & MASK_SP != 0 {
+            out.push(self.sp);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub prev: Registers, // After the previous record, filled in by the tracer
+    pub before: Registers,
+    pub after: Registers,
+}
+
+impl TraceRecord {
+    pub fn to_text(&self) -> String {
+        let (p, b, a) = (&self.prev, &self.before, &self.after);
+        let mut line = format!(
+            "{:08} {:03X} {:04X} {:<16}",
+            self.cycle,
+            self.pc,
+            self.opcode,
+            disassemble(self.opcode)
+        );
+        for r in 0..16 {
+            if p.v[r] != b.v[r] {
+                line += &format!(" V{:X}={:02X}", r, b.v[r]);
+            }
+        }
+        if p.i != b.i {
+            line += &format!(" I={:03X}", b.i);
+        }
+        if p.dt != b.dt {
+            line += &format!(" DT={:02X}", b.dt);
+        }
+        if p.st != b.st {
+            line += &format!(" ST={:02X}", b.st);
+        }
+        if p.sp != b.sp {
+            line += &format!(" SP={:X}", b.sp);
+        }
+        for r in 0..16 {
+            if b.v[r] != a.v[r] {
+                line += &format!(" V{:X}:{:02X}->{:02X}", r, b.v[r], a.v[r]);
+            }
+        }
+        if b.i != a.i {
+            line += &format!(" I:{:03X}->{:03X}", b.i, a.i);
+        }
+        if b.dt != a.dt {
+            line += &format!(" DT:{:02X}->{:02X}", b.dt, a.dt);
+        }
+        if b.st != a.st {
+            line += &format!(" ST:{:02X}->{:02X}", b.st, a.st);
+        }
+        if b.sp != a.sp {
+            line += &format!(" SP:{:X}->{:X}", b.sp, a.sp);
+        }
+        line.trim_end().to_string()
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let pre = self.prev.changes(&self.before);
+        let mut mask = self.before.changes(&self.after);
+        if pre != 0 {
+            mask |= MASK_PRE;
+        }
+        let mut out = Vec::with_capacity(16);
+        out.extend_from_slice(&self.cycle.to_le_bytes());
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&self.opcode.to_le_bytes());
+        out.extend_from_slice(&mask.to_le_bytes());
+        if pre != 0 {
+            out.extend_from_slice(&pre.to_le_bytes());
+            self.before.encode(pre, &mut out);
+        }
+        self.after.encode(mask, &mut out);
+        out
+    }
+}
+
+pub struct Tracer {
+    format: TraceFormat,
+    sink: Box<dyn Write + Send>,
+    ring: Option<VecDeque<TraceRecord>>, // Last N records, only written out on fault
+    capacity: usize,
+    last: Option<Registers>, // State after the previous record
+}
+
+impl Tracer {
+    pub fn new(
+        sink: Box<dyn Write + Send>,
+        format: TraceFormat,
+        ring: Option<usize>,
+    ) -> Result<Self, CpuError> {
+        let mut tracer = Self {
+            format,
+            sink,
+            ring: ring.map(VecDeque::with_capacity),
+            capacity: ring.unwrap_or(0),
+            last: None,
+        };
+        if format == TraceFormat::Binary {
+            tracer
+                .sink
+                .write_all(BIN_MAGIC)
+                .and_then(|_| tracer.sink.write_all(&[BIN_VERSION]))
+                .map_err(|e| CpuError::TraceOpenError { err: e })?;
+        }
+        Ok(tracer)
+    }
+
+    pub fn to_file(path: &str, format: TraceFormat, ring: Option<usize>) -> Result<Self, CpuError> {
+        let f = File::create(path).map_err(|e| CpuError::TraceOpenError { err: e })?;
+        Self::new(Box::new(BufWriter::new(f)), format, ring)
+    }
+
+    /// Build a tracer from C8EMU_TRACE (output path), C8EMU_TRACE_FORMAT (`text` or `bin`)
+    /// and C8EMU_TRACE_RING (keep only the last N instructions, dumped on fault).
+    pub fn from_env() -> Result<Option<Self>, CpuError> {
+        let path = match std::env::var("C8EMU_TRACE") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let format = match std::env::var("C8EMU_TRACE_FORMAT").as_deref() {
+            Ok("bin") | Ok("binary") => TraceFormat::Binary,
+            _ => TraceFormat::Text,
+        };
+        let ring = match std::env::var("C8EMU_TRACE_RING") {
+            Ok(n) => match n.parse::<usize>() {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    warn!("Ignoring C8EMU_TRACE_RING={}, expected a record count", n);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        Self::to_file(&path, format, ring).map(Some)
+    }
+
+    pub fn record(&mut self, mut record: TraceRecord) {
+        record.prev = self.last.replace(record.after).unwrap_or(record.before);
+        match self.ring.as_mut() {
+            Some(ring) => {
+                if ring.len() == self.capacity {
+                    ring.pop_front();
+                }
+                if self.capacity > 0 {
+                    ring.push_back(record);
+                }
+            }
+            None => self.write(&record),
+        }
+    }
+
+    /// Flush any buffered ring records followed by a marker for the faulting instruction.
+    pub fn fault(&mut self, cycle: u64, pc: u16, err: &CpuError) {
+        if let Some(ring) = self.ring.take() {
+            for record in ring.iter() {
+                self.write(record);
+            }
+            self.ring = Some(VecDeque::with_capacity(self.capacity));
+        }
+
+        let result = match self.format {
+            TraceFormat::Text => writeln!(self.sink, "{:08} {:03X} FAULT {}", cycle, pc, err),
+            TraceFormat::Binary => {
+                let mut out = Vec::with_capacity(16);
+                out.extend_from_slice(&cycle.to_le_bytes());
+                out.extend_from_slice(&pc.to_le_bytes());
+                out.extend_from_slice(&0u16.to_le_bytes());
+                out.extend_from_slice(&MASK_FAULT.to_le_bytes());
+                self.sink.write_all(&out)
+            }
+        };
+        if let Err(e) = result.and_then(|_| self.sink.flush()) {
+            error!("Failed to write trace: {}", e);
+        }
+    }
+
+    fn write(&mut self, record: &TraceRecord) {
+        let result = match self.format {
+            TraceFormat::Text => writeln!(self.sink, "{}", record.to_text()),
+            TraceFormat::Binary => self.sink.write_all(&record.to_binary()),
+        };
+        if let Err(e) = result {
+            error!("Failed to write trace: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Trace sink that can be read back after the tracer has taken ownership.
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Sink {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    /// A machine with `program` at 0x200 tracing into the returned sink.
+    fn traced(program: &[u16], format: TraceFormat, ring: Option<usize>) -> (Cpu, Sink) {
+        let mut cpu = Cpu::new();
+        for (n, word) in program.iter().enumerate() {
+            cpu.set_memory(0x200 + n * 2, (word >> 8) as u8);
+            cpu.set_memory(0x200 + n * 2 + 1, *word as u8);
+        }
+        let sink = Sink::default();
+        cpu.set_tracer(Some(
+            Tracer::new(Box::new(sink.clone()), format, ring).unwrap(),
+        ));
+        (cpu, sink)
+    }
+
+    fn registers() -> Registers {
+        Registers {
+            v: [0; 16],
+            i: 0,
+            dt: 0,
+            st: 0,
+            sp: 0,
+        }
+    }
+
+    #[test]
+    fn encodes_text_and_binary_records() {
+        let prev = registers();
+        let mut before = prev;
+        before.dt = 4;
+        let mut after = before;
+        after.v[1] = 0x2A;
+        after.i = 0x345;
+        let record = TraceRecord {
+            cycle: 12,
+            pc: 0x204,
+            opcode: 0x612A,
+            prev,
+            before,
+            after,
+        };
+        assert_eq!(
+            record.to_text(),
+            "00000012 204 612A LD V1, 2A        DT=04 V1:00->2A I:000->345"
+        );
+
+        let mut expected = 12u64.to_le_bytes().to_vec();
+        expected.extend([0x04, 0x02, 0x2A, 0x61]);
+        expected.extend((MASK_PRE | MASK_I | 1 << 1).to_le_bytes());
+        expected.extend(MASK_DT.to_le_bytes());
+        expected.push(4);
+        expected.extend([0x2A, 0x45, 0x03]);
+        assert_eq!(record.to_binary(), expected);
+
+        // Nothing changed between records, so no pre-state block
+        let record = TraceRecord {
+            prev: before,
+            ..record
+        };
+        assert_eq!(record.to_binary()[12..16], (MASK_I | 1 << 1).to_le_bytes());
+        assert_eq!(record.to_binary().len(), 16 + 3);
+    }
+
+    #[test]
+    fn timer_ticks_between_frames_are_traced() {
+        // LD DT, V0 with V0 = 5, then spin
+        let (mut cpu, sink) = traced(&[0x6005, 0xF015, 0x1204], TraceFormat::Text, None);
+        cpu.run_frame(3).unwrap();
+        cpu.run_frame(1).unwrap();
+        cpu.run_frame(1).unwrap();
+
+        let text = sink.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].ends_with("DT:00->05"), "{}", lines[1]);
+        assert!(lines[2].ends_with("JP 204"), "{}", lines[2]);
+        assert!(lines[3].ends_with("DT=04"), "{}", lines[3]);
+        assert!(lines[4].ends_with("DT=03"), "{}", lines[4]);
+    }
+
+    #[test]
+    fn ring_buffer_is_written_on_fault() {
+        // Three loads, then RET with an empty stack
+        let program = [0x6001, 0x6102, 0x6203, 0x00EE];
+        let (mut cpu, sink) = traced(&program, TraceFormat::Text, Some(2));
+        for _ in 0..3 {
+            cpu.cpu_exec().unwrap();
+        }
+        assert_eq!(sink.text(), "");
+
+        assert!(cpu.cpu_exec().is_err());
+        let text = sink.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("00000002 202 6102"));
+        assert!(lines[1].starts_with("00000003 204 6203"));
+        assert!(lines[2].starts_with("00000004 206 FAULT"));
+
+        let (mut cpu, sink) = traced(&program, TraceFormat::Binary, Some(0));
+        while cpu.cpu_exec().is_ok() {}
+        let data = sink.0.lock().unwrap().clone();
+        assert_eq!(data[..5], [b'C', b'8', b'T', b'R', BIN_VERSION]);
+        assert_eq!(data.len(), 5 + 16);
+        assert_eq!(data[5 + 12..], MASK_FAULT.to_le_bytes());
+    }
+}
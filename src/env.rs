@@ -0,0 +1,610 @@
+//! Reinforcement learning environments, gym style: `reset`, then `step` with an
+//! action index until the episode is done. What an action presses, how the reward
+//! is scored and when an episode ends come from a spec file written for each ROM.
+//!
+//! An environment is a plain `Cpu` with no threads of its own, so thousands can
+//! share a process. `VecEnv` steps a batch of them across the available cores.
+
+use crate::cpu::rng::SplitMix64;
+use crate::cpu::{Cpu, CpuError, DEFAULT_IPF, HEIGHT, WIDTH};
+use crate::romdb::RomDb;
+use log::warn;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Bytes in an observation, one per pixel (0 or 1) row by row.
+pub const OBSERVATION_SIZE: usize = WIDTH * HEIGHT;
+
+const DEFAULT_FRAME_SKIP: usize = 4;
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum EnvError {
+    #[error("Failed to read spec file '{path}': {err}")]
+    ReadError { path: PathBuf, err: std::io::Error },
+    #[error("Malformed spec file '{path}': {err}")]
+    ParseError { path: PathBuf, err: toml::de::Error },
+    #[error("Invalid spec: {msg}")]
+    SpecError { msg: String },
+    #[error("Action {action} out of range, the spec has {count} actions")]
+    ActionError { action: usize, count: usize },
+    #[error("Got {actions} actions for {count} environments")]
+    BatchError { actions: usize, count: usize },
+    #[error("{err}")]
+    CpuError { err: CpuError },
+}
+
+/// How a number is stored in memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Binary, // Unsigned, most significant byte first
+    Bcd, // One decimal digit per byte, most significant first, as Fx33 stores them
+}
+
+/// A number in memory: a score, lives left, a level.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Counter {
+    pub addr: u16,
+    #[serde(default = "default_len")]
+    pub len: usize, // Bytes
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+fn default_len() -> usize {
+    1
+}
+
+impl Counter {
+    pub fn read(&self, memory: &[u8]) -> i64 {
+        let addr = self.addr as usize;
+        let bytes = &memory[addr..addr + self.len];
+        match self.encoding {
+            Encoding::Binary => bytes.iter().fold(0, |n, &b| (n << 8) | b as i64),
+            Encoding::Bcd => bytes.iter().fold(0, |n, &b| n * 10 + b as i64),
+        }
+    }
+}
+
+/// Reward for a counter going up, `scale` per unit. A fall gives a negative reward.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RewardSpec {
+    #[serde(flatten)]
+    pub counter: Counter,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+/// The episode ends once a counter holds `equals`, e.g. no lives left.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DoneSpec {
+    #[serde(flatten)]
+    pub counter: Counter,
+    pub equals: i64,
+}
+
+/// A spec file as written.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecFile {
+    rom: String,              // Relative to the spec file
+    platform: Option<String>, // Quirks, over the ROM database
+    ipf: Option<usize>,       // Instructions per frame, over the ROM database
+    frame_skip: Option<usize>,
+    #[serde(default)]
+    start_frames: usize, // Run with no keys held after a reset, to get past title screens
+    max_frames: Option<u64>,
+    actions: Option<Vec<String>>, // Hex digits of the keys held, "" for none
+    #[serde(default)]
+    reward: Vec<RewardSpec>,
+    #[serde(default)]
+    done: Vec<DoneSpec>,
+}
+
+/// A loaded spec, ready to create environments from.
+#[derive(Debug, Clone)]
+pub struct EnvSpec {
+    pub ipf: usize,
+    pub frame_skip: usize, // Frames run per step, with the action held
+    pub start_frames: usize,
+    pub max_frames: Option<u64>, // Episode length limit, counting start frames
+    pub actions: Vec<u16>,       // Keypad mask held for each action
+    pub rewards: Vec<RewardSpec>,
+    pub dones: Vec<DoneSpec>,
+    start: Vec<u8>, // Save state just after the ROM loads
+}
+
+impl EnvSpec {
+    pub fn load(path: &Path) -> Result<EnvSpec, EnvError> {
+        let text = fs::read_to_string(path).map_err(|e| EnvError::ReadError {
+            path: path.to_path_buf(),
+            err: e,
+        })?;
+        let file: SpecFile = toml::from_str(&text).map_err(|e| EnvError::ParseError {
+            path: path.to_path_buf(),
+            err: e,
+        })?;
+        let rom = path.parent().unwrap_or(Path::new("")).join(&file.rom);
+        EnvSpec::build(file, &rom.to_string_lossy())
+    }
+
+    fn build(file: SpecFile, rom: &str) -> Result<EnvSpec, EnvError> {
+        let mut cpu = Cpu::new();
+        let loaded = cpu
+            .load_rom(rom)
+            .map_err(|e| EnvError::CpuError { err: e })?;
+        if let Some(name) = &file.platform {
+            let quirks = RomDb::get()
+                .platform_quirks(name)
+                .ok_or_else(|| EnvError::SpecError {
+                    msg: format!("unknown platform '{}'", name),
+                })?;
+            cpu.set_quirks(quirks);
+        }
+        let ipf = file
+            .ipf
+            .or(loaded.info.and_then(|info| info.tickrate))
+            .unwrap_or(DEFAULT_IPF);
+
+        let actions = match file.actions {
+            Some(actions) => actions
+                .iter()
+                .map(|keys| key_mask(keys))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => std::iter::once(0)
+                .chain((0..16).map(|key| 1 << key))
+                .collect(), // No keys, then each
+        };
+        if actions.is_empty() {
+            return Err(EnvError::SpecError {
+                msg: String::from("no actions"),
+            });
+        }
+        let counters = file.reward.iter().map(|r| &r.counter);
+        for counter in counters.chain(file.done.iter().map(|d| &d.counter)) {
+            let end = counter.addr as usize + counter.len;
+            if counter.len == 0 || counter.len > 8 || end > cpu.get_memory().len() {
+                return Err(EnvError::SpecError {
+                    msg: format!("counter at {:03X} out of range", counter.addr),
+                });
+            }
+        }
+
+        Ok(EnvSpec {
+            ipf: ipf.max(1),
+            frame_skip: file.frame_skip.unwrap_or(DEFAULT_FRAME_SKIP).max(1),
+            start_frames: file.start_frames,
+            max_frames: file.max_frames,
+            actions,
+            rewards: file.reward,
+            dones: file.done,
+            start: cpu.save_state(),
+        })
+    }
+
+    /// Sum of the rewards, for comparing counters between frames.
+    fn score(&self, memory: &[u8]) -> f64 {
+        self.rewards
+            .iter()
+            .map(|r| r.counter.read(memory) as f64 * r.scale)
+            .sum()
+    }
+
+    fn is_done(&self, memory: &[u8]) -> bool {
+        self.dones
+            .iter()
+            .any(|d| d.counter.read(memory) == d.equals)
+    }
+}
+
+/// Keys held by an action, as hex digits: "" for none, "5" or "46".
+fn key_mask(keys: &str) -> Result<u16, EnvError> {
+    keys.chars().try_fold(0u16, |mask, c| match c.to_digit(16) {
+        Some(key) => Ok(mask | 1 << key),
+        None => Err(EnvError::SpecError {
+            msg: format!("'{}' in action \"{}\" is not a key", c, keys),
+        }),
+    })
+}
+
+/// What a step returned.
+#[derive(Debug)]
+pub struct Step<'a> {
+    pub observation: &'a [u8],
+    pub reward: f64,
+    pub done: bool, // Ended by the spec or `max_frames`, call `reset` before stepping on
+}
+
+pub struct Env {
+    spec: EnvSpec,
+    cpu: Cpu,
+    rng: SplitMix64, // Seeds for each episode
+    score: f64,
+    done: bool,
+    observation: Vec<u8>,
+}
+
+impl Env {
+    /// An environment seeded with `seed`, so runs with the same actions repeat exactly.
+    /// Call `reset` before the first step.
+    pub fn new(spec: &EnvSpec, seed: u64) -> Env {
+        Env {
+            spec: spec.clone(),
+            cpu: Cpu::new(),
+            rng: SplitMix64::new(seed),
+            score: 0.0,
+            done: true,
+            observation: vec![0; OBSERVATION_SIZE],
+        }
+    }
+
+    pub fn spec(&self) -> &EnvSpec {
+        &self.spec
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn action_count(&self) -> usize {
+        self.spec.actions.len()
+    }
+
+    pub fn observation(&self) -> &[u8] {
+        &self.observation
+    }
+
+    /// Start a new episode, reseeding first when `seed` is given.
+    pub fn reset(&mut self, seed: Option<u64>) -> Result<&[u8], EnvError> {
+        if let Some(seed) = seed {
+            self.rng = SplitMix64::new(seed);
+        }
+        self.cpu
+            .load_state(&self.spec.start)
+            .map_err(|e| EnvError::CpuError { err: e })?;
+        self.cpu.set_seed(self.rng.next_u64());
+        for _ in 0..self.spec.start_frames {
+            self.run_frame()?;
+        }
+        self.score = self.spec.score(self.cpu.get_memory());
+        self.done = false;
+        self.observe();
+        Ok(&self.observation)
+    }
+
+    /// Hold the keys of `action` for `frame_skip` frames, stopping early if the
+    /// episode ends. The reward is the change in score over those frames.
+    pub fn step(&mut self, action: usize) -> Result<Step<'_>, EnvError> {
+        let mask = *self.spec.actions.get(action).ok_or(EnvError::ActionError {
+            action,
+            count: self.spec.actions.len(),
+        })?;
+        if self.done {
+            self.reset(None)?;
+        }
+
+        self.cpu.set_keypad_mask(mask);
+        let start = self.score;
+        for _ in 0..self.spec.frame_skip {
+            self.run_frame()?;
+            let memory = self.cpu.get_memory();
+            self.score = self.spec.score(memory);
+            let out_of_time = self
+                .spec
+                .max_frames
+                .is_some_and(|max| self.cpu.frame_count() >= max);
+            if self.spec.is_done(memory) || out_of_time {
+                self.done = true;
+                break;
+            }
+        }
+        self.observe();
+        Ok(Step {
+            observation: &self.observation,
+            reward: self.score - start,
+            done: self.done,
+        })
+    }
+
+    fn run_frame(&mut self) -> Result<(), EnvError> {
+        self.cpu
+            .run_frame(self.spec.ipf)
+            .map(|_| ())
+            .map_err(|e| EnvError::CpuError { err: e })
+    }
+
+    fn observe(&mut self) {
+        let display = self.cpu.get_display();
+        for (byte, &on) in self.observation.iter_mut().zip(display.iter().flatten()) {
+            *byte = on as u8;
+        }
+    }
+}
+
+/// A batch of environments stepped together, spread over threads. Finished episodes
+/// restart straight away, so the observation after a done is the next episode's first.
+pub struct VecEnv {
+    envs: Vec<Env>,
+    pool: Option<rayon::ThreadPool>, // Kept between steps, None to run on the caller
+    observations: Vec<u8>,           // OBSERVATION_SIZE bytes per environment
+    rewards: Vec<f64>,
+    dones: Vec<bool>,
+}
+
+/// What a batch step returned, in environment order.
+#[derive(Debug)]
+pub struct VecStep<'a> {
+    pub observations: &'a [u8],
+    pub rewards: &'a [f64],
+    pub dones: &'a [bool],
+}
+
+impl VecEnv {
+    /// `count` environments, seeded `seed`, `seed + 1`, ...
+    pub fn new(spec: &EnvSpec, count: usize, seed: u64) -> VecEnv {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut envs = VecEnv {
+            envs: (0..count)
+                .map(|i| Env::new(spec, seed.wrapping_add(i as u64)))
+                .collect(),
+            pool: None,
+            observations: vec![0; count * OBSERVATION_SIZE],
+            rewards: vec![0.0; count],
+            dones: vec![false; count],
+        };
+        envs.set_threads(threads);
+        envs
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    pub fn envs(&self) -> &[Env] {
+        &self.envs
+    }
+
+    /// Threads used by `reset` and `step`, at most one per environment. They are
+    /// started here and wait between calls, so a step costs no thread spawns.
+    pub fn set_threads(&mut self, threads: usize) {
+        let threads = threads.clamp(1, self.envs.len().max(1));
+        self.pool = None;
+        if threads > 1 {
+            self.pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|n| format!("c8emu-env-{}", n))
+                .build()
+                .map_err(|e| warn!("Stepping environments on one thread: {}", e))
+                .ok();
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool
+            .as_ref()
+            .map_or(1, rayon::ThreadPool::current_num_threads)
+    }
+
+    pub fn reset(&mut self) -> Result<&[u8], EnvError> {
+        self.run(|_, env, observation, reward, done| {
+            observation.copy_from_slice(env.reset(None)?);
+            (*reward, *done) = (0.0, false);
+            Ok(())
+        })?;
+        Ok(&self.observations)
+    }
+
+    /// Step every environment with its action from `actions`.
+    pub fn step(&mut self, actions: &[usize]) -> Result<VecStep<'_>, EnvError> {
+        if actions.len() != self.envs.len() {
+            return Err(EnvError::BatchError {
+                actions: actions.len(),
+                count: self.envs.len(),
+            });
+        }
+        self.run(|index, env, observation, reward, done| {
+            let step = env.step(actions[index])?;
+            (*reward, *done) = (step.reward, step.done);
+            if step.done {
+                env.reset(None)?;
+            }
+            observation.copy_from_slice(env.observation());
+            Ok(())
+        })?;
+        Ok(VecStep {
+            observations: &self.observations,
+            rewards: &self.rewards,
+            dones: &self.dones,
+        })
+    }
+
+    /// Call `f` for each environment with its index and output slots, on the
+    /// thread pool if there is one.
+    fn run<F>(&mut self, f: F) -> Result<(), EnvError>
+    where
+        F: Fn(usize, &mut Env, &mut [u8], &mut f64, &mut bool) -> Result<(), EnvError> + Sync,
+    {
+        let call = |(i, (env, ((observation, reward), done))): (usize, _)| {
+            f(i, env, observation, reward, done)
+        };
+        let Some(pool) = &self.pool else {
+            let outputs = self
+                .observations
+                .chunks_mut(OBSERVATION_SIZE)
+                .zip(self.rewards.iter_mut())
+                .zip(self.dones.iter_mut());
+            return self
+                .envs
+                .iter_mut()
+                .zip(outputs)
+                .enumerate()
+                .try_for_each(call);
+        };
+        let outputs = self
+            .observations
+            .par_chunks_mut(OBSERVATION_SIZE)
+            .zip(self.rewards.par_iter_mut())
+            .zip(self.dones.par_iter_mut());
+        let envs = self.envs.par_iter_mut().zip(outputs).enumerate();
+        pool.install(|| envs.try_for_each(call))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/roms/test_opcode.ch8");
+
+    /// Counts frames in V0, stored as BCD at 0x300, and keeps the last RND roll
+    /// at 0x311 next to a binary copy of the count.
+    const COUNTER_ROM: [u8; 14] = [
+        0x70, 0x01, // V0 += 1
+        0xA3, 0x00, // I = 0x300
+        0xF0, 0x33, // BCD V0
+        0xC1, 0xFF, // V1 = RND
+        0xA3, 0x10, // I = 0x310
+        0xF1, 0x55, // [I] = V0..V1
+        0x12, 0x00, // JP 0x200
+    ];
+
+    /// Write `spec` and the ROM it names to a scratch directory and load it.
+    fn load(name: &str, spec: &str, rom: Option<&[u8]>) -> Result<EnvSpec, EnvError> {
+        let dir = std::env::temp_dir().join(format!("c8emu-env-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        if let Some(rom) = rom {
+            fs::write(dir.join("counter.ch8"), rom).unwrap();
+        }
+        let path = dir.join("spec.toml");
+        fs::write(&path, spec).unwrap();
+        let result = EnvSpec::load(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    fn counter_spec(name: &str, extra: &str) -> EnvSpec {
+        let spec = format!(
+            "rom = \"counter.ch8\"\nipf = 7\n{}\n[[reward]]\naddr = 0x300\nlen = 3\nencoding = \"bcd\"\n",
+            extra
+        );
+        load(name, &spec, Some(&COUNTER_ROM)).unwrap()
+    }
+
+    #[test]
+    fn counters_read_binary_and_bcd() {
+        let memory = [0x01, 0x02, 0x03, 0x09];
+        let counter = |len, encoding| Counter {
+            addr: 1,
+            len,
+            encoding,
+        };
+        assert_eq!(counter(1, Encoding::Binary).read(&memory), 0x02);
+        assert_eq!(counter(3, Encoding::Binary).read(&memory), 0x020309);
+        assert_eq!(counter(3, Encoding::Bcd).read(&memory), 239);
+    }
+
+    #[test]
+    fn steps_score_reward_deltas_until_done() {
+        let spec = counter_spec("done", "[[done]]\naddr = 0x310\nequals = 10");
+        let mut env = Env::new(&spec, 1);
+        env.reset(None).unwrap();
+        let rewards: Vec<(f64, bool)> = (0..3)
+            .map(|_| env.step(0).map(|s| (s.reward, s.done)).unwrap())
+            .collect();
+        // Four frames a step, cut short when the count reaches 10
+        assert_eq!(rewards, [(4.0, false), (4.0, false), (2.0, true)]);
+
+        let spec = counter_spec("max-frames", "max_frames = 6");
+        let mut env = Env::new(&spec, 1);
+        env.reset(None).unwrap();
+        assert!(!env.step(0).unwrap().done);
+        let step = env.step(0).unwrap();
+        assert_eq!((step.reward, step.done), (2.0, true));
+        // Stepping on starts a new episode
+        assert_eq!(env.step(0).unwrap().reward, 4.0);
+    }
+
+    #[test]
+    fn rejects_invalid_actions() {
+        let spec = counter_spec("actions", "");
+        let mut env = Env::new(&spec, 1);
+        env.reset(None).unwrap();
+        assert_eq!(env.action_count(), 17);
+        assert!(matches!(
+            env.step(17),
+            Err(EnvError::ActionError {
+                action: 17,
+                count: 17
+            })
+        ));
+
+        let spec = "rom = \"counter.ch8\"\nactions = [\"\", \"4g\"]\n";
+        let result = load("bad-action", spec, Some(&COUNTER_ROM));
+        assert!(matches!(result, Err(EnvError::SpecError { .. })));
+    }
+
+    #[test]
+    fn same_seed_repeats_exactly() {
+        let spec = counter_spec("seed", "max_frames = 8");
+        let episode = |env: &mut Env| {
+            env.reset(None).unwrap();
+            for _ in 0..2 {
+                env.step(0).unwrap();
+            }
+            (env.cpu().seed(), env.cpu().get_memory()[0x311])
+        };
+
+        let (mut a, mut b) = (Env::new(&spec, 42), Env::new(&spec, 42));
+        let first = episode(&mut a);
+        assert_eq!(first, episode(&mut b));
+        // Each episode draws a fresh seed, the same one for the same env seed
+        let second = episode(&mut a);
+        assert_ne!(first.0, second.0);
+        assert_eq!(second, episode(&mut b));
+        // Reseeding restarts the sequence
+        a.reset(Some(42)).unwrap();
+        assert_eq!(a.cpu().seed(), first.0);
+    }
+
+    #[test]
+    fn vec_env_matches_on_and_off_the_pool() {
+        let spec = format!(
+            "rom = \"{}\"\nmax_frames = 40\nactions = [\"\", \"5\", \"46\"]\n\
+             [[reward]]\naddr = 0x3E0\nlen = 2\n",
+            ROM
+        );
+        let spec = load("vec", &spec, None).unwrap();
+
+        let run = |threads: usize| {
+            let mut envs = VecEnv::new(&spec, 4, 9);
+            envs.set_threads(threads);
+            let mut log = vec![envs.reset().unwrap().to_vec()];
+            for n in 0..30 {
+                let actions: Vec<usize> = (0..envs.len()).map(|i| (n + i) % 3).collect();
+                let step = envs.step(&actions).unwrap();
+                log.push(step.observations.to_vec());
+                log.push(step.rewards.iter().flat_map(|r| r.to_le_bytes()).collect());
+                log.push(step.dones.iter().map(|&d| d as u8).collect());
+            }
+            log
+        };
+
+        let inline = run(1);
+        assert_eq!(inline, run(4));
+        assert_eq!(inline, run(1));
+        assert!(inline.iter().skip(1).step_by(3).any(|o| o.contains(&1)));
+    }
+}
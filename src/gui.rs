@@ -1,52 +1,250 @@
+mod debugger;
 mod display;
+mod keypad;
+mod library;
+mod memory_viewer;
+mod movie;
+mod quirks;
 mod rom_loader;
+mod speed;
 
-use crate::cpu::Cpu;
+use crate::args::LaunchArgs;
+use crate::config::Config;
+use crate::cpu::trace::Tracer;
+use crate::cpu::{Cpu, DEFAULT_IPF};
+use crate::gui::debugger::Debugger;
 use crate::gui::display::Display;
+use crate::gui::keypad::Keypad;
+use crate::gui::library::Library;
+use crate::gui::memory_viewer::MemoryViewer;
+use crate::gui::movie::MoviePanel;
+use crate::gui::quirks::QuirksPanel;
 use crate::gui::rom_loader::RomLoader;
+use crate::gui::speed::SpeedControl;
+use crate::keymap::KeyMap;
+use crate::palette::Preset;
+use crate::recorder::RecordSettings;
+use crate::remote;
+use crate::romfile;
+use crate::runner::{self, Runner, FRAME};
+use crate::screenshot;
+use iced::keyboard::key::Named;
+use iced::keyboard::Key;
 use iced::{Application, Command, Element, Subscription, Theme};
-use log::error;
-use std::time::{Duration, Instant};
+use log::{error, warn};
+use std::path::PathBuf;
+use std::time::Instant;
+
+const SCREENSHOT_SCALE: usize = 8; // Hotkey screenshots are 512 x 256
+const RECORD_SCALE: usize = 4; // GUI recordings are 256 x 128
 
 #[derive(Debug, Clone)]
 pub enum Message {
-    CpuTick,
-    DisplayTick,
+    Tick(Instant),
     RomLoader(rom_loader::Message),
+    Library(library::Message),
     Display(display::Message),
+    Debugger(debugger::Message),
+    MemoryViewer(memory_viewer::Message),
+    Movie(movie::Message),
+    Quirks(quirks::Message),
+    Keypad(keypad::Message),
+    Speed(speed::Message),
+    ToggleRecording,
+    KeyPressed(Key),
+    KeyReleased(Key),
+    FileDropped(PathBuf),
+    WindowResized(u32, u32),
+    CloseRequested,
+}
+
+/// Startup settings, read before the window opens.
+pub struct Flags {
+    pub config: Config,
+    pub config_error: Option<String>, // Why the config file couldn't be used
+    pub launch: LaunchArgs,
 }
 
 pub struct Gui {
-    cpu: Cpu,
-    last_cpu_update: Instant,
-    last_display_update: Instant,
-    cpu_hz: u64,
-    display_hz: u64,
+    runner: Runner,
     rom_loader: RomLoader,
+    library: Library,
     display: Display,
-    count: u32,
+    debugger: Debugger,
+    memory_viewer: MemoryViewer,
+    movie: MoviePanel,
+    quirks: QuirksPanel,
+    keypad: Keypad,
+    speed: SpeedControl,
+    config: Config,
+    config_error: Option<String>,
+    palette: Option<Preset>, // Chosen on the command line, over the config file and ROM colours
+    key_map: KeyMap,
+    recording: bool,
+    remote: Option<remote::Server>, // Remote control, from `--remote`
+    launch: Vec<runner::Command>,   // Command line settings for the first ROM loaded
+}
+
+impl Gui {
+    fn load_rom(&mut self, path: String) {
+        let (file, entry) = romfile::split(&path);
+        if entry.is_none() {
+            self.rom_loader.entries.clear();
+            // Archives holding several ROMs ask which one to run first
+            if romfile::is_archive(file) {
+                match romfile::entries(file) {
+                    Ok(entries) if entries.len() > 1 => {
+                        self.rom_loader.rom_path = path.clone();
+                        self.rom_loader.archive = path;
+                        self.rom_loader.entries = entries;
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("{}", e),
+                }
+            }
+        }
+
+        let quirks = romfile::sha1(&path)
+            .ok()
+            .and_then(|sha1| self.config.rom(&sha1, &path))
+            .and_then(|rom| rom.quirks);
+        self.rom_loader.rom_path = path.clone();
+        self.runner.send(runner::Command::Load(path, quirks));
+        for command in self.launch.drain(..) {
+            self.runner.send(command);
+        }
+    }
+
+    /// Write the config back, unless it failed to load, so a typo can't cost the
+    /// rest of the file.
+    fn save_config(&self) {
+        if self.config_error.is_some() {
+            warn!("Not saving settings until the config file is fixed");
+            return;
+        }
+        if let Err(e) = self.config.save() {
+            error!("{}", e);
+        }
+    }
+
+    /// Forward a host keyboard event to the keypad through the active key map.
+    fn host_key(&mut self, key: &Key, pressed: bool) {
+        let keypad = match key {
+            Key::Character(c) => c.chars().next().and_then(|c| self.key_map.lookup(c)),
+            Key::Named(named) => self.rom_button(*named),
+            _ => None,
+        };
+        if let Some(k) = keypad {
+            self.runner.send(runner::Command::Key(k, pressed));
+        }
+    }
+
+    /// Keypad value for the arrow keys, Space and Enter, from the ROM database's
+    /// button assignments for the loaded ROM.
+    fn rom_button(&self, key: Named) -> Option<usize> {
+        let button = match key {
+            Named::ArrowUp => "up",
+            Named::ArrowDown => "down",
+            Named::ArrowLeft => "left",
+            Named::ArrowRight => "right",
+            Named::Space => "a",
+            Named::Enter => "b",
+            _ => return None,
+        };
+        let info = self.rom_loader.info.as_ref()?;
+        info.keys.get(button).map(|&k| k as usize & 0xF)
+    }
+
+    /// Speed control bound to a function key, if any.
+    fn hotkey(&self, key: &Key) -> Option<speed::Message> {
+        match key {
+            Key::Named(Named::F5) => Some(speed::Message::TogglePause(self.runner.frame().paused)),
+            Key::Named(Named::F6) => Some(speed::Message::FrameAdvance),
+            Key::Named(Named::F7) => Some(speed::Message::Slower),
+            Key::Named(Named::F8) => Some(speed::Message::Faster),
+            Key::Named(Named::F9) => Some(speed::Message::Normal),
+            Key::Named(Named::F10) => Some(speed::Message::ToggleUncapped),
+            _ => None,
+        }
+    }
 }
 
 impl Application for Gui {
     type Executor = iced::executor::Default;
     type Message = Message;
-    type Flags = ();
+    type Flags = Flags;
     type Theme = Theme;
 
-    fn new(_flags: ()) -> (Self, Command<Message>) {
-        (
-            Self {
-                cpu: Cpu::new(),
-                last_cpu_update: Instant::now(),
-                last_display_update: Instant::now(),
-                cpu_hz: 1,      //500,
-                display_hz: 60, // 60
-                rom_loader: RomLoader::new(),
-                display: Display::new(),
-                count: 0,
-            },
-            Command::none(),
-        )
+    fn new(flags: Flags) -> (Self, Command<Message>) {
+        let mut cpu = Cpu::new();
+        match Tracer::from_env() {
+            Ok(tracer) => cpu.set_tracer(tracer),
+            Err(e) => error!("Error enabling trace: {}", e),
+        }
+
+        let Flags {
+            config,
+            config_error,
+            launch,
+        } = flags;
+        let key_map = config.key_map(None);
+        let (palette, preset) = match launch.palette {
+            Some(preset) => (preset.palette(), Some(preset)),
+            None => (config.display.palette(), config.display.preset()),
+        };
+        cpu.set_default_quirks(config.quirks);
+        let runner = Runner::spawn(cpu, config.audio.clone(), DEFAULT_IPF);
+        let remote = launch.remote.and_then(|port| {
+            remote::Server::start(port)
+                .map_err(|e| error!("{}", e))
+                .ok()
+        });
+        let mut speed = SpeedControl::new();
+        if let Some(command) = speed.update(speed::Message::SpeedChanged(config.speed)) {
+            runner.send(command);
+        }
+
+        let mut gui = Self {
+            runner,
+            rom_loader: RomLoader::new(config.recent.first().cloned().unwrap_or_default()),
+            library: Library::new(config.library.clone()),
+            display: Display::new(palette, preset),
+            debugger: Debugger::new(),
+            memory_viewer: MemoryViewer::new(),
+            movie: MoviePanel::new(),
+            quirks: QuirksPanel::new(),
+            keypad: Keypad::new(),
+            speed,
+            config,
+            config_error,
+            palette: launch.palette,
+            key_map,
+            recording: false,
+            remote,
+            launch: Vec::new(),
+        };
+        gui.display.scale = launch.scale;
+
+        // Settings from the command line follow the load, and last until the next one.
+        // An archive with several ROMs holds them until an entry is picked.
+        if let Some(rom) = launch.rom.clone() {
+            if let Some(quirks) = launch.quirks {
+                gui.launch.push(runner::Command::SetQuirks(Some(quirks)));
+            }
+            if let Some(ipf) = launch.ipf() {
+                gui.launch.push(runner::Command::SetIpf(ipf));
+            }
+            if launch.paused {
+                gui.launch.push(runner::Command::Pause(true));
+            }
+            gui.load_rom(rom);
+        }
+        if launch.fullscreen {
+            let command = gui.update(Message::Display(display::Message::ToggleFullscreen));
+            return (gui, command);
+        }
+        (gui, Command::none())
     }
 
     fn title(&self) -> String {
@@ -55,63 +253,321 @@ impl Application for Gui {
 
     fn update(&mut self, message: Message) -> Command<Message> {
         match message {
-            Message::CpuTick => {
-                let now = Instant::now();
-                let elapsed = now.duration_since(self.last_cpu_update);
-                if elapsed >= Duration::from_secs_f64(1.0 / self.cpu_hz as f64) {
-                    self.cpu.cpu_exec();
-                    self.last_cpu_update = now;
+            Message::Tick(_now) => {
+                let roms = self
+                    .remote
+                    .as_mut()
+                    .map(|remote| remote.poll(&self.runner))
+                    .unwrap_or_default();
+                for rom in roms {
+                    self.load_rom(rom);
                 }
-            }
-            Message::DisplayTick => {
-                let now = Instant::now();
-                let elapsed = now.duration_since(self.last_display_update);
-                if elapsed >= Duration::from_secs_f64(1.0 / self.display_hz as f64) {
-                    self.cpu.set_display(self.count as usize % 64, 10, true);
-                    self.count += 1;
 
-                    self.display.update(self.cpu.get_display()); // Update display buffer on display tick
-                    self.last_display_update = now;
-                }
-            }
-            Message::RomLoader(msg) => match msg {
-                rom_loader::Message::RomPathChanged(path) => {
-                    self.rom_loader.rom_path = path;
-                }
-                rom_loader::Message::LoadRom => {
-                    match self.cpu.load_rom(&self.rom_loader.rom_path) {
-                        Ok(result) => {
+                while let Some(event) = self.runner.poll_event() {
+                    if let (Some(remote), runner::Event::RomLoaded(result)) =
+                        (&mut self.remote, &event)
+                    {
+                        remote.rom_loaded(result);
+                    }
+                    match event {
+                        runner::Event::RomLoaded(Ok(result)) => {
                             self.rom_loader.size_bytes = result.bytes_read;
                             self.rom_loader.read_status = true;
+                            let rom = self.config.rom(&result.sha1, &self.rom_loader.rom_path);
+                            self.key_map = self.config.key_map(rom);
+                            // ROMs with their own colours use them until another ROM is loaded
+                            let colors = result.info.as_ref().and_then(|info| info.palette.clone());
+                            match (self.palette, colors) {
+                                (Some(preset), _) => {
+                                    self.display.set_palette(preset.palette(), Some(preset))
+                                }
+                                (None, Some(palette)) => self.display.set_palette(palette, None),
+                                (None, None) => self.display.set_palette(
+                                    self.config.display.palette(),
+                                    self.config.display.preset(),
+                                ),
+                            }
+                            self.rom_loader.sha1 = result.sha1;
+                            self.rom_loader.info = result.info;
+                            self.config.add_recent(&self.rom_loader.rom_path);
+                            self.save_config();
                         }
-                        Err(e) => {
+                        runner::Event::RomLoaded(Err(e)) => {
                             self.rom_loader.read_status = false;
                             error!("Error loading ROM: {}", e)
                         }
+                        runner::Event::Fault(e) => error!("CPU fault: {}", e),
+                        runner::Event::MovieStopped(result) => {
+                            self.movie.state = movie::State::Idle;
+                            if let Err(e) = result {
+                                error!("{}", e);
+                            }
+                        }
+                        runner::Event::RecordError(e) => {
+                            self.recording = false;
+                            error!("{}", e)
+                        }
+                    }
+                }
+
+                // Pick up the newest frame from the emulation thread, if there is one
+                if self.runner.update() {
+                    let frame = self.runner.frame();
+                    self.display.update_frame(
+                        &frame.display,
+                        &frame.previous_display,
+                        frame.count,
+                        frame.display_version,
+                    );
+                    self.memory_viewer.update_memory(&frame.memory);
+                }
+            }
+            Message::RomLoader(msg) => match msg {
+                rom_loader::Message::RomPathChanged(path) => {
+                    self.rom_loader.rom_path = path;
+                }
+                rom_loader::Message::LoadRom => self.load_rom(self.rom_loader.rom_path.clone()),
+                rom_loader::Message::Browse => {
+                    return Command::perform(rom_loader::browse(), |path| {
+                        Message::RomLoader(rom_loader::Message::Browsed(path))
+                    });
+                }
+                rom_loader::Message::Browsed(Some(path))
+                | rom_loader::Message::RecentSelected(path) => self.load_rom(path),
+                rom_loader::Message::Browsed(None) => {}
+                rom_loader::Message::EntrySelected(entry) => {
+                    self.load_rom(format!("{}#{}", self.rom_loader.archive, entry))
+                }
+            },
+            Message::Library(msg) => match msg {
+                library::Message::Open(path) => self.load_rom(path),
+                msg => {
+                    if let library::Message::Scan = msg {
+                        self.config.library = Some(self.library.dir.clone());
+                        self.save_config();
                     }
+                    return self.library.update(msg).map(Message::Library);
                 }
             },
-            Message::Display(msg) => match msg {
-                _ => {}
+            Message::Display(msg) => {
+                if let display::Message::PaletteSelected(preset) = msg {
+                    self.palette = None;
+                    self.config.display.palette = preset;
+                    self.config.display.colors.clear();
+                    self.save_config();
+                }
+                let refilter = matches!(msg, display::Message::FilterSelected(_));
+                let fullscreen = self.display.fullscreen;
+                self.display.update(msg);
+                if refilter {
+                    let frame = self.runner.frame();
+                    self.display
+                        .refilter(&frame.display, &frame.previous_display);
+                }
+                if self.display.fullscreen != fullscreen {
+                    let mode = if self.display.fullscreen {
+                        iced::window::Mode::Fullscreen
+                    } else {
+                        iced::window::Mode::Windowed
+                    };
+                    return iced::window::change_mode(iced::window::Id::MAIN, mode);
+                }
+            }
+            Message::Debugger(msg) => {
+                if let Some(command) = self.debugger.update(msg) {
+                    self.runner.send(command);
+                }
+            }
+            Message::MemoryViewer(msg) => {
+                if let Some((addr, value)) = self.memory_viewer.update(msg) {
+                    self.runner.send(runner::Command::WriteMemory(addr, value));
+                }
+            }
+            Message::Movie(msg) => {
+                if let Some(command) = self.movie.update(msg) {
+                    self.runner.send(command);
+                }
+            }
+            Message::Quirks(msg) => {
+                if let Some(command) = self.quirks.update(msg) {
+                    if let runner::Command::SetQuirks(quirks) = &command {
+                        let (sha1, path) = (&self.rom_loader.sha1, &self.rom_loader.rom_path);
+                        self.config.rom_mut(sha1, path).quirks = *quirks;
+                        self.save_config();
+                    }
+                    self.runner.send(command);
+                }
+            }
+            Message::Keypad(msg) => {
+                if let Some(command) = self.keypad.update(msg) {
+                    self.runner.send(command);
+                }
+            }
+            Message::Speed(msg) => {
+                if let Some(command) = self.speed.update(msg) {
+                    self.runner.send(command);
+                }
+            }
+            Message::ToggleRecording => {
+                self.recording = !self.recording;
+                let settings = self.recording.then(|| RecordSettings {
+                    path: screenshot::timestamped_path("gif"),
+                    palette: self.display.palette.clone(),
+                    scale: RECORD_SCALE,
+                });
+                self.runner.send(runner::Command::Record(settings));
+            }
+            Message::KeyPressed(Key::Named(Named::F12)) => {
+                let path = screenshot::timestamped_path("png");
+                let display = &self.runner.frame().display;
+                if let Err(e) =
+                    screenshot::save(&path, display, &self.display.palette, SCREENSHOT_SCALE)
+                {
+                    error!("{}", e);
+                }
+            }
+            Message::KeyPressed(Key::Named(Named::F4)) => {
+                return self.update(Message::Debugger(debugger::Message::Step));
+            }
+            Message::KeyPressed(Key::Named(Named::F11)) => {
+                return self.update(Message::Display(display::Message::ToggleFullscreen));
+            }
+            Message::KeyPressed(Key::Named(Named::Escape)) if self.display.fullscreen => {
+                return self.update(Message::Display(display::Message::ToggleFullscreen));
+            }
+            Message::KeyPressed(key) => match self.hotkey(&key) {
+                Some(msg) => return self.update(Message::Speed(msg)),
+                None => self.host_key(&key, true),
             },
+            Message::KeyReleased(key) => self.host_key(&key, false),
+            Message::FileDropped(path) => self.load_rom(path.display().to_string()),
+            Message::WindowResized(width, height) if !self.display.fullscreen => {
+                self.config.window.width = width as f32;
+                self.config.window.height = height as f32;
+            }
+            Message::WindowResized(..) => {}
+            Message::CloseRequested => {
+                // Window size and speed change too often to save each time
+                self.config.speed = self.speed.speed;
+                self.save_config();
+                return iced::window::close(iced::window::Id::MAIN);
+            }
         }
         Command::none()
     }
 
-    fn view(&self) -> Element<Message> {
+    fn view(&self) -> Element<'_, Message> {
         // GUI layout here
-        iced::widget::Column::new()
-            .push(self.rom_loader.view().map(Message::RomLoader))
-            .push(self.display.view().map(Message::Display))
-            .padding(15)
-            .into()
+        if self.display.fullscreen {
+            return self.display.view().map(Message::Display);
+        }
+
+        let frame = self.runner.frame();
+        let toggles = iced::widget::row![
+            self.debugger.toggle_view().map(Message::Debugger),
+            self.memory_viewer.toggle_view().map(Message::MemoryViewer),
+            self.library.toggle_view().map(Message::Library),
+            self.quirks.toggle_view().map(Message::Quirks),
+            iced::widget::Button::new(if self.recording {
+                "Stop Recording"
+            } else {
+                "Record GIF"
+            })
+            .on_press(Message::ToggleRecording),
+        ]
+        .spacing(10);
+
+        let mut screen = iced::widget::Row::new().push(self.display.view().map(Message::Display));
+        if self.memory_viewer.visible {
+            screen = screen.push(
+                self.memory_viewer
+                    .view(frame.state.pc, frame.state.i)
+                    .map(Message::MemoryViewer),
+            );
+        }
+
+        let mut main = iced::widget::Column::new();
+        if let Some(e) = &self.config_error {
+            main = main.push(
+                iced::widget::Text::new(format!("{} (settings won't be saved)", e))
+                    .style(iced::Color::from_rgb(0.9, 0.2, 0.2)),
+            );
+        }
+        main = main
+            .push(
+                self.rom_loader
+                    .view(&self.config.recent)
+                    .map(Message::RomLoader),
+            )
+            .push(toggles)
+            .push(self.speed.view(frame.paused, frame.ips).map(Message::Speed))
+            .push(self.display.controls().map(Message::Display))
+            .push(self.movie.view().map(Message::Movie));
+        if self.quirks.visible && self.rom_loader.read_status {
+            let overridden = self
+                .config
+                .rom(&self.rom_loader.sha1, &self.rom_loader.rom_path)
+                .is_some_and(|rom| rom.quirks.is_some());
+            main = main.push(
+                self.quirks
+                    .view(frame.quirks, overridden)
+                    .map(Message::Quirks),
+            );
+        }
+        let main = main
+            .push(screen)
+            .push(
+                self.keypad
+                    .view(&frame.state.keypad, &self.key_map)
+                    .map(Message::Keypad),
+            )
+            .padding(15);
+
+        // Library on the left, debugger on the right
+        let mut layout = iced::widget::Row::new();
+        if self.library.visible {
+            layout = layout.push(self.library.view().map(Message::Library));
+        }
+        layout = layout.push(main);
+        if self.debugger.visible {
+            layout = layout.push(
+                self.debugger
+                    .view(&frame.state, &frame.memory, frame.paused)
+                    .map(Message::Debugger),
+            );
+        }
+        layout.into()
     }
 
     fn subscription(&self) -> Subscription<Message> {
         Subscription::batch(vec![
-            // 16 ms = ~60 Hz
-            iced::time::every(Duration::from_millis(16)).map(|_| Message::CpuTick),
-            iced::time::every(Duration::from_millis(16)).map(|_| Message::DisplayTick),
+            // Poll the emulation thread once per frame
+            iced::time::every(FRAME).map(Message::Tick),
+            iced::keyboard::on_key_press(|key, _| Some(Message::KeyPressed(key))),
+            iced::keyboard::on_key_release(|key, _| Some(Message::KeyReleased(key))),
+            iced::event::listen_with(|event, _| match event {
+                iced::Event::Window(_, iced::window::Event::FileDropped(path)) => {
+                    Some(Message::FileDropped(path))
+                }
+                iced::Event::Window(_, iced::window::Event::Resized { width, height }) => {
+                    Some(Message::WindowResized(width, height))
+                }
+                iced::Event::Window(_, iced::window::Event::CloseRequested) => {
+                    Some(Message::CloseRequested)
+                }
+                iced::Event::Mouse(iced::mouse::Event::ButtonReleased(
+                    iced::mouse::Button::Left,
+                ))
+                | iced::Event::Touch(iced::touch::Event::FingerLifted { .. })
+                | iced::Event::Touch(iced::touch::Event::FingerLost { .. }) => {
+                    Some(Message::Keypad(keypad::Message::Release))
+                }
+                _ => None,
+            }),
         ])
     }
 }
+
+fn mono(text: String) -> iced::widget::Text<'static> {
+    iced::widget::Text::new(text).font(iced::Font::MONOSPACE)
+}
@@ -0,0 +1,149 @@
+use crate::cpu::disasm::disassemble;
+use crate::cpu::CpuState;
+use crate::gui::mono;
+use crate::runner::Command;
+
+const DISASM_LINES: u16 = 12; // Instructions shown either side of PC
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Toggle,
+    Pause,
+    Step,
+    Continue,
+}
+
+pub struct Debugger {
+    pub visible: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self { visible: false }
+    }
+
+    /// Handle a debugger message, returning the command for the emulation thread.
+    pub fn update(&mut self, message: Message) -> Option<Command> {
+        match message {
+            Message::Toggle => {
+                self.visible = !self.visible;
+                None
+            }
+            Message::Pause => Some(Command::Pause(true)),
+            Message::Step => Some(Command::Step),
+            Message::Continue => Some(Command::Pause(false)),
+        }
+    }
+
+    pub fn toggle_view(&self) -> iced::Element<'_, Message> {
+        iced::widget::Button::new(if self.visible {
+            "Hide Debugger"
+        } else {
+            "Show Debugger"
+        })
+        .on_press(Message::Toggle)
+        .into()
+    }
+
+    pub fn view(
+        &self,
+        state: &CpuState,
+        memory: &[u8],
+        paused: bool,
+    ) -> iced::Element<'_, Message> {
+        let controls = iced::widget::row![
+            iced::widget::Button::new("Pause").on_press_maybe((!paused).then_some(Message::Pause)),
+            iced::widget::Button::new("Step").on_press(Message::Step),
+            iced::widget::Button::new("Continue")
+                .on_press_maybe(paused.then_some(Message::Continue)),
+        ]
+        .spacing(10);
+
+        let cols = iced::widget::column![
+            controls,
+            Self::registers(state),
+            iced::widget::Text::new("Disassembly"),
+            iced::widget::Scrollable::new(Self::disassembly(state.pc, memory))
+                .height(iced::Length::Fill),
+            iced::widget::Text::new("Stack"),
+            Self::stack(state),
+        ]
+        .spacing(10)
+        .width(iced::Length::Fixed(300.0));
+
+        iced::widget::Container::new(cols).padding(15).into()
+    }
+
+    fn registers(state: &CpuState) -> iced::Element<'static, Message> {
+        let mut regs = iced::widget::Column::new();
+        for (r, vals) in state.v.chunks(4).enumerate() {
+            let line = vals
+                .iter()
+                .enumerate()
+                .map(|(c, val)| format!("V{:X}={:02X}", r * 4 + c, val))
+                .collect::<Vec<_>>()
+                .join(" ");
+            regs = regs.push(mono(line));
+        }
+
+        let keys = (0..16)
+            .filter(|&k| state.keypad[k])
+            .map(|k| format!("{:X}", k))
+            .collect::<String>();
+
+        regs.push(mono(format!(
+            "PC={:03X}  I={:03X}  SP={:X}",
+            state.pc, state.i, state.sp
+        )))
+        .push(mono(format!("DT={:02X}   ST={:02X}", state.dt, state.st)))
+        .push(mono(format!(
+            "Keys: {}",
+            if keys.is_empty() { "-" } else { &keys }
+        )))
+        .into()
+    }
+
+    fn disassembly(pc: u16, memory: &[u8]) -> iced::Element<'static, Message> {
+        let mut lines = iced::widget::Column::new();
+        let start = pc.saturating_sub(DISASM_LINES * 2);
+
+        for addr in (start..pc.saturating_add(DISASM_LINES * 2)).step_by(2) {
+            let (hi, lo) = match (memory.get(addr as usize), memory.get(addr as usize + 1)) {
+                (Some(&hi), Some(&lo)) => (hi, lo),
+                _ => break,
+            };
+            let cmd = ((hi as u16) << 8) | lo as u16;
+            let text = format!(
+                "{} {:03X}: {:04X}  {}",
+                if addr == pc { ">" } else { " " },
+                addr,
+                cmd,
+                disassemble(cmd)
+            );
+
+            lines = lines.push(if addr == pc {
+                mono(text).style(iced::Color::from_rgb(0.85, 0.2, 0.2))
+            } else {
+                mono(text)
+            });
+        }
+
+        lines.into()
+    }
+
+    fn stack(state: &CpuState) -> iced::Element<'static, Message> {
+        if state.stack.is_empty() {
+            return mono(String::from("(empty)")).into();
+        }
+
+        state
+            .stack
+            .iter()
+            .enumerate()
+            .rev()
+            .fold(iced::widget::Column::new(), |col, (depth, addr)| {
+                col.push(mono(format!("{:X}: {:03X}", depth, addr)))
+            })
+            .into()
+    }
+}
@@ -1,45 +1,241 @@
+use crate::gui::mono;
+use crate::palette::{Palette, Preset, Rgb};
+
+const MAX_DECAY_FRAMES: u64 = 60; // Phosphor is fully dark well before this
+
+/// Post-processing applied to the display to hide XOR-draw flicker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    None,
+    Phosphor, // Lit pixels fade out over several frames
+    Blend,    // Average of the current and previous frame
+    MaxOfTwo, // Pixel is lit if it was lit in either of the last two frames
+}
+
+impl Filter {
+    const ALL: [Filter; 4] = [
+        Filter::None,
+        Filter::Phosphor,
+        Filter::Blend,
+        Filter::MaxOfTwo,
+    ];
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Filter::None => "No filter",
+            Filter::Phosphor => "Phosphor",
+            Filter::Blend => "Frame blend",
+            Filter::MaxOfTwo => "Max of two",
+        })
+    }
+}
+
+/// How the display is fitted into the space available to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    Integer, // Largest whole-number pixel size that fits, for crisp edges
+    Fit,     // Fill as much as possible keeping square pixels
+    Stretch, // Fill the whole area
+}
+
+impl Scaling {
+    const ALL: [Scaling; 3] = [Scaling::Integer, Scaling::Fit, Scaling::Stretch];
+}
+
+impl std::fmt::Display for Scaling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Scaling::Integer => "Integer scale",
+            Scaling::Fit => "Fit",
+            Scaling::Stretch => "Stretch",
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
-pub enum Message {}
+pub enum Message {
+    FilterSelected(Filter),
+    PersistenceChanged(f32),
+    PaletteSelected(Preset),
+    ScalingSelected(Scaling),
+    GridToggled(bool),
+    ToggleFullscreen,
+}
 
 pub struct Display {
-    buffer: [[bool; 64]; 32], // CHIP-8 display is 64 x 32
+    buffer: [[f32; 64]; 32], // CHIP-8 display is 64 x 32, pixel intensity 0.0 - 1.0
+    pub filter: Filter,
+    pub persistence: f32, // Phosphor brightness kept per emulated frame, 0.0 - 0.95
+    pub palette: Palette,
+    pub preset: Option<Preset>, // None for a custom palette from the config file
+    pub scaling: Scaling,
+    pub grid: bool, // Draw lines between pixels
+    pub fullscreen: bool,
+    pub scale: Option<u16>, // Fixed screen pixels per CHIP-8 pixel, outside fullscreen
+    count: u64,             // Emulated frame last shown
+    version: u64,           // Display version last shown
+    settled: bool,          // Filter output stopped changing at this version
     cache: iced::widget::canvas::Cache,
 }
 
 impl Display {
-    pub fn new() -> Self {
-        let mut display = Self {
-            buffer: [[false; 64]; 32],
+    pub fn new(palette: Palette, preset: Option<Preset>) -> Self {
+        let display = Self {
+            buffer: [[0.0; 64]; 32],
+            filter: Filter::None,
+            persistence: 0.7,
+            palette,
+            preset,
+            scaling: Scaling::Fit,
+            grid: false,
+            fullscreen: false,
+            scale: None,
+            count: 0,
+            version: 0,
+            settled: false,
             cache: iced::widget::canvas::Cache::default(),
         };
         // display.draw_test_pattern();
         display
     }
 
-    pub fn view(&self) -> iced::Element<Message> {
+    pub fn update(&mut self, message: Message) {
+        match message {
+            Message::FilterSelected(filter) => self.filter = filter,
+            Message::PersistenceChanged(persistence) => self.persistence = persistence,
+            Message::PaletteSelected(preset) => {
+                self.palette = preset.palette();
+                self.preset = Some(preset);
+            }
+            Message::ScalingSelected(scaling) => self.scaling = scaling,
+            Message::GridToggled(grid) => self.grid = grid,
+            Message::ToggleFullscreen => self.fullscreen = !self.fullscreen,
+        }
+        self.settled = false;
+        self.cache.clear();
+    }
+
+    pub fn set_palette(&mut self, palette: Palette, preset: Option<Preset>) {
+        self.palette = palette;
+        self.preset = preset;
+        self.cache.clear();
+    }
+
+    pub fn view(&self) -> iced::Element<'_, Message> {
+        let (width, height) = match self.scale {
+            Some(scale) if !self.fullscreen => (
+                iced::Length::Fixed(64.0 * scale as f32),
+                iced::Length::Fixed(32.0 * scale as f32),
+            ),
+            _ => (iced::Length::Fill, iced::Length::Fill),
+        };
         iced::widget::Canvas::new(self)
-            .width(iced::Length::Fill)
-            .height(iced::Length::Fill)
+            .width(width)
+            .height(height)
             .into()
     }
 
+    pub fn controls(&self) -> iced::Element<'_, Message> {
+        let mut swatches = iced::widget::Row::new().spacing(2);
+        for &rgb in &self.palette.colors {
+            swatches = swatches.push(
+                iced::widget::Container::new(iced::widget::Space::new(12, 12)).style(
+                    iced::widget::container::Appearance::default().with_background(color(rgb)),
+                ),
+            );
+        }
+
+        let mut row = iced::widget::row![
+            iced::widget::PickList::new(Preset::ALL, self.preset, Message::PaletteSelected)
+                .placeholder("Custom"),
+            swatches,
+            iced::widget::PickList::new(Filter::ALL, Some(self.filter), Message::FilterSelected),
+            iced::widget::PickList::new(Scaling::ALL, Some(self.scaling), Message::ScalingSelected),
+            iced::widget::Checkbox::new("Grid", self.grid).on_toggle(Message::GridToggled),
+            iced::widget::Button::new("Fullscreen").on_press(Message::ToggleFullscreen),
+        ]
+        .spacing(10)
+        .align_items(iced::Alignment::Center);
+
+        if self.filter == Filter::Phosphor {
+            row = row
+                .push(
+                    iced::widget::Slider::new(
+                        0.0..=0.95,
+                        self.persistence,
+                        Message::PersistenceChanged,
+                    )
+                    .step(0.05)
+                    .width(iced::Length::Fixed(150.0)),
+                )
+                .push(mono(format!("{:.2}", self.persistence)));
+        }
+        row.into()
+    }
+
+    #[allow(dead_code)]
     pub fn draw_test_pattern(&mut self) {
         // [y][x] --> max: [31, 63]]
-        self.buffer[5][5] = true;
-        self.buffer[9][13] = true;
-        self.buffer[10][10] = true;
-        self.buffer[15][15] = true;
-        self.buffer[27][60] = true;
-        self.buffer[19][38] = true;
-        self.buffer[30][45] = true;
+        self.buffer[5][5] = 1.0;
+        self.buffer[9][13] = 1.0;
+        self.buffer[10][10] = 1.0;
+        self.buffer[15][15] = 1.0;
+        self.buffer[27][60] = 1.0;
+        self.buffer[19][38] = 1.0;
+        self.buffer[30][45] = 1.0;
+    }
+
+    /// Show emulated frame `count`. Filters advance by emulated frames, not redraws,
+    /// so the output is the same at any host refresh rate or emulation speed.
+    /// `version` changes whenever the emulator draws, so idle frames cost nothing.
+    pub fn update_frame(
+        &mut self,
+        current: &[[bool; 64]; 32],
+        previous: &[[bool; 64]; 32],
+        count: u64,
+        version: u64,
+    ) {
+        let elapsed = count.saturating_sub(self.count).min(MAX_DECAY_FRAMES);
+        self.count = count;
+        // Nothing drawn and every filter has run its course, the output can't change
+        if version == self.version && self.settled {
+            return;
+        }
+        self.version = version;
+        self.apply_filter(current, previous, elapsed);
     }
 
-    pub fn update(&mut self, new_disp: [[bool; 64]; 32]) {
+    /// Filter the frame already shown again, after the filter setting changed. The
+    /// emulator may be paused, so no new frame would come along to pick it up.
+    pub fn refilter(&mut self, current: &[[bool; 64]; 32], previous: &[[bool; 64]; 32]) {
+        self.apply_filter(current, previous, 0);
+    }
+
+    fn apply_filter(
+        &mut self,
+        current: &[[bool; 64]; 32],
+        previous: &[[bool; 64]; 32],
+        elapsed: u64,
+    ) {
+        let fade = self.persistence.powi(elapsed as i32);
+
         let mut changed = false;
-        for y in 0..32 {
-            for x in 0..64 {
-                if new_disp[y][x] != self.buffer[y][x] {
-                    self.buffer[y][x] = new_disp[y][x];
+        for (y, row) in self.buffer.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                let (now, before) = (current[y][x], previous[y][x]);
+                let value = match self.filter {
+                    Filter::None => now as u8 as f32,
+                    Filter::Phosphor if now => 1.0,
+                    Filter::Phosphor => *cell * fade,
+                    Filter::Blend => (now as u8 + before as u8) as f32 / 2.0,
+                    Filter::MaxOfTwo => (now || before) as u8 as f32,
+                };
+                // Snap faint pixels off so the display settles and stops redrawing
+                let value = if value < 0.02 { 0.0 } else { value };
+                if *cell != value {
+                    *cell = value;
                     changed = true;
                 }
             }
@@ -47,6 +243,7 @@ impl Display {
         if changed {
             self.cache.clear();
         }
+        self.settled = !changed;
     }
 }
 
@@ -62,29 +259,119 @@ impl<Message> iced::widget::canvas::Program<Message> for Display {
         _cursor: iced::mouse::Cursor,
     ) -> Vec<iced::widget::canvas::Geometry> {
         let screen = self.cache.draw(renderer, bounds.size(), |frame| {
-            let w: f32 = 7.0;
-            let h: f32 = 7.0;
+            let (cols, rows) = (self.buffer[0].len() as f32, self.buffer.len() as f32);
+            let (w, h) = match self.scaling {
+                Scaling::Stretch => (bounds.width / cols, bounds.height / rows),
+                Scaling::Fit => {
+                    let size = (bounds.width / cols).min(bounds.height / rows);
+                    (size, size)
+                }
+                Scaling::Integer => {
+                    let size = (bounds.width / cols)
+                        .min(bounds.height / rows)
+                        .floor()
+                        .max(1.0);
+                    (size, size)
+                }
+            };
+            // Centre the display in the leftover space
+            let origin = iced::Point::new(
+                ((bounds.width - w * cols) / 2.0).floor(),
+                ((bounds.height - h * rows) / 2.0).floor(),
+            );
+
+            let on = color(self.palette.foreground());
+            let off = color(self.palette.background());
+            let mix = |t: f32| {
+                iced::Color::from_rgb(
+                    off.r + (on.r - off.r) * t,
+                    off.g + (on.g - off.g) * t,
+                    off.b + (on.b - off.b) * t,
+                )
+            };
 
             for (y, row) in self.buffer.iter().enumerate() {
                 for (x, &cell) in row.iter().enumerate() {
-                    if cell {
-                        let path = iced::widget::canvas::Path::rectangle(
-                            iced::Point::new(x as f32 * w, y as f32 * h),
-                            iced::Size::new(w, h),
-                        );
-                        frame.fill(&path, iced::Color::BLACK);
-                    } else {
-                        // Fill empty cells light gray
-                        let path = iced::widget::canvas::Path::rectangle(
-                            iced::Point::new(x as f32 * w, y as f32 * h),
-                            iced::Size::new(w, h),
-                        );
-                        frame.fill(&path, iced::Color::from_rgb(0.95, 0.95, 0.95));
-                    }
+                    let path = iced::widget::canvas::Path::rectangle(
+                        iced::Point::new(origin.x + x as f32 * w, origin.y + y as f32 * h),
+                        iced::Size::new(w, h),
+                    );
+                    frame.fill(&path, mix(cell));
                 }
             }
+
+            // Grid lines are skipped when pixels are too small for them to be useful
+            if self.grid && w >= 4.0 && h >= 4.0 {
+                let stroke = iced::widget::canvas::Stroke::default()
+                    .with_width(1.0)
+                    .with_color(mix(0.25));
+                let grid = iced::widget::canvas::Path::new(|path| {
+                    for x in 1..self.buffer[0].len() {
+                        let px = origin.x + x as f32 * w;
+                        path.move_to(iced::Point::new(px, origin.y));
+                        path.line_to(iced::Point::new(px, origin.y + h * rows));
+                    }
+                    for y in 1..self.buffer.len() {
+                        let py = origin.y + y as f32 * h;
+                        path.move_to(iced::Point::new(origin.x, py));
+                        path.line_to(iced::Point::new(origin.x + w * cols, py));
+                    }
+                });
+                frame.stroke(&grid, stroke);
+            }
         });
 
         vec![screen]
     }
 }
+
+fn color(rgb: Rgb) -> iced::Color {
+    iced::Color::from_rgb8(rgb.0, rgb.1, rgb.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two frames with the left pixel lit in the first and the right one in the second.
+    fn frames() -> ([[bool; 64]; 32], [[bool; 64]; 32]) {
+        let (mut previous, mut current) = ([[false; 64]; 32], [[false; 64]; 32]);
+        previous[0][0] = true;
+        current[0][1] = true;
+        (current, previous)
+    }
+
+    #[test]
+    fn filters_blend_the_last_two_frames() {
+        let (current, previous) = frames();
+        let mut display = Display::new(Preset::Classic.palette(), Some(Preset::Classic));
+        for (filter, expected) in [
+            (Filter::None, [0.0, 1.0]),
+            (Filter::Blend, [0.5, 0.5]),
+            (Filter::MaxOfTwo, [1.0, 1.0]),
+        ] {
+            display.update(Message::FilterSelected(filter));
+            display.refilter(&current, &previous);
+            assert_eq!(display.buffer[0][..2], expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn phosphor_fades_by_emulated_frames() {
+        let (current, previous) = frames();
+        let mut display = Display::new(Preset::Classic.palette(), None);
+        display.update(Message::FilterSelected(Filter::Phosphor));
+        display.update(Message::PersistenceChanged(0.5));
+        display.update_frame(&previous, &previous, 1, 1);
+        assert_eq!(display.buffer[0][..2], [1.0, 0.0]);
+
+        // Two frames later the old pixel has faded twice
+        display.update_frame(&current, &previous, 3, 2);
+        assert_eq!(display.buffer[0][..2], [0.25, 1.0]);
+
+        // Switching filter while paused shows at once, without a new frame
+        display.update(Message::FilterSelected(Filter::None));
+        display.refilter(&current, &previous);
+        assert_eq!(display.buffer[0][..2], [0.0, 1.0]);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::keymap::{KeyMap, LAYOUT};
+use crate::runner::Command;
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Press(usize),
+    Release, // Mouse button or finger lifted anywhere in the window
+}
+
+/// On-screen 4x4 keypad, lights up pressed keys and accepts mouse/touch input.
+pub struct Keypad {
+    held: Option<usize>, // Key under the mouse when the button went down
+}
+
+impl Keypad {
+    pub fn new() -> Self {
+        Self { held: None }
+    }
+
+    /// Handle a keypad message, returning the command for the emulation thread.
+    /// Releases come from window-wide events, so a key is let go even when the
+    /// pointer has left its button.
+    pub fn update(&mut self, message: Message) -> Option<Command> {
+        match message {
+            Message::Press(key) => {
+                self.held = Some(key);
+                Some(Command::Key(key, true))
+            }
+            Message::Release => self.held.take().map(|key| Command::Key(key, false)),
+        }
+    }
+
+    pub fn view(&self, pressed: &[bool; 16], key_map: &KeyMap) -> iced::Element<'_, Message> {
+        let mut rows = iced::widget::Column::new().spacing(4);
+
+        for row in LAYOUT.chunks(4) {
+            let mut keys = iced::widget::Row::new().spacing(4);
+            for &key in row {
+                let label = iced::widget::Text::new(format!(
+                    "{:X}\n{}",
+                    key,
+                    key_map.host_key(key).to_ascii_uppercase()
+                ))
+                .size(14)
+                .horizontal_alignment(iced::alignment::Horizontal::Center);
+
+                let cell = iced::widget::Container::new(if pressed[key] {
+                    label.style(iced::Color::from_rgb(0.85, 0.2, 0.2))
+                } else {
+                    label
+                })
+                .width(iced::Length::Fixed(40.0))
+                .height(iced::Length::Fixed(40.0))
+                .center_x()
+                .center_y()
+                .style(if pressed[key] {
+                    iced::theme::Container::Box
+                } else {
+                    iced::theme::Container::Transparent
+                });
+
+                keys = keys.push(iced::widget::MouseArea::new(cell).on_press(Message::Press(key)));
+            }
+            rows = rows.push(keys);
+        }
+
+        iced::widget::Container::new(rows).padding(15).into()
+    }
+}
@@ -0,0 +1,136 @@
+use crate::romdb::{self, RomDb};
+use crate::romfile::is_rom;
+use log::{error, info};
+
+/// A ROM found in the library directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: String,
+    pub title: String, // Title from the ROM database, or the file name
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Toggle,
+    DirChanged(String),
+    Scan,
+    Scanned(Vec<Entry>),
+    Open(String), // Load the ROM at a path
+}
+
+pub struct Library {
+    pub visible: bool,
+    pub dir: String,
+    entries: Vec<Entry>,
+}
+
+impl Library {
+    pub fn new(dir: Option<String>) -> Self {
+        Self {
+            visible: false,
+            dir: dir.unwrap_or_else(|| String::from("roms")),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Handle a library message, scanning in the background when asked to.
+    pub fn update(&mut self, message: Message) -> iced::Command<Message> {
+        match message {
+            Message::Toggle => {
+                self.visible = !self.visible;
+                if self.visible && self.entries.is_empty() {
+                    return self.update(Message::Scan);
+                }
+            }
+            Message::DirChanged(dir) => self.dir = dir,
+            Message::Scan => {
+                let dir = self.dir.clone();
+                return iced::Command::perform(async move { scan(&dir) }, Message::Scanned);
+            }
+            Message::Scanned(entries) => self.entries = entries,
+            Message::Open(_) => {}
+        }
+        iced::Command::none()
+    }
+
+    pub fn toggle_view(&self) -> iced::Element<'_, Message> {
+        iced::widget::Button::new(if self.visible {
+            "Hide Library"
+        } else {
+            "Show Library"
+        })
+        .on_press(Message::Toggle)
+        .into()
+    }
+
+    pub fn view(&self) -> iced::Element<'_, Message> {
+        let controls = iced::widget::row![
+            iced::widget::TextInput::new("ROM directory", &self.dir)
+                .on_input(Message::DirChanged)
+                .on_submit(Message::Scan),
+            iced::widget::Button::new("Scan").on_press(Message::Scan),
+        ]
+        .spacing(10);
+
+        let mut list = iced::widget::Column::new().spacing(2);
+        for entry in &self.entries {
+            let label = match &entry.platform {
+                Some(platform) => format!("{} ({})", entry.title, platform),
+                None => entry.title.clone(),
+            };
+            list = list.push(
+                iced::widget::Button::new(iced::widget::Text::new(label))
+                    .on_press(Message::Open(entry.path.clone()))
+                    .style(iced::theme::Button::Text)
+                    .width(iced::Length::Fill),
+            );
+        }
+        if self.entries.is_empty() {
+            list = list.push(iced::widget::Text::new("No ROMs found."));
+        }
+
+        iced::widget::column![
+            controls,
+            iced::widget::Scrollable::new(list).height(iced::Length::Fixed(200.0)),
+        ]
+        .spacing(10)
+        .width(iced::Length::Fixed(320.0))
+        .padding(15)
+        .into()
+    }
+}
+
+/// List the ROMs in a directory, named from the ROM database where possible.
+fn scan(dir: &str) -> Vec<Entry> {
+    let files = match std::fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) => {
+            error!("Failed to read ROM directory '{}': {}", dir, e);
+            return Vec::new();
+        }
+    };
+
+    let mut entries: Vec<Entry> = files
+        .filter_map(|file| file.ok().map(|file| file.path()))
+        .filter(|path| path.is_file() && is_rom(path))
+        .map(|path| {
+            let info = std::fs::read(&path)
+                .ok()
+                .and_then(|bytes| RomDb::get().lookup(&romdb::sha1(&bytes)));
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Entry {
+                path: path.display().to_string(),
+                title: info.as_ref().map_or(name, |info| info.title.clone()),
+                platform: info.map(|info| info.platform),
+            }
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.title.to_lowercase());
+
+    info!("Found {} ROMs in '{}'", entries.len(), dir);
+    entries
+}
@@ -0,0 +1,190 @@
+use crate::gui::mono;
+use log::warn;
+
+const ROW_BYTES: usize = 16; // Bytes per row in the hex grid
+const PAGE_ROWS: usize = 16; // Rows shown per page (256 bytes)
+const FLASH_FRAMES: u8 = 20; // Frames a changed byte stays highlighted
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Toggle,
+    GoToChanged(String),
+    GoTo,
+    PageUp,
+    PageDown,
+    Select(usize),
+    ValueChanged(String),
+    Write,
+}
+
+pub struct MemoryViewer {
+    pub visible: bool,
+    offset: usize,      // First address shown in the grid
+    goto_input: String, // "Go to address" text box
+    selected: Option<usize>,
+    value_input: String, // Hex byte to write at `selected`
+    previous: Vec<u8>,   // Memory as of the last frame
+    flash: Vec<u8>,      // Remaining highlight frames per byte
+}
+
+impl MemoryViewer {
+    pub fn new() -> Self {
+        Self {
+            visible: false,
+            offset: 0x200,
+            goto_input: String::new(),
+            selected: None,
+            value_input: String::new(),
+            previous: Vec::new(),
+            flash: Vec::new(),
+        }
+    }
+
+    /// Compare against the previous frame's memory and start highlighting any changed bytes.
+    pub fn update_memory(&mut self, memory: &[u8]) {
+        if self.previous.len() != memory.len() {
+            self.previous = memory.to_vec();
+            self.flash = vec![0; memory.len()];
+            return;
+        }
+
+        for ((prev, &new), flash) in self
+            .previous
+            .iter_mut()
+            .zip(memory.iter())
+            .zip(self.flash.iter_mut())
+        {
+            if *prev != new {
+                *prev = new;
+                *flash = FLASH_FRAMES;
+            } else {
+                *flash = flash.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Handle a viewer message, returning an `(address, value)` write for the running machine.
+    pub fn update(&mut self, message: Message) -> Option<(usize, u8)> {
+        let size = self.previous.len().max(ROW_BYTES * PAGE_ROWS);
+        let page = ROW_BYTES * PAGE_ROWS;
+
+        match message {
+            Message::Toggle => self.visible = !self.visible,
+            Message::GoToChanged(text) => self.goto_input = text,
+            Message::GoTo => {
+                let text = self.goto_input.trim().trim_start_matches("0x");
+                match usize::from_str_radix(text, 16) {
+                    Ok(addr) if addr < size => {
+                        self.offset = (addr - addr % ROW_BYTES).min(size - page);
+                        self.selected = Some(addr);
+                    }
+                    _ => warn!("Invalid memory address '{}'", self.goto_input),
+                }
+            }
+            Message::PageUp => self.offset = self.offset.saturating_sub(page),
+            Message::PageDown => self.offset = (self.offset + page).min(size - page),
+            Message::Select(addr) => {
+                self.selected = Some(addr);
+                self.value_input = self
+                    .previous
+                    .get(addr)
+                    .map(|b| format!("{:02X}", b))
+                    .unwrap_or_default();
+            }
+            Message::ValueChanged(text) => self.value_input = text,
+            Message::Write => {
+                let addr = self.selected?;
+                match u8::from_str_radix(self.value_input.trim(), 16) {
+                    Ok(value) => return Some((addr, value)),
+                    Err(_) => warn!("Invalid byte value '{}'", self.value_input),
+                }
+            }
+        }
+        None
+    }
+
+    pub fn toggle_view(&self) -> iced::Element<'_, Message> {
+        iced::widget::Button::new(if self.visible {
+            "Hide Memory"
+        } else {
+            "Show Memory"
+        })
+        .on_press(Message::Toggle)
+        .into()
+    }
+
+    pub fn view(&self, pc: u16, i: u16) -> iced::Element<'_, Message> {
+        let nav = iced::widget::row![
+            iced::widget::TextInput::new("Go to address", &self.goto_input)
+                .on_input(Message::GoToChanged)
+                .on_submit(Message::GoTo)
+                .width(iced::Length::Fixed(120.0)),
+            iced::widget::Button::new("Go").on_press(Message::GoTo),
+            iced::widget::Button::new("<").on_press(Message::PageUp),
+            iced::widget::Button::new(">").on_press(Message::PageDown),
+        ]
+        .spacing(5)
+        .align_items(iced::Alignment::Center);
+
+        let mut grid = iced::widget::Column::new();
+        let end = (self.offset + ROW_BYTES * PAGE_ROWS).min(self.previous.len());
+        for row_addr in (self.offset..end).step_by(ROW_BYTES) {
+            let bytes = &self.previous[row_addr..(row_addr + ROW_BYTES).min(end)];
+            let mut row = iced::widget::Row::new().push(mono(format!("{:03X}:", row_addr)));
+
+            for (col, &byte) in bytes.iter().enumerate() {
+                let addr = row_addr + col;
+                let mut text = mono(format!("{:02X}", byte));
+                if addr == pc as usize || addr == pc as usize + 1 {
+                    text = text.style(iced::Color::from_rgb(0.85, 0.2, 0.2));
+                } else if addr == i as usize {
+                    text = text.style(iced::Color::from_rgb(0.2, 0.4, 0.9));
+                } else if self.flash[addr] > 0 {
+                    text = text.style(iced::Color::from_rgb(0.95, 0.6, 0.0));
+                }
+
+                row = row.push(
+                    iced::widget::Button::new(text)
+                        .padding([0, 3])
+                        .style(if self.selected == Some(addr) {
+                            iced::theme::Button::Primary
+                        } else {
+                            iced::theme::Button::Text
+                        })
+                        .on_press(Message::Select(addr)),
+                );
+            }
+
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+            grid = grid.push(row.push(mono(format!(" {}", ascii))));
+        }
+
+        let edit = iced::widget::row![
+            mono(match self.selected {
+                Some(addr) => format!("[{:03X}] =", addr),
+                None => String::from("[---] ="),
+            }),
+            iced::widget::TextInput::new("Hex", &self.value_input)
+                .on_input(Message::ValueChanged)
+                .on_submit(Message::Write)
+                .width(iced::Length::Fixed(60.0)),
+            iced::widget::Button::new("Write")
+                .on_press_maybe(self.selected.map(|_| Message::Write)),
+        ]
+        .spacing(5)
+        .align_items(iced::Alignment::Center);
+
+        let cols = iced::widget::column![
+            nav,
+            grid,
+            edit,
+            mono(String::from("PC: red, I: blue, changed: orange")),
+        ]
+        .spacing(10);
+
+        iced::widget::Container::new(cols).padding(15).into()
+    }
+}
@@ -0,0 +1,78 @@
+use crate::runner::Command;
+use crate::screenshot::timestamped_path;
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    PathChanged(String),
+    Record,
+    Play,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    Recording,
+    Playing,
+}
+
+/// Record keypad input to a movie file and replay it.
+pub struct MoviePanel {
+    pub path: String,
+    pub state: State,
+}
+
+impl MoviePanel {
+    pub fn new() -> Self {
+        Self {
+            path: String::new(),
+            state: State::Idle,
+        }
+    }
+
+    /// Handle a movie message, returning the command for the emulation thread.
+    pub fn update(&mut self, message: Message) -> Option<Command> {
+        match message {
+            Message::PathChanged(path) => {
+                self.path = path;
+                None
+            }
+            Message::Record => {
+                if self.path.is_empty() {
+                    self.path = timestamped_path("c8m");
+                }
+                self.state = State::Recording;
+                Some(Command::RecordMovie(Some(self.path.clone())))
+            }
+            Message::Play => {
+                self.state = State::Playing;
+                Some(Command::PlayMovie(self.path.clone()))
+            }
+            Message::Stop => Some(Command::RecordMovie(None)),
+        }
+    }
+
+    pub fn view(&self) -> iced::Element<'_, Message> {
+        let idle = self.state == State::Idle;
+        let status = match self.state {
+            State::Idle => "",
+            State::Recording => "Recording",
+            State::Playing => "Playing",
+        };
+
+        iced::widget::row![
+            iced::widget::TextInput::new("Movie file (.c8m)", &self.path)
+                .on_input(Message::PathChanged)
+                .width(iced::Length::Fixed(250.0)),
+            iced::widget::Button::new("Record Movie")
+                .on_press_maybe(idle.then_some(Message::Record)),
+            iced::widget::Button::new("Play Movie")
+                .on_press_maybe((idle && !self.path.is_empty()).then_some(Message::Play)),
+            iced::widget::Button::new("Stop").on_press_maybe((!idle).then_some(Message::Stop)),
+            iced::widget::Text::new(status),
+        ]
+        .spacing(10)
+        .align_items(iced::Alignment::Center)
+        .into()
+    }
+}
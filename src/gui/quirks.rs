@@ -0,0 +1,71 @@
+use crate::cpu::Quirks;
+use crate::runner::Command;
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Toggle,
+    Changed(Quirks),
+    Reset, // Drop the override, going back to the ROM database or defaults
+}
+
+/// Quirk switches for the loaded ROM, saved as an override in its config section.
+pub struct QuirksPanel {
+    pub visible: bool,
+}
+
+impl QuirksPanel {
+    pub fn new() -> Self {
+        Self { visible: false }
+    }
+
+    /// Handle a quirks message, returning the command for the emulation thread.
+    pub fn update(&mut self, message: Message) -> Option<Command> {
+        match message {
+            Message::Toggle => {
+                self.visible = !self.visible;
+                None
+            }
+            Message::Changed(quirks) => Some(Command::SetQuirks(Some(quirks))),
+            Message::Reset => Some(Command::SetQuirks(None)),
+        }
+    }
+
+    pub fn toggle_view(&self) -> iced::Element<'_, Message> {
+        iced::widget::Button::new(if self.visible {
+            "Hide Quirks"
+        } else {
+            "Show Quirks"
+        })
+        .on_press(Message::Toggle)
+        .into()
+    }
+
+    /// `overridden` is whether the ROM has quirks of its own in the config file.
+    pub fn view(&self, quirks: Quirks, overridden: bool) -> iced::Element<'_, Message> {
+        let flag = |label: &str, on: bool, set: fn(&mut Quirks, bool)| {
+            iced::widget::Checkbox::new(label, on).on_toggle(move |on| {
+                let mut quirks = quirks;
+                set(&mut quirks, on);
+                Message::Changed(quirks)
+            })
+        };
+
+        let reset = overridden.then_some(Message::Reset);
+        iced::widget::Row::new()
+            .push(flag("Shift", quirks.shift, |q, on| q.shift = on))
+            .push(flag("Load/store", quirks.load_store, |q, on| {
+                q.load_store = on
+            }))
+            .push(flag("Increment X", quirks.increment_x, |q, on| {
+                q.increment_x = on
+            }))
+            .push(flag("Jump", quirks.jump, |q, on| q.jump = on))
+            .push(flag("VF reset", quirks.logic, |q, on| q.logic = on))
+            .push(flag("Wrap", quirks.wrap, |q, on| q.wrap = on))
+            .push(flag("VBlank", quirks.vblank, |q, on| q.vblank = on))
+            .push(iced::widget::Button::new("Reset").on_press_maybe(reset))
+            .spacing(10)
+            .align_items(iced::Alignment::Center)
+            .into()
+    }
+}
@@ -1,25 +1,40 @@
+use crate::romdb::RomInfo;
+use crate::romfile::EXTENSIONS;
+
 #[derive(Debug, Clone)]
 pub enum Message {
     RomPathChanged(String),
     LoadRom,
+    Browse,
+    Browsed(Option<String>), // Path picked in the file dialog, None if cancelled
+    RecentSelected(String),
+    EntrySelected(String), // ROM chosen from a zip archive
 }
 
 pub struct RomLoader {
     pub rom_path: String,
     pub size_bytes: usize,
     pub read_status: bool,
+    pub sha1: String,
+    pub archive: String,            // Zip archive holding several ROMs
+    pub entries: Vec<String>,       // ROMs in `archive` to choose between
+    pub info: Option<Box<RomInfo>>, // ROM database entry for the loaded ROM
 }
 
 impl RomLoader {
-    pub fn new() -> Self {
+    pub fn new(rom_path: String) -> Self {
         Self {
-            rom_path: String::from("roms/test_opcode.ch8"),
+            rom_path,
             size_bytes: 0,
             read_status: false,
+            sha1: String::new(),
+            archive: String::new(),
+            entries: Vec::new(),
+            info: None,
         }
     }
 
-    pub fn view(&self) -> iced::Element<Message> {
+    pub fn view<'a>(&'a self, recent: &'a [String]) -> iced::Element<'a, Message> {
         let content = iced::widget::row![
             iced::widget::Text::new("Load ROM: "),
             iced::widget::TextInput::new("Enter ROM Path", &self.rom_path)
@@ -27,11 +42,17 @@ impl RomLoader {
             iced::widget::Button::new("Load")
                 .on_press(Message::LoadRom)
                 .padding(15),
+            iced::widget::Button::new("Browse...")
+                .on_press(Message::Browse)
+                .padding(15),
+            iced::widget::PickList::new(recent, None::<String>, Message::RecentSelected)
+                .placeholder("Recent")
+                .width(iced::Length::Fixed(160.0)),
         ]
         .spacing(10)
         .align_items(iced::Alignment::Center);
 
-        let cols = iced::widget::column![
+        let mut cols = iced::widget::column![
             content,
             if self.read_status {
                 iced::widget::Text::new(format!(
@@ -45,8 +66,55 @@ impl RomLoader {
             }
         ];
 
+        if !self.entries.is_empty() {
+            let selected = crate::romfile::split(&self.rom_path).1.map(String::from);
+            cols = cols.push(
+                iced::widget::row![
+                    iced::widget::Text::new("ROM in archive: "),
+                    iced::widget::PickList::new(
+                        self.entries.as_slice(),
+                        selected,
+                        Message::EntrySelected
+                    )
+                    .placeholder("Choose a ROM"),
+                ]
+                .align_items(iced::Alignment::Center),
+            );
+        }
+
+        if let (true, Some(info)) = (self.read_status, &self.info) {
+            let mut title = info.title.clone();
+            if !info.authors.is_empty() {
+                title = format!("{} by {}", title, info.authors.join(", "));
+            }
+            if let Some(release) = &info.release {
+                title = format!("{} ({})", title, release);
+            }
+            cols =
+                cols.push(iced::widget::Text::new(title).size(20))
+                    .push(iced::widget::Text::new(format!(
+                        "Platform: {}",
+                        info.platform
+                    )));
+            if let Some(description) = &info.description {
+                cols = cols.push(iced::widget::Text::new(description.as_str()));
+            }
+        } else if self.read_status {
+            cols = cols.push(super::mono(format!("Unknown ROM, SHA-1 {}", self.sha1)));
+        }
+
         let container = iced::widget::Container::new(cols).padding(15);
 
         container.into()
     }
 }
+
+/// Ask for a ROM file with the platform's file dialog.
+pub async fn browse() -> Option<String> {
+    rfd::AsyncFileDialog::new()
+        .set_title("Load CHIP-8 ROM")
+        .add_filter("CHIP-8 ROM", &EXTENSIONS)
+        .pick_file()
+        .await
+        .map(|file| file.path().display().to_string())
+}
@@ -0,0 +1,82 @@
+use crate::gui::mono;
+use crate::runner::{Command, MAX_SPEED, MIN_SPEED};
+
+const PRESETS: [u32; 10] = [10, 25, 50, 75, 100, 150, 200, 300, 500, 1000]; // Hotkey speed steps
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    TogglePause(bool), // Currently paused
+    FrameAdvance,
+    SpeedChanged(u32),
+    Slower,
+    Faster,
+    Normal,
+    ToggleUncapped,
+}
+
+/// Pause, frame advance and emulation speed controls.
+pub struct SpeedControl {
+    pub speed: u32, // Percent of normal speed
+    pub uncapped: bool,
+}
+
+impl SpeedControl {
+    pub fn new() -> Self {
+        Self {
+            speed: 100,
+            uncapped: false,
+        }
+    }
+
+    /// Handle a speed message, returning the command for the emulation thread.
+    pub fn update(&mut self, message: Message) -> Option<Command> {
+        match message {
+            Message::TogglePause(paused) => Some(Command::Pause(!paused)),
+            Message::FrameAdvance => Some(Command::FrameAdvance),
+            Message::SpeedChanged(speed) => self.set_speed(speed),
+            Message::Slower => {
+                let speed = PRESETS.iter().rev().find(|&&p| p < self.speed);
+                self.set_speed(*speed.unwrap_or(&MIN_SPEED))
+            }
+            Message::Faster => {
+                let speed = PRESETS.iter().find(|&&p| p > self.speed);
+                self.set_speed(*speed.unwrap_or(&MAX_SPEED))
+            }
+            Message::Normal => self.set_speed(100),
+            Message::ToggleUncapped => {
+                self.uncapped = !self.uncapped;
+                Some(Command::SetUncapped(self.uncapped))
+            }
+        }
+    }
+
+    fn set_speed(&mut self, speed: u32) -> Option<Command> {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        Some(Command::SetSpeed(self.speed))
+    }
+
+    pub fn view(&self, paused: bool, ips: u64) -> iced::Element<'_, Message> {
+        let speed = if self.uncapped {
+            String::from("Uncapped")
+        } else {
+            format!("{:>4}%", self.speed)
+        };
+
+        iced::widget::row![
+            iced::widget::Button::new(if paused { "Resume" } else { "Pause" })
+                .on_press(Message::TogglePause(paused)),
+            iced::widget::Button::new("Frame").on_press(Message::FrameAdvance),
+            iced::widget::Slider::new(MIN_SPEED..=MAX_SPEED, self.speed, Message::SpeedChanged)
+                .step(10u32)
+                .width(iced::Length::Fixed(150.0)),
+            mono(speed),
+            iced::widget::Button::new("1x").on_press(Message::Normal),
+            iced::widget::Checkbox::new("Uncapped", self.uncapped)
+                .on_toggle(|_| Message::ToggleUncapped),
+            mono(format!("{:>8} IPS", ips)),
+        ]
+        .spacing(10)
+        .align_items(iced::Alignment::Center)
+        .into()
+    }
+}
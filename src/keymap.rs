@@ -0,0 +1,128 @@
+use thiserror::Error;
+
+// CHIP-8 keypad layout, row by row:
+//   1 2 3 C
+//   4 5 6 D
+//   7 8 9 E
+//   A 0 B F
+pub const LAYOUT: [usize; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+pub const DEFAULT_KEY_MAP: &str = "1234qwerasdfzxcv";
+
+// Shifted symbols of a US keyboard, and below each one the key it is typed on
+const SHIFTED: &str = "!@#$%^&*()_+{}|:\"<>?~";
+const UNSHIFTED: &str = "1234567890-=[]\\;',./`";
+
+#[derive(Error, Debug)]
+pub enum KeyMapError {
+    #[error("Key map must have exactly 16 keys, got {actual}")]
+    LengthError { actual: usize },
+    #[error("Key map assigns host key '{key}' more than once")]
+    DuplicateError { key: char },
+}
+
+/// Host keyboard to CHIP-8 keypad mapping.
+///
+/// Written as 16 host keys in keypad layout order (see `LAYOUT`), so the default
+/// "1234qwerasdfzxcv" puts the keypad on the left-hand block of a QWERTY keyboard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    keys: [char; 16], // Host key for each keypad value 0x0..0xF
+}
+
+impl KeyMap {
+    pub fn parse(spec: &str) -> Result<Self, KeyMapError> {
+        let chars: Vec<char> = spec.chars().map(unshifted).collect();
+        if chars.len() != 16 {
+            return Err(KeyMapError::LengthError {
+                actual: chars.len(),
+            });
+        }
+
+        let mut keys = [' '; 16];
+        for (pos, &c) in chars.iter().enumerate() {
+            if chars[..pos].contains(&c) {
+                return Err(KeyMapError::DuplicateError { key: c });
+            }
+            keys[LAYOUT[pos]] = c;
+        }
+        Ok(Self { keys })
+    }
+
+    /// Keypad value bound to a host key, if any. Shifted characters count as the
+    /// key they are typed on, so a key released after Shift is let go still matches.
+    pub fn lookup(&self, key: char) -> Option<usize> {
+        let key = unshifted(key);
+        self.keys.iter().position(|&k| k == key)
+    }
+
+    /// Host key bound to a keypad value.
+    pub fn host_key(&self, keypad: usize) -> char {
+        self.keys[keypad & 0xF]
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let mut keys = [' '; 16];
+        for (pos, c) in DEFAULT_KEY_MAP.chars().enumerate() {
+            keys[LAYOUT[pos]] = c;
+        }
+        Self { keys }
+    }
+}
+
+/// The unshifted character on the key that types `c`.
+fn unshifted(c: char) -> char {
+    match SHIFTED.find(c) {
+        Some(pos) => UNSHIFTED.as_bytes()[pos] as char,
+        None => c.to_ascii_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_in_keypad_layout_order() {
+        let map = KeyMap::parse("1234QWERasdfzxcv").unwrap();
+        assert_eq!(map, KeyMap::default());
+        assert_eq!(map.lookup('1'), Some(0x1));
+        assert_eq!(map.lookup('r'), Some(0xD));
+        assert_eq!(map.lookup('X'), Some(0x0));
+        assert_eq!(map.lookup('v'), Some(0xF));
+        assert_eq!(map.lookup('p'), None);
+        assert_eq!((map.host_key(0xC), map.host_key(0x0)), ('4', 'x'));
+
+        assert!(matches!(
+            KeyMap::parse("1234"),
+            Err(KeyMapError::LengthError { actual: 4 })
+        ));
+        assert!(matches!(
+            KeyMap::parse("1234qwerasdfzxcQ"),
+            Err(KeyMapError::DuplicateError { key: 'q' })
+        ));
+    }
+
+    #[test]
+    fn shifted_keys_match_the_key_they_are_typed_on() {
+        let map = KeyMap::default();
+        for (shifted, keypad) in [('!', 0x1), ('@', 0x2), ('#', 0x3), ('$', 0xC), ('Q', 0x4)] {
+            assert_eq!(map.lookup(shifted), Some(keypad), "{}", shifted);
+        }
+
+        let map = KeyMap::parse("7890uiopjkl;m,./").unwrap();
+        assert_eq!(map.lookup(':'), map.lookup(';'));
+        assert_eq!(map.lookup('?'), Some(0xF));
+        assert_eq!(map.lookup('<'), Some(0x0));
+        // Symbols in the spec mean the key they are typed on
+        assert_eq!(
+            KeyMap::parse("!@#$qwerasdfzxcv").unwrap(),
+            KeyMap::default()
+        );
+        assert!(KeyMap::parse("1!34qwerasdfzxcv").is_err());
+    }
+}
@@ -0,0 +1,8 @@
+//! The emulator core, shared by the `c8emu` frontends and the libretro core.
+
+pub mod cpu;
+pub mod env;
+pub mod octo;
+pub mod palette;
+pub mod romdb;
+pub mod romfile;
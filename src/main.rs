@@ -1,10 +1,74 @@
-mod cpu;
+mod args;
+mod audio;
+mod config;
 mod gui;
+mod keymap;
+mod movie;
+mod recorder;
+mod remote;
+mod runner;
+mod screenshot;
+mod tracediff;
+mod tui;
 
-use crate::gui::Gui;
+// The core lives in the library, for the libretro core to share
+use c8emu::{cpu, palette, romdb, romfile};
+
+use crate::args::{LaunchArgs, USAGE};
+use crate::config::Config;
+use crate::gui::{Flags, Gui};
 use iced::{Application, Settings};
+use log::error;
 
 fn main() -> iced::Result {
     env_logger::init();
-    Gui::run(Settings::default())
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("tracediff") {
+        std::process::exit(tracediff::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("record") {
+        std::process::exit(recorder::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("replay") {
+        std::process::exit(movie::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("screenshot") {
+        std::process::exit(screenshot::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("tui") {
+        std::process::exit(tui::run(&args[2..]));
+    }
+    if matches!(args.get(1).map(String::as_str), Some("--help" | "-h")) {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let launch = match LaunchArgs::parse(&args[1..]) {
+        Ok(launch) => launch,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let (config, config_error) = match Config::load() {
+        Ok(config) => (config, None),
+        Err(e) => {
+            error!("{}", e);
+            (Config::default(), Some(e.to_string()))
+        }
+    };
+    let window = iced::window::Settings {
+        size: iced::Size::new(config.window.width, config.window.height),
+        exit_on_close_request: false, // Settings are saved first
+        ..Default::default()
+    };
+    Gui::run(Settings {
+        window,
+        ..Settings::with_flags(Flags {
+            config,
+            config_error,
+            launch,
+        })
+    })
 }
@@ -0,0 +1,362 @@
+use crate::cpu::{Cpu, CpuError, Quirks};
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::Write as _;
+use std::fs;
+use thiserror::Error;
+
+const USAGE: &str = "usage: c8emu replay <movie> <rom>";
+const VERSION: u32 = 1;
+const SEPARATOR: &str = "---";
+const SYNC_INTERVAL: u64 = 60; // Frames between state checks
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum MovieError {
+    #[error("{}", USAGE)]
+    Usage,
+    #[error("Failed to read movie '{path}': {err}")]
+    ReadError { path: String, err: std::io::Error },
+    #[error("Failed to write movie '{path}': {err}")]
+    WriteError { path: String, err: std::io::Error },
+    #[error("Malformed movie '{path}': {msg}")]
+    ParseError { path: String, msg: String },
+    #[error("Load a ROM before recording or replaying a movie")]
+    NoRomError,
+    #[error("Movie was recorded with a different ROM ('{rom}')")]
+    RomMismatchError { rom: String },
+    #[error("Replay out of sync at frame {frame}")]
+    DesyncError { frame: u64 },
+    #[error("{err}")]
+    CpuError { err: CpuError },
+}
+
+/// Everything needed to start a replay from the same state as the recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MovieHeader {
+    pub version: u32,
+    pub rom: String,      // ROM file name, for reference
+    pub rom_hash: String, // Must match the ROM being replayed
+    #[serde(with = "hex_seed")]
+    pub seed: u64,
+    pub ipf: usize,
+    pub frames: u64, // Length of the recording
+    pub quirks: Quirks,
+}
+
+/// TOML integers are signed 64-bit, so seeds are written as hex strings.
+mod hex_seed {
+    use super::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(seed: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:016X}", seed))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        u64::from_str_radix(&text, 16).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Keypad input per frame, stored only when it changes, with periodic state hashes.
+///
+/// The file is the TOML header, a `---` line, then one `<frame> keys <mask>` or
+/// `<frame> sync <hash>` line per event, in hex.
+#[derive(Debug, Clone)]
+pub struct Movie {
+    pub header: MovieHeader,
+    pub inputs: Vec<(u64, u16)>, // (frame, keypad mask) from this frame on
+    pub syncs: Vec<(u64, u64)>,  // (frame, state hash) after this frame
+}
+
+impl Movie {
+    pub fn load(path: &str) -> Result<Self, MovieError> {
+        let text = fs::read_to_string(path).map_err(|e| MovieError::ReadError {
+            path: path.to_string(),
+            err: e,
+        })?;
+        let err = |msg: String| MovieError::ParseError {
+            path: path.to_string(),
+            msg,
+        };
+
+        // Line by line, so files saved with CRLF endings load too
+        let lines: Vec<&str> = text.lines().collect();
+        let split = lines
+            .iter()
+            .position(|line| line.trim_end() == SEPARATOR)
+            .ok_or_else(|| err(format!("missing '{}' after header", SEPARATOR)))?;
+        let header = lines[..split].join("\n");
+        let header: MovieHeader = toml::from_str(&header).map_err(|e| err(e.to_string()))?;
+        if header.version != VERSION {
+            return Err(err(format!("unsupported version {}", header.version)));
+        }
+
+        let mut movie = Movie {
+            header,
+            inputs: Vec::new(),
+            syncs: Vec::new(),
+        };
+        for (n, line) in lines
+            .iter()
+            .enumerate()
+            .skip(split + 1)
+            .filter(|(_, l)| !l.trim().is_empty())
+        {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields[..] {
+                [frame, kind, value] => frame.parse::<u64>().ok().and_then(|frame| match kind {
+                    "keys" => u16::from_str_radix(value, 16)
+                        .ok()
+                        .map(|mask| movie.inputs.push((frame, mask))),
+                    "sync" => u64::from_str_radix(value, 16)
+                        .ok()
+                        .map(|hash| movie.syncs.push((frame, hash))),
+                    _ => None,
+                }),
+                _ => None,
+            };
+            if parsed.is_none() {
+                return Err(err(format!("bad event on line {}: '{}'", n + 1, line)));
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), MovieError> {
+        let mut text = toml::to_string(&self.header).map_err(|e| MovieError::ParseError {
+            path: path.to_string(),
+            msg: e.to_string(),
+        })?;
+        writeln!(text, "{}", SEPARATOR).ok();
+
+        // Merge both event lists back into frame order
+        let mut events: Vec<(u64, String)> = self
+            .inputs
+            .iter()
+            .map(|&(frame, mask)| (frame, format!("{} keys {:04X}", frame, mask)))
+            .chain(
+                self.syncs
+                    .iter()
+                    .map(|&(frame, hash)| (frame, format!("{} sync {:016X}", frame, hash))),
+            )
+            .collect();
+        events.sort_by_key(|(frame, _)| *frame);
+        for (_, line) in events {
+            writeln!(text, "{}", line).ok();
+        }
+
+        fs::write(path, text).map_err(|e| MovieError::WriteError {
+            path: path.to_string(),
+            err: e,
+        })?;
+        info!("Saved movie to '{}'", path);
+        Ok(())
+    }
+}
+
+/// Captures input from a freshly loaded ROM. Call around every `Cpu::run_frame`.
+pub struct MovieRecorder {
+    movie: Movie,
+    keys: Option<u16>, // Last recorded keypad mask
+}
+
+impl MovieRecorder {
+    pub fn start(cpu: &Cpu, rom: &str, ipf: usize) -> Self {
+        Self {
+            movie: Movie {
+                header: MovieHeader {
+                    version: VERSION,
+                    rom: rom.to_string(),
+                    rom_hash: format!("{:016X}", cpu.rom_hash()),
+                    seed: cpu.seed(),
+                    ipf,
+                    frames: 0,
+                    quirks: cpu.quirks(),
+                },
+                inputs: Vec::new(),
+                syncs: Vec::new(),
+            },
+            keys: None,
+        }
+    }
+
+    pub fn before_frame(&mut self, cpu: &Cpu) {
+        let keys = cpu.keypad_mask();
+        if self.keys != Some(keys) {
+            self.movie.inputs.push((cpu.frame_count(), keys));
+            self.keys = Some(keys);
+        }
+    }
+
+    pub fn after_frame(&mut self, cpu: &Cpu) {
+        let frame = cpu.frame_count();
+        self.movie.header.frames = frame;
+        if frame.is_multiple_of(SYNC_INTERVAL) {
+            self.movie.syncs.push((frame, cpu.state_hash()));
+        }
+    }
+
+    /// Write the movie, ending with a state check on the last frame.
+    pub fn finish(mut self, cpu: &Cpu, path: &str) -> Result<(), MovieError> {
+        let frame = self.movie.header.frames;
+        if self.movie.syncs.last().map(|&(f, _)| f) != Some(frame) {
+            self.movie.syncs.push((frame, cpu.state_hash()));
+        }
+        self.movie.save(path)
+    }
+}
+
+/// Feeds recorded input back into a `Cpu`. Call around every `Cpu::run_frame`.
+pub struct MoviePlayer {
+    movie: Movie,
+    next_input: usize,
+    next_sync: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        Self {
+            movie,
+            next_input: 0,
+            next_sync: 0,
+        }
+    }
+
+    /// Put a Cpu with the movie's ROM loaded into the recorded starting state.
+    pub fn start(&self, cpu: &mut Cpu) -> Result<(), MovieError> {
+        let header = &self.movie.header;
+        if format!("{:016X}", cpu.rom_hash()) != header.rom_hash {
+            return Err(MovieError::RomMismatchError {
+                rom: header.rom.clone(),
+            });
+        }
+        cpu.set_quirks(header.quirks);
+        cpu.set_seed(header.seed);
+        Ok(())
+    }
+
+    pub fn ipf(&self) -> usize {
+        self.movie.header.ipf
+    }
+
+    pub fn finished(&self, cpu: &Cpu) -> bool {
+        cpu.frame_count() >= self.movie.header.frames
+    }
+
+    pub fn before_frame(&mut self, cpu: &mut Cpu) {
+        let frame = cpu.frame_count();
+        while let Some(&(start, keys)) = self.movie.inputs.get(self.next_input) {
+            if start > frame {
+                break;
+            }
+            cpu.set_keypad_mask(keys);
+            self.next_input += 1;
+        }
+    }
+
+    /// Check the state against the recording, if it was hashed on this frame.
+    pub fn after_frame(&mut self, cpu: &Cpu) -> Result<(), MovieError> {
+        let frame = cpu.frame_count();
+        while let Some(&(at, hash)) = self.movie.syncs.get(self.next_sync) {
+            if at > frame {
+                break;
+            }
+            self.next_sync += 1;
+            if at == frame && hash != cpu.state_hash() {
+                return Err(MovieError::DesyncError { frame });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Entry point for `c8emu replay`, returns the process exit code: 0 when the replay
+/// stays in sync, 1 when it diverges and 2 on errors.
+pub fn run(args: &[String]) -> i32 {
+    match replay(args) {
+        Ok(frames) => {
+            println!("Replay in sync ({} frames)", frames);
+            0
+        }
+        Err(e @ MovieError::DesyncError { .. }) => {
+            println!("{}", e);
+            1
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+fn replay(args: &[String]) -> Result<u64, MovieError> {
+    let [movie, rom] = args else {
+        return Err(MovieError::Usage);
+    };
+    let mut player = MoviePlayer::new(Movie::load(movie)?);
+
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom)
+        .map_err(|e| MovieError::CpuError { err: e })?;
+    player.start(&mut cpu)?;
+
+    while !player.finished(&cpu) {
+        player.before_frame(&mut cpu);
+        cpu.run_frame(player.ipf())
+            .map_err(|e| MovieError::CpuError { err: e })?;
+        player.after_frame(&cpu)?;
+    }
+    Ok(cpu.frame_count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::DEFAULT_IPF;
+
+    const ROM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/roms/test_opcode.ch8");
+
+    #[test]
+    fn recorded_movie_replays_in_sync() {
+        let path = std::env::temp_dir().join(format!("c8emu-movie-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        // Tap keys 0-3 in turn while recording
+        let mut cpu = Cpu::new();
+        cpu.load_rom(ROM).unwrap();
+        let mut recorder = MovieRecorder::start(&cpu, "test_opcode.ch8", DEFAULT_IPF);
+        for frame in 0..150u64 {
+            cpu.set_keypad_mask(if frame % 40 < 10 {
+                1 << (frame / 40)
+            } else {
+                0
+            });
+            recorder.before_frame(&cpu);
+            cpu.run_frame(DEFAULT_IPF).unwrap();
+            recorder.after_frame(&cpu);
+        }
+        recorder.finish(&cpu, &path).unwrap();
+
+        let movie = Movie::load(&path).unwrap();
+        assert_eq!(movie.header.frames, 150);
+        assert_eq!(movie.inputs.len(), 8);
+        assert_eq!(movie.syncs.last(), Some(&(150, cpu.state_hash())));
+
+        let args = [path.clone(), ROM.to_string()];
+        assert_eq!(replay(&args).unwrap(), 150);
+
+        // Editors may save it back with CRLF line endings
+        let crlf = fs::read_to_string(&path).unwrap().replace('\n', "\r\n");
+        fs::write(&path, crlf).unwrap();
+        assert_eq!(replay(&args).unwrap(), 150);
+
+        // A replay that doesn't reach the recorded state is caught at the next sync
+        let mut tampered = movie;
+        tampered.syncs[0].1 ^= 1;
+        tampered.save(&path).unwrap();
+        let result = replay(&args);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(MovieError::DesyncError { frame: 60 })));
+    }
+}
@@ -0,0 +1,929 @@
+use std::collections::{HashMap, VecDeque};
+use thiserror::Error;
+
+const BASE: usize = 0x200; // Programs are assembled for the CHIP-8 load address
+const END: usize = 0x10000; // XO-CHIP programs may use a full 64K
+const MAX_EXPANDED: usize = 1 << 20; // Macro tokens per program, stops runaway recursion
+const MAX_DEPTH: usize = 256; // Nesting of `:calc` terms, keeps hostile input off the stack limit
+const ADDR_MAX: usize = 0xFFF; // Highest address a 12-bit operand can hold
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum OctoError {
+    #[error("Line {line}: {msg}")]
+    SyntaxError { line: usize, msg: String },
+    #[error("Line {line}: undefined name '{name}'")]
+    UndefinedError { line: usize, name: String },
+    #[error("Program is missing a 'main' label")]
+    NoMainError,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// How a forward reference is patched once its label is known.
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    Addr,       // Low 12 bits of the instruction at the address
+    Long,       // Whole 16-bit word at the address
+    Unpack(u8), // `:unpack` pair of 6xnn loads, with the high nibble
+    UnpackLong, // `:unpack long` pair of 6xnn loads
+}
+
+/// Open `begin`/`loop` blocks, with the addresses of jumps still to patch.
+enum Block {
+    If(usize),
+    Else(usize),
+    Loop { start: usize, exits: Vec<usize> },
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Assemble Octo source into a ROM image loaded at 0x200.
+///
+/// Covers the Octo language as written by hand and shared in cartridges: labels,
+/// constants and aliases, `:calc`, `:macro`, `:unpack` and `:next`, structured
+/// `if`/`begin`/`else`/`end` and `loop`/`while`/`again`, and the SUPER-CHIP and
+/// XO-CHIP instructions. `:stringmode` is not supported.
+pub fn assemble(source: &str) -> Result<Vec<u8>, OctoError> {
+    let mut asm = Assembler::new(tokenize(source));
+    asm.run()?;
+    Ok(asm.rom[BASE..asm.used.max(BASE + 2)].to_vec())
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (n, line) in source.lines().enumerate() {
+        for word in line.split_whitespace() {
+            if word.starts_with('#') {
+                break;
+            }
+            tokens.push_back(Token {
+                text: word.to_string(),
+                line: n + 1,
+            });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+    (digit.len() == 1)
+        .then(|| u8::from_str_radix(digit, 16).ok())
+        .flatten()
+}
+
+/// Skip instruction with the opposite sense, for `begin` and `while`.
+fn invert(skip: u16) -> u16 {
+    match skip >> 12 {
+        0x3 => skip + 0x1000,
+        0x4 => skip - 0x1000,
+        0x5 | 0x9 => skip ^ 0xC000,
+        _ if skip & 0xFF == 0xA1 => (skip & 0xFF00) | 0x9E,
+        _ => (skip & 0xFF00) | 0xA1,
+    }
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    used: usize,                 // End of the highest byte written
+    names: HashMap<String, f64>, // Labels and constants
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    expanded: usize,                            // Tokens produced by macros so far
+    fixups: Vec<(usize, String, Fixup, usize)>, // Address, name, kind, line
+    blocks: Vec<Block>,
+}
+
+impl Assembler {
+    fn new(tokens: VecDeque<Token>) -> Self {
+        Self {
+            tokens,
+            line: 1,
+            rom: vec![0; END],
+            here: BASE + 2, // Room for the jump to main
+            used: BASE,
+            names: HashMap::new(),
+            aliases: HashMap::from([
+                (String::from("unpack-hi"), 0),
+                (String::from("unpack-lo"), 1),
+            ]),
+            macros: HashMap::new(),
+            expanded: 0,
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), OctoError> {
+        while let Some(token) = self.next_token() {
+            self.statement(&token)?;
+        }
+        if !self.blocks.is_empty() {
+            return Err(self.error("'begin' or 'loop' without a matching 'end' or 'again'"));
+        }
+
+        for (addr, name, kind, line) in std::mem::take(&mut self.fixups) {
+            let value = *self
+                .names
+                .get(&name)
+                .ok_or(OctoError::UndefinedError { line, name })? as usize;
+            self.line = line;
+            self.patch(addr, value, kind)?;
+        }
+        let main = *self.names.get("main").ok_or(OctoError::NoMainError)? as usize;
+        let main = self.short(main)?;
+        let (here, used) = (self.here, self.used);
+        self.here = BASE;
+        self.word(0x1000 | main)?;
+        self.here = here;
+        self.used = used.max(BASE + 2);
+        Ok(())
+    }
+
+    fn error(&self, msg: impl Into<String>) -> OctoError {
+        OctoError::SyntaxError {
+            line: self.line,
+            msg: msg.into(),
+        }
+    }
+
+    fn next_token(&mut self) -> Option<String> {
+        let token = self.tokens.pop_front()?;
+        self.line = token.line;
+        Some(token.text)
+    }
+
+    fn token(&mut self) -> Result<String, OctoError> {
+        self.next_token()
+            .ok_or_else(|| self.error("Unexpected end of program"))
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), OctoError> {
+        let token = self.token()?;
+        if token != text {
+            return Err(self.error(format!("Expected '{}', got '{}'", text, token)));
+        }
+        Ok(())
+    }
+
+    fn byte(&mut self, value: u8) -> Result<(), OctoError> {
+        if self.here >= END {
+            return Err(self.error("Program is larger than 64K"));
+        }
+        self.rom[self.here] = value;
+        self.here += 1;
+        self.used = self.used.max(self.here);
+        Ok(())
+    }
+
+    fn word(&mut self, value: u16) -> Result<(), OctoError> {
+        self.byte((value >> 8) as u8)?;
+        self.byte(value as u8)
+    }
+
+    /// Check that an address fits the 12 bits of an instruction operand.
+    fn short(&self, addr: usize) -> Result<u16, OctoError> {
+        if addr > ADDR_MAX {
+            return Err(self.error(format!("Address 0x{:X} does not fit in 12 bits", addr)));
+        }
+        Ok(addr as u16)
+    }
+
+    fn patch(&mut self, addr: usize, value: usize, kind: Fixup) -> Result<(), OctoError> {
+        if matches!(kind, Fixup::Addr | Fixup::Unpack(_)) {
+            self.short(value)?;
+        }
+        match kind {
+            Fixup::Addr => {
+                self.rom[addr] = (self.rom[addr] & 0xF0) | ((value >> 8) & 0xF) as u8;
+                self.rom[addr + 1] = value as u8;
+            }
+            Fixup::Long => {
+                self.rom[addr] = (value >> 8) as u8;
+                self.rom[addr + 1] = value as u8;
+            }
+            Fixup::Unpack(nibble) => {
+                self.rom[addr + 1] = (nibble << 4) | ((value >> 8) & 0xF) as u8;
+                self.rom[addr + 3] = value as u8;
+            }
+            Fixup::UnpackLong => {
+                self.rom[addr + 1] = (value >> 8) as u8;
+                self.rom[addr + 3] = value as u8;
+            }
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: String, value: f64) -> Result<(), OctoError> {
+        if register(&name).is_some() || self.names.contains_key(&name) {
+            return Err(self.error(format!("The name '{}' is already defined", name)));
+        }
+        self.names.insert(name, value);
+        Ok(())
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        register(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn reg(&mut self) -> Result<u16, OctoError> {
+        let token = self.token()?;
+        register(&token)
+            .or_else(|| self.aliases.get(&token).copied())
+            .map(u16::from)
+            .ok_or_else(|| self.error(format!("Expected a register, got '{}'", token)))
+    }
+
+    /// A number, constant or `{ expression }`.
+    fn number(&mut self) -> Result<f64, OctoError> {
+        let token = self.token()?;
+        if token == "{" {
+            return self.calc();
+        }
+        parse_number(&token)
+            .or_else(|| self.names.get(&token).copied())
+            .ok_or_else(|| self.error(format!("Expected a number, got '{}'", token)))
+    }
+
+    fn value(&mut self, min: i64, max: i64) -> Result<u16, OctoError> {
+        let value = self.number()?.floor() as i64;
+        if value < min || value > max {
+            return Err(self.error(format!("Value {} out of range {}..{}", value, min, max)));
+        }
+        Ok((value & 0xFFFF) as u16)
+    }
+
+    fn value8(&mut self) -> Result<u16, OctoError> {
+        Ok(self.value(-128, 255)? & 0xFF)
+    }
+
+    fn nibble(&mut self) -> Result<u16, OctoError> {
+        self.value(0, 15)
+    }
+
+    /// An address for the instruction about to be written at `here`, patched later
+    /// when the label isn't defined yet.
+    fn addr(&mut self, kind: Fixup) -> Result<u16, OctoError> {
+        let token = self.peek().unwrap_or_default().to_string();
+        let known =
+            token == "{" || parse_number(&token).is_some() || self.names.contains_key(&token);
+        if known {
+            let max = match kind {
+                Fixup::Addr | Fixup::Unpack(_) => ADDR_MAX,
+                Fixup::Long | Fixup::UnpackLong => END - 1,
+            };
+            return self.value(0, max as i64);
+        }
+        self.token()?;
+        self.fixups.push((self.here, token, kind, self.line));
+        Ok(0)
+    }
+
+    fn addr12(&mut self) -> Result<u16, OctoError> {
+        self.addr(Fixup::Addr)
+    }
+
+    fn statement(&mut self, token: &str) -> Result<(), OctoError> {
+        if self.macros.contains_key(token) {
+            return self.expand(token);
+        }
+        if self.is_register(token) {
+            self.tokens.push_front(Token {
+                text: token.to_string(),
+                line: self.line,
+            });
+            return self.assignment();
+        }
+        if let Some(value) = parse_number(token) {
+            return self.byte(value as i64 as u8);
+        }
+
+        match token {
+            ":" => {
+                let name = self.token()?;
+                self.define(name, self.here as f64)?;
+            }
+            ":alias" => {
+                let name = self.token()?;
+                let reg = self.reg()? as u8;
+                self.aliases.insert(name, reg);
+            }
+            ":const" => {
+                let name = self.token()?;
+                let value = self.number()?;
+                self.define(name, value)?;
+            }
+            ":calc" => {
+                let name = self.token()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.names.insert(name, value);
+            }
+            ":byte" => {
+                let value = self.value8()?;
+                self.byte(value as u8)?;
+            }
+            ":pointer" => {
+                let addr = self.addr(Fixup::Long)?;
+                self.word(addr)?;
+            }
+            ":org" => self.here = self.value(0, END as i64 - 1)? as usize,
+            ":next" => {
+                let name = self.token()?;
+                self.define(name, self.here as f64 + 1.0)?;
+            }
+            ":unpack" => {
+                let addr = if self.peek() == Some("long") {
+                    self.token()?;
+                    self.addr(Fixup::UnpackLong)?
+                } else {
+                    let nibble = self.nibble()?;
+                    (nibble << 12) | self.addr(Fixup::Unpack(nibble as u8))?
+                };
+                let (hi, lo) = (
+                    self.aliases["unpack-hi"] as u16,
+                    self.aliases["unpack-lo"] as u16,
+                );
+                self.word(0x6000 | (hi << 8) | (addr >> 8))?;
+                self.word(0x6000 | (lo << 8) | (addr & 0xFF))?;
+            }
+            ":call" => {
+                let addr = self.addr12()?;
+                self.word(0x2000 | addr)?;
+            }
+            ":macro" => self.define_macro()?,
+            ":breakpoint" => {
+                self.token()?;
+            }
+            ":monitor" => {
+                self.token()?;
+                self.token()?;
+            }
+            ":assert" => {
+                if self.peek() != Some("{") {
+                    self.token()?;
+                }
+                self.expect("{")?;
+                if self.calc()? == 0.0 {
+                    return Err(self.error("Assertion failed"));
+                }
+            }
+            ":stringmode" => return Err(self.error(":stringmode is not supported")),
+            ";" | "return" => self.word(0x00EE)?,
+            "clear" => self.word(0x00E0)?,
+            "hires" => self.word(0x00FF)?,
+            "lores" => self.word(0x00FE)?,
+            "exit" => self.word(0x00FD)?,
+            "scroll-left" => self.word(0x00FC)?,
+            "scroll-right" => self.word(0x00FB)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.word(0x00C0 | n)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.word(0x00D0 | n)?;
+            }
+            "audio" => self.word(0xF002)?,
+            "plane" => {
+                let n = self.nibble()?;
+                self.word(0xF001 | (n << 8))?;
+            }
+            "bcd" => {
+                let x = self.reg()?;
+                self.word(0xF033 | (x << 8))?;
+            }
+            "save" | "load" => {
+                let x = self.reg()?;
+                let store = token == "save";
+                if self.peek() == Some("-") {
+                    self.token()?;
+                    let y = self.reg()?;
+                    let op = if store { 0x5002 } else { 0x5003 };
+                    self.word(op | (x << 8) | (y << 4))?;
+                } else {
+                    let op = if store { 0xF055 } else { 0xF065 };
+                    self.word(op | (x << 8))?;
+                }
+            }
+            "saveflags" => {
+                let x = self.reg()?;
+                self.word(0xF075 | (x << 8))?;
+            }
+            "loadflags" => {
+                let x = self.reg()?;
+                self.word(0xF085 | (x << 8))?;
+            }
+            "sprite" => {
+                let x = self.reg()?;
+                let y = self.reg()?;
+                let n = self.nibble()?;
+                self.word(0xD000 | (x << 8) | (y << 4) | n)?;
+            }
+            "jump" => {
+                let addr = self.addr12()?;
+                self.word(0x1000 | addr)?;
+            }
+            "jump0" => {
+                let addr = self.addr12()?;
+                self.word(0xB000 | addr)?;
+            }
+            "native" => {
+                let addr = self.addr12()?;
+                self.word(addr)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.reg()?;
+                let op = match token {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.word(op | (x << 8))?;
+            }
+            "i" => match self.token()?.as_str() {
+                ":=" => match self.peek() {
+                    Some("hex") => {
+                        self.token()?;
+                        let x = self.reg()?;
+                        self.word(0xF029 | (x << 8))?;
+                    }
+                    Some("bighex") => {
+                        self.token()?;
+                        let x = self.reg()?;
+                        self.word(0xF030 | (x << 8))?;
+                    }
+                    Some("long") => {
+                        self.token()?;
+                        self.word(0xF000)?;
+                        let addr = self.addr(Fixup::Long)?;
+                        self.word(addr)?;
+                    }
+                    _ => {
+                        let addr = self.addr12()?;
+                        self.word(0xA000 | addr)?;
+                    }
+                },
+                "+=" => {
+                    let x = self.reg()?;
+                    self.word(0xF01E | (x << 8))?;
+                }
+                op => return Err(self.error(format!("Unknown operator 'i {}'", op))),
+            },
+            "if" => {
+                let (prefix, skip) = self.condition()?;
+                for op in prefix {
+                    self.word(op)?;
+                }
+                match self.token()?.as_str() {
+                    "then" => self.word(skip)?,
+                    "begin" => {
+                        self.word(invert(skip))?;
+                        self.blocks.push(Block::If(self.here));
+                        self.word(0x1000)?;
+                    }
+                    other => {
+                        return Err(
+                            self.error(format!("Expected 'then' or 'begin', got '{}'", other))
+                        )
+                    }
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some(Block::If(jump)) => {
+                    self.blocks.push(Block::Else(self.here));
+                    self.word(0x1000)?;
+                    self.patch(jump, self.here, Fixup::Addr)?;
+                }
+                _ => return Err(self.error("'else' without a matching 'begin'")),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(jump) | Block::Else(jump)) => {
+                    self.patch(jump, self.here, Fixup::Addr)?
+                }
+                _ => return Err(self.error("'end' without a matching 'begin'")),
+            },
+            "loop" => self.blocks.push(Block::Loop {
+                start: self.here,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                let (prefix, skip) = self.condition()?;
+                for op in prefix {
+                    self.word(op)?;
+                }
+                self.word(invert(skip))?;
+                let here = self.here;
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|b| matches!(b, Block::Loop { .. }))
+                {
+                    Some(Block::Loop { exits, .. }) => exits.push(here),
+                    _ => return Err(self.error("'while' outside of a loop")),
+                }
+                self.word(0x1000)?;
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits }) => {
+                    let start = self.short(start)?;
+                    self.word(0x1000 | start)?;
+                    for exit in exits {
+                        self.patch(exit, self.here, Fixup::Addr)?;
+                    }
+                }
+                _ => return Err(self.error("'again' without a matching 'loop'")),
+            },
+            name => {
+                // A bare name calls the subroutine at that label
+                self.tokens.push_front(Token {
+                    text: name.to_string(),
+                    line: self.line,
+                });
+                let addr = self.addr12()?;
+                self.word(0x2000 | addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `vx := ...`, `vx += ...` and the other register operators.
+    fn assignment(&mut self) -> Result<(), OctoError> {
+        let x = self.reg()?;
+        let op = self.token()?;
+        let rhs_reg = self.peek().is_some_and(|t| self.is_register(t));
+
+        let logic = |n: u16| Some(0x8000 | n);
+        let alu = match op.as_str() {
+            ":=" | "+=" | "-=" if rhs_reg => None,
+            "|=" => logic(0x1),
+            "&=" => logic(0x2),
+            "^=" => logic(0x3),
+            ">>=" => logic(0x6),
+            "=-" => logic(0x7),
+            "<<=" => logic(0xE),
+            _ => None,
+        };
+        if let Some(base) = alu {
+            let y = self.reg()?;
+            return self.word(base | (x << 8) | (y << 4));
+        }
+
+        match op.as_str() {
+            ":=" if rhs_reg => {
+                let y = self.reg()?;
+                self.word(0x8000 | (x << 8) | (y << 4))
+            }
+            "+=" if rhs_reg => {
+                let y = self.reg()?;
+                self.word(0x8004 | (x << 8) | (y << 4))
+            }
+            "-=" if rhs_reg => {
+                let y = self.reg()?;
+                self.word(0x8005 | (x << 8) | (y << 4))
+            }
+            ":=" => match self.peek() {
+                Some("key") => {
+                    self.token()?;
+                    self.word(0xF00A | (x << 8))
+                }
+                Some("delay") => {
+                    self.token()?;
+                    self.word(0xF007 | (x << 8))
+                }
+                Some("random") => {
+                    self.token()?;
+                    let mask = self.value8()?;
+                    self.word(0xC000 | (x << 8) | mask)
+                }
+                _ => {
+                    let value = self.value8()?;
+                    self.word(0x6000 | (x << 8) | value)
+                }
+            },
+            "+=" => {
+                let value = self.value8()?;
+                self.word(0x7000 | (x << 8) | value)
+            }
+            "-=" => {
+                let value = self.value8()?;
+                self.word(0x7000 | (x << 8) | (value.wrapping_neg() & 0xFF))
+            }
+            op => Err(self.error(format!("Unknown operator '{}'", op))),
+        }
+    }
+
+    /// Parse a condition into the instructions that set it up and the final
+    /// skip, which skips the next instruction when the condition is false.
+    fn condition(&mut self) -> Result<(Vec<u16>, u16), OctoError> {
+        let x = self.reg()?;
+        let op = self.token()?;
+        match op.as_str() {
+            "key" => return Ok((Vec::new(), 0xE0A1 | (x << 8))),
+            "-key" => return Ok((Vec::new(), 0xE09E | (x << 8))),
+            _ => {}
+        }
+
+        let rhs = if self.peek().is_some_and(|t| self.is_register(t)) {
+            Ok(self.reg()?)
+        } else {
+            Err(self.value8()?)
+        };
+        let simple = |reg_op: u16, value_op: u16| match rhs {
+            Ok(y) => reg_op | (x << 8) | (y << 4),
+            Err(n) => value_op | (x << 8) | n,
+        };
+        // Comparisons subtract into VF and test the borrow flag
+        let load = match rhs {
+            Ok(y) => 0x8F00 | (y << 4),
+            Err(n) => 0x6F00 | n,
+        };
+        let compare = |sub: u16, skip: u16| -> Result<(Vec<u16>, u16), OctoError> {
+            Ok((vec![load, sub | (x << 4)], skip))
+        };
+        match op.as_str() {
+            "==" => Ok((Vec::new(), simple(0x9000, 0x4000))),
+            "!=" => Ok((Vec::new(), simple(0x5000, 0x3000))),
+            ">" => compare(0x8F05, 0x4F00),
+            "<" => compare(0x8F07, 0x4F00),
+            ">=" => compare(0x8F07, 0x4F01),
+            "<=" => compare(0x8F05, 0x4F01),
+            op => Err(self.error(format!("Unknown comparison '{}'", op))),
+        }
+    }
+
+    /// Tokens up to the `}` matching an already consumed `{`.
+    fn braced(&mut self) -> Result<Vec<Token>, OctoError> {
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self
+                .tokens
+                .pop_front()
+                .ok_or_else(|| self.error("Missing '}'"))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return Ok(body);
+            }
+            body.push(token);
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.token()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.token()?;
+            if token == "{" {
+                break;
+            }
+            args.push(token);
+        }
+        let body = self.braced()?;
+        self.macros.insert(name, Macro { args, body });
+        Ok(())
+    }
+
+    fn expand(&mut self, name: &str) -> Result<(), OctoError> {
+        let mut values = HashMap::new();
+        for arg in &self.macros[name].args {
+            let value = self
+                .tokens
+                .pop_front()
+                .ok_or_else(|| self.error(format!("Too few arguments for macro '{}'", name)))?;
+            values.insert(arg.clone(), value.text);
+        }
+        let line = self.line;
+        let body: Vec<Token> = self.macros[name]
+            .body
+            .iter()
+            .map(|t| Token {
+                text: values.get(&t.text).unwrap_or(&t.text).clone(),
+                line,
+            })
+            .collect();
+        self.expanded += body.len() + 1;
+        if self.expanded > MAX_EXPANDED {
+            return Err(self.error(format!(
+                "Macro expansion too large, is '{}' recursive?",
+                name
+            )));
+        }
+        for token in body.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    /// Evaluate a `:calc` expression after its `{`. As in Octo, operators have no
+    /// precedence and are applied right to left; parentheses group.
+    fn calc(&mut self) -> Result<f64, OctoError> {
+        let tokens: Vec<String> = self.braced()?.into_iter().map(|t| t.text).collect();
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos, 0)?;
+        if pos != tokens.len() {
+            return Err(self.error(format!("Unexpected '{}' in expression", tokens[pos])));
+        }
+        Ok(value)
+    }
+
+    fn expression(
+        &self,
+        tokens: &[String],
+        pos: &mut usize,
+        depth: usize,
+    ) -> Result<f64, OctoError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("Expression is nested too deeply"));
+        }
+        let lhs = self.term(tokens, pos, depth + 1)?;
+        let Some(op) = tokens.get(*pos).filter(|t| *t != ")") else {
+            return Ok(lhs);
+        };
+        *pos += 1;
+        let rhs = self.expression(tokens, pos, depth + 1)?;
+        let (a, b) = (lhs as i64, rhs as i64);
+        let flag = |v: bool| v as u8 as f64;
+        Ok(match op.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => (a % b.max(1)) as f64,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" | ">>" => {
+                let shifted = u32::try_from(b).ok().and_then(|b| match op.as_str() {
+                    "<<" => a.checked_shl(b),
+                    _ => a.checked_shr(b),
+                });
+                shifted.ok_or_else(|| self.error(format!("Shift by {} out of range", b)))? as f64
+            }
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => flag(lhs < rhs),
+            ">" => flag(lhs > rhs),
+            "<=" => flag(lhs <= rhs),
+            ">=" => flag(lhs >= rhs),
+            "==" => flag(lhs == rhs),
+            "!=" => flag(lhs != rhs),
+            op => return Err(self.error(format!("Unknown operator '{}' in expression", op))),
+        })
+    }
+
+    fn term(&self, tokens: &[String], pos: &mut usize, depth: usize) -> Result<f64, OctoError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("Expression is nested too deeply"));
+        }
+        let token = tokens
+            .get(*pos)
+            .ok_or_else(|| self.error("Incomplete expression"))?;
+        *pos += 1;
+
+        let unary: Option<fn(f64) -> f64> = match token.as_str() {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| (v == 0.0) as u8 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(f) = unary {
+            return Ok(f(self.term(tokens, pos, depth + 1)?));
+        }
+
+        match token.as_str() {
+            "(" => {
+                let value = self.expression(tokens, pos, depth + 1)?;
+                if tokens.get(*pos).map(String::as_str) != Some(")") {
+                    return Err(self.error("Missing ')' in expression"));
+                }
+                *pos += 1;
+                Ok(value)
+            }
+            "@" => {
+                let addr = self.term(tokens, pos, depth + 1)? as usize;
+                Ok(*self.rom.get(addr).unwrap_or(&0) as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            name => parse_number(name)
+                .or_else(|| self.names.get(name).copied())
+                .ok_or_else(|| self.error(format!("Unknown name '{}' in expression", name))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_structured_program() {
+        let source = "
+            : main
+                v0 := 5
+                if v0 == 5 then v1 := 1
+                if v0 != v1 begin
+                    v2 += 1
+                else
+                    v2 -= 1
+                end
+                loop
+                    while v1 < 3
+                    v1 += 1
+                again
+                :unpack 0xA data # Forward labels are patched at the end
+                i := data
+                jump main
+            : data
+                0x12 0x34
+        ";
+        let expected = [
+            0x12, 0x02, // jump main
+            0x60, 0x05, 0x40, 0x05, 0x61, 0x01, // if ... then
+            0x90, 0x10, 0x12, 0x10, 0x72, 0x01, // if ... begin, inverted skip
+            0x12, 0x12, 0x72, 0xFF, // else ... end
+            0x6F, 0x03, 0x8F, 0x17, 0x3F, 0x00, 0x12, 0x1E, // loop, while
+            0x71, 0x01, 0x12, 0x12, // again
+            0x60, 0xA2, 0x61, 0x26, // :unpack
+            0xA2, 0x26, 0x12, 0x02, // i := data, jump main
+            0x12, 0x34,
+        ];
+        assert_eq!(assemble(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_runaway_input() {
+        let err = assemble(":macro boom { boom } : main boom").unwrap_err();
+        assert!(err.to_string().contains("recursive"), "{}", err);
+
+        let err = assemble(":calc x { 1 << 64 } : main").unwrap_err();
+        assert!(err.to_string().contains("Shift by 64"), "{}", err);
+
+        // Deep nesting is an error rather than a stack overflow
+        let source = format!(":calc x {{ {} 1 }} : main", "- ".repeat(50_000));
+        let err = assemble(&source).unwrap_err();
+        assert!(err.to_string().contains("nested too deeply"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_addresses_past_12_bits() {
+        assert!(assemble(": main jump 0x1234").is_err());
+        assert!(assemble(": main jump far :org 0x1000 : far").is_err());
+        // 16-bit operands take the full range
+        assert_eq!(
+            assemble(": main i := long 0x1234").unwrap(),
+            [0x12, 0x02, 0xF0, 0x00, 0x12, 0x34]
+        );
+    }
+}
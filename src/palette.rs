@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PaletteError {
+    #[error("Invalid colour '{text}', expected #RRGGBB")]
+    ColorError { text: String },
+}
+
+/// 24-bit colour, written as `#RRGGBB` (or `#RGB`) in the config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl TryFrom<String> for Rgb {
+    type Error = PaletteError;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let hex = text.strip_prefix('#').unwrap_or(&text);
+        // Short #RGB form doubles each digit
+        let hex = match hex.len() {
+            3 => hex.chars().flat_map(|c| [c, c]).collect(),
+            _ => hex.to_string(),
+        };
+        // from_str_radix alone would also take a leading sign
+        let valid = hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit());
+        let value = valid
+            .then(|| u32::from_str_radix(&hex, 16).ok())
+            .flatten()
+            .ok_or_else(|| PaletteError::ColorError { text: text.clone() })?;
+        Ok(Rgb((value >> 16) as u8, (value >> 8) as u8, value as u8))
+    }
+}
+
+impl From<Rgb> for String {
+    fn from(rgb: Rgb) -> Self {
+        format!("#{:02X}{:02X}{:02X}", rgb.0, rgb.1, rgb.2)
+    }
+}
+
+/// Display colours indexed by bitplane value. Index 0 is the background and index 1
+/// the foreground; XO-CHIP palettes carry 4 (two planes) or 16 (four planes) entries,
+/// but with a single plane emulated only the first two are drawn.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    pub colors: Vec<Rgb>,
+}
+
+impl Palette {
+    pub fn background(&self) -> Rgb {
+        self.color(0)
+    }
+
+    pub fn foreground(&self) -> Rgb {
+        self.color(1)
+    }
+
+    /// Colour for a bitplane value, falling back to the foreground when out of range.
+    pub fn color(&self, planes: usize) -> Rgb {
+        self.colors
+            .get(planes)
+            .or(self.colors.get(1))
+            .copied()
+            .unwrap_or(Rgb(0, 0, 0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    Classic,
+    Green, // P1 phosphor CRT
+    Amber, // P3 phosphor CRT
+    Lcd,
+    Octo,
+    HighContrast,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 6] = [
+        Preset::Classic,
+        Preset::Green,
+        Preset::Amber,
+        Preset::Lcd,
+        Preset::Octo,
+        Preset::HighContrast,
+    ];
+
+    /// Name used in the config file and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Classic => "classic",
+            Preset::Green => "green",
+            Preset::Amber => "amber",
+            Preset::Lcd => "lcd",
+            Preset::Octo => "octo",
+            Preset::HighContrast => "high-contrast",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Preset> {
+        Preset::ALL.into_iter().find(|p| p.name() == name)
+    }
+
+    pub fn palette(self) -> Palette {
+        let hex: &[u32] = match self {
+            Preset::Classic => &[0xF2F2F2, 0x000000, 0x808080, 0x404040],
+            Preset::Green => &[0x0A1A0A, 0x33FF66, 0x1E9E40, 0x99FFB3],
+            Preset::Amber => &[0x1A1000, 0xFFB000, 0x9E6D00, 0xFFD580],
+            Preset::Lcd => &[0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F],
+            Preset::Octo => &[0x996600, 0xFFCC00, 0xFF6600, 0x662200],
+            Preset::HighContrast => &[0x000000, 0xFFFFFF, 0xFFFF00, 0x00FFFF],
+        };
+
+        Palette {
+            colors: hex
+                .iter()
+                .map(|&c| Rgb((c >> 16) as u8, (c >> 8) as u8, c as u8))
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Preset::Classic => "Classic",
+            Preset::Green => "Green CRT",
+            Preset::Amber => "Amber CRT",
+            Preset::Lcd => "LCD",
+            Preset::Octo => "Octo",
+            Preset::HighContrast => "High contrast",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Option<Rgb> {
+        Rgb::try_from(text.to_string()).ok()
+    }
+
+    #[test]
+    fn parses_hex_colours() {
+        assert_eq!(parse("#FFB000"), Some(Rgb(0xFF, 0xB0, 0x00)));
+        assert_eq!(parse("0a1a0a"), Some(Rgb(0x0A, 0x1A, 0x0A)));
+        assert_eq!(parse("#F80"), Some(Rgb(0xFF, 0x88, 0x00)));
+        assert_eq!(String::from(Rgb(0x0A, 0xB0, 0xFF)), "#0AB0FF");
+
+        for text in [
+            "", "#", "#12345", "#1234567", "#+12345", "#-12345", "#12 345", "#GGGGGG", "#+12",
+        ] {
+            assert_eq!(parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn out_of_range_planes_use_the_foreground() {
+        let palette = Preset::Green.palette();
+        assert_eq!(palette.color(3), palette.colors[3]);
+        assert_eq!(palette.color(4), palette.foreground());
+        assert_eq!(palette.color(15), palette.colors[1]);
+
+        let mono = Palette {
+            colors: vec![Rgb(1, 1, 1)],
+        };
+        assert_eq!((mono.color(0), mono.color(1)), (Rgb(1, 1, 1), Rgb(0, 0, 0)));
+    }
+}
@@ -0,0 +1,356 @@
+use crate::cpu::{Cpu, CpuError, DEFAULT_IPF, HEIGHT, WIDTH};
+use crate::palette::{Palette, Rgb};
+use crate::screenshot::{CaptureArgs, ScreenshotError, MAX_SCALE};
+use log::info;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use thiserror::Error;
+
+const USAGE: &str =
+    "usage: c8emu record <rom> <output.gif|.rgb|-> [--frames N] [--scale N] [--palette NAME]";
+const DEFAULT_FRAMES: usize = 300; // Five second clip
+const MIN_DELAY_CS: u64 = 2; // Browsers slow down GIF frames shorter than this
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum RecordError {
+    #[error("{}", USAGE)]
+    Usage,
+    #[error("Unknown recording format for '{path}', expected .gif, .rgb or - for stdout")]
+    FormatError { path: String },
+    #[error("Failed to create recording '{path}': {err}")]
+    CreateError { path: String, err: std::io::Error },
+    #[error("Failed to write recording: {err}")]
+    WriteError { err: std::io::Error },
+    #[error("Failed to encode GIF: {err}")]
+    EncodeError { err: gif::EncodingError },
+    #[error("Unknown palette '{name}'")]
+    PaletteError { name: String },
+    #[error("Scale {scale} out of range 1 - {}", MAX_SCALE)]
+    ScaleError { scale: usize },
+    #[error("{err}")]
+    CpuError { err: CpuError },
+}
+
+/// Destination for a recording, fed every emulated frame at 60 Hz.
+pub trait FrameSink {
+    fn frame(&mut self, display: &[[bool; WIDTH]; HEIGHT]) -> Result<(), RecordError>;
+    fn finish(&mut self) -> Result<(), RecordError>;
+}
+
+/// Settings for starting a recording from the GUI.
+#[derive(Debug, Clone)]
+pub struct RecordSettings {
+    pub path: String,
+    pub palette: Palette,
+    pub scale: usize,
+}
+
+/// Open a recording, picking the format from the file extension.
+pub fn create(settings: &RecordSettings) -> Result<Box<dyn FrameSink>, RecordError> {
+    let path = settings.path.as_str();
+    let scale = settings.scale;
+    if !(1..=MAX_SCALE).contains(&scale) {
+        return Err(RecordError::ScaleError { scale });
+    }
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+
+    let open = || {
+        File::create(path)
+            .map(BufWriter::new)
+            .map_err(|e| RecordError::CreateError {
+                path: path.to_string(),
+                err: e,
+            })
+    };
+    let sink: Box<dyn FrameSink> = match (path, ext.as_deref()) {
+        ("-", _) => Box::new(RawRecorder::new(
+            Box::new(std::io::stdout()),
+            &settings.palette,
+            scale,
+        )),
+        (_, Some("gif")) => Box::new(GifRecorder::new(open()?, &settings.palette, scale)?),
+        (_, Some("rgb" | "raw")) => Box::new(RawRecorder::new(
+            Box::new(open()?),
+            &settings.palette,
+            scale,
+        )),
+        _ => {
+            return Err(RecordError::FormatError {
+                path: path.to_string(),
+            })
+        }
+    };
+    info!("Recording to '{}'", path);
+    Ok(sink)
+}
+
+/// Animated GIF with a two colour palette. Repeated frames are merged into one longer
+/// frame, and each new frame only covers the area that changed.
+pub struct GifRecorder {
+    encoder: Option<gif::Encoder<BufWriter<File>>>,
+    scale: usize,
+    shown: Option<[[bool; WIDTH]; HEIGHT]>, // What the GIF shows after the frames written so far
+    pending: Option<[[bool; WIDTH]; HEIGHT]>, // Latest display, not yet written
+    frames: u64,                            // 60 Hz frames recorded, including pending
+    written_cs: u64,                        // GIF time written so far, centiseconds
+}
+
+impl GifRecorder {
+    pub fn new(out: BufWriter<File>, palette: &Palette, scale: usize) -> Result<Self, RecordError> {
+        let colors: Vec<u8> = [palette.background(), palette.foreground()]
+            .iter()
+            .flat_map(|&Rgb(r, g, b)| [r, g, b])
+            .collect();
+        let mut encoder = gif::Encoder::new(
+            out,
+            (WIDTH * scale) as u16,
+            (HEIGHT * scale) as u16,
+            &colors,
+        )
+        .and_then(|mut encoder| {
+            encoder.set_repeat(gif::Repeat::Infinite)?;
+            Ok(encoder)
+        })
+        .map_err(|e| RecordError::EncodeError { err: e })?;
+        encoder.get_mut().flush().ok();
+
+        Ok(Self {
+            encoder: Some(encoder),
+            scale,
+            shown: None,
+            pending: None,
+            frames: 0,
+            written_cs: 0,
+        })
+    }
+
+    /// Write the pending display, lasting until 60 Hz frame `self.frames`.
+    fn flush(&mut self, last: bool) -> Result<(), RecordError> {
+        let Some(display) = self.pending else {
+            return Ok(());
+        };
+        let end_cs = self.frames * 100 / 60;
+        let delay = end_cs.saturating_sub(self.written_cs);
+        if delay < MIN_DELAY_CS && !last {
+            return Ok(()); // Too short to show, let the next display replace it
+        }
+        let Some(encoder) = self.encoder.as_mut() else {
+            return Ok(());
+        };
+
+        // Bounding box of pixels that differ from what is already shown
+        let changed = |x: usize, y: usize| self.shown.is_none_or(|s| s[y][x] != display[y][x]);
+        let (mut x0, mut y0, mut x1, mut y1) = (WIDTH, HEIGHT, 0, 0);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if changed(x, y) {
+                    (x0, y0, x1, y1) = (x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1));
+                }
+            }
+        }
+        if x0 >= x1 {
+            (x0, y0, x1, y1) = (0, 0, 1, 1); // Nothing changed, extend with a 1 pixel frame
+        }
+
+        let s = self.scale;
+        let (width, height) = ((x1 - x0) * s, (y1 - y0) * s);
+        let pixels: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (y, x)))
+            .map(|(y, x)| display[y0 + y / s][x0 + x / s] as u8)
+            .collect();
+        let mut frame = gif::Frame::from_indexed_pixels(width as u16, height as u16, pixels, None);
+        frame.left = (x0 * s) as u16;
+        frame.top = (y0 * s) as u16;
+        frame.delay = delay.max(MIN_DELAY_CS) as u16;
+        encoder
+            .write_frame(&frame)
+            .map_err(|e| RecordError::EncodeError { err: e })?;
+
+        self.written_cs += delay.max(MIN_DELAY_CS);
+        self.shown = Some(display);
+        self.pending = None;
+        Ok(())
+    }
+}
+
+impl FrameSink for GifRecorder {
+    fn frame(&mut self, display: &[[bool; WIDTH]; HEIGHT]) -> Result<(), RecordError> {
+        if self.pending.as_ref() != Some(display) {
+            if self.pending.is_some() {
+                self.flush(false)?;
+            }
+            self.pending = Some(*display);
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), RecordError> {
+        self.flush(true)?;
+        if let Some(encoder) = self.encoder.take() {
+            encoder
+                .into_inner()
+                .and_then(|mut out| out.flush())
+                .map_err(|e| RecordError::WriteError { err: e })?;
+        }
+        Ok(())
+    }
+}
+
+/// Uncompressed RGB24 frames at 60 Hz, for piping to an external encoder.
+pub struct RawRecorder {
+    out: Box<dyn Write>,
+    colors: [[u8; 3]; 2],
+    scale: usize,
+}
+
+impl RawRecorder {
+    pub fn new(out: Box<dyn Write>, palette: &Palette, scale: usize) -> Self {
+        let rgb = |Rgb(r, g, b): Rgb| [r, g, b];
+        Self {
+            out,
+            colors: [rgb(palette.background()), rgb(palette.foreground())],
+            scale,
+        }
+    }
+}
+
+impl FrameSink for RawRecorder {
+    fn frame(&mut self, display: &[[bool; WIDTH]; HEIGHT]) -> Result<(), RecordError> {
+        let s = self.scale;
+        let data: Vec<u8> = (0..HEIGHT * s)
+            .flat_map(|y| (0..WIDTH * s).map(move |x| display[y / s][x / s]))
+            .flat_map(|on| self.colors[on as usize])
+            .collect();
+        self.out
+            .write_all(&data)
+            .map_err(|e| RecordError::WriteError { err: e })
+    }
+
+    fn finish(&mut self) -> Result<(), RecordError> {
+        self.out
+            .flush()
+            .map_err(|e| RecordError::WriteError { err: e })
+    }
+}
+
+/// Entry point for `c8emu record`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    match record(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn record(args: &[String]) -> Result<(), RecordError> {
+    let args = CaptureArgs::parse(args, DEFAULT_FRAMES).map_err(|e| match e {
+        ScreenshotError::PaletteError { name } => RecordError::PaletteError { name },
+        ScreenshotError::ScaleError { scale } => RecordError::ScaleError { scale },
+        _ => RecordError::Usage,
+    })?;
+
+    let mut cpu = Cpu::new();
+    let loaded = cpu
+        .load_rom(&args.rom)
+        .map_err(|e| RecordError::CpuError { err: e })?;
+    let ipf = loaded
+        .info
+        .and_then(|info| info.tickrate)
+        .unwrap_or(DEFAULT_IPF);
+    let mut sink = create(&RecordSettings {
+        path: args.output,
+        palette: args.palette,
+        scale: args.scale,
+    })?;
+
+    for _ in 0..args.frames {
+        cpu.run_frame(ipf)
+            .map_err(|e| RecordError::CpuError { err: e })?;
+        sink.frame(&cpu.get_display())?;
+    }
+    sink.finish()?;
+    info!("Recorded {} frames", args.frames);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::Preset;
+
+    fn lit(pixels: &[(usize, usize)]) -> [[bool; WIDTH]; HEIGHT] {
+        let mut display = [[false; WIDTH]; HEIGHT];
+        for &(x, y) in pixels {
+            display[y][x] = true;
+        }
+        display
+    }
+
+    #[test]
+    fn gif_frames_merge_repeats_and_cover_changes() {
+        let path = std::env::temp_dir().join(format!("c8emu-clip-{}.gif", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut sink = create(&RecordSettings {
+            path: path.clone(),
+            palette: Preset::Classic.palette(),
+            scale: 2,
+        })
+        .unwrap();
+        let blank = lit(&[]);
+        let one = lit(&[(10, 5)]);
+        let two = lit(&[(10, 5), (20, 8)]);
+        let flicker = lit(&[(0, 0)]);
+        for display in [&blank, &blank, &blank, &flicker, &one, &one, &two] {
+            sink.frame(display).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(data.as_slice())
+            .unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (128, 64));
+
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push(frame.clone());
+        }
+        // Blank for 3/60 s, then the flicker frame is too short to show and is
+        // dropped, one pixel for 2/60 s and the second pixel for the last frame
+        let rects: Vec<_> = frames
+            .iter()
+            .map(|f| (f.left, f.top, f.width, f.height, f.delay))
+            .collect();
+        assert_eq!(
+            rects,
+            [(0, 0, 128, 64, 5), (20, 10, 2, 2, 5), (40, 16, 2, 2, 2)]
+        );
+        assert!(frames[0].buffer.iter().all(|&p| p == 0));
+        assert!(frames[1..].iter().all(|f| *f.buffer == [1; 4]));
+    }
+
+    #[test]
+    fn rejects_scales_out_of_range() {
+        for scale in [0, MAX_SCALE + 1] {
+            let settings = RecordSettings {
+                path: String::from("never-created.gif"),
+                palette: Preset::Classic.palette(),
+                scale,
+            };
+            assert!(matches!(
+                create(&settings),
+                Err(RecordError::ScaleError { .. })
+            ));
+        }
+        assert!(!Path::new("never-created.gif").exists());
+    }
+}
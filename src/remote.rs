@@ -0,0 +1,650 @@
+// Remote control over HTTP on localhost, for driving a running emulator from test
+// scripts. Requests are served on a thread of their own and passed to the frontend,
+// which forwards them to the emulation thread; replies come straight back from there.
+//
+//   GET  /registers                 Registers, keys held, paused and frame count
+//   POST /registers                 Write any of {"v": [..], "i", "pc", "dt", "st"}
+//   GET  /memory?addr=A&len=N       {"addr": A, "data": [..]}
+//   POST /memory                    Write {"addr": A, "data": [..]}
+//   POST /load                      Load {"path": ".."}
+//   POST /pause, /resume
+//   POST /step?count=N              Execute instructions, pausing
+//   POST /frame?count=N             Run whole frames, pausing
+//   POST /keys/K/press, /keys/K/release
+//   GET  /screenshot?scale=N&palette=NAME   PNG
+//   GET  /state                     Save state, as bytes
+//   POST /state                     Load a save state sent as the body
+//
+// Movies must replay exactly, so writes to memory and registers get a 409 while one
+// is recording or playing.
+//
+// Web pages can reach localhost too, by DNS rebinding if nothing else, so requests
+// must name 127.0.0.1 or localhost with this port as their Host, and any request
+// with an Origin header, which only browsers send, is refused.
+
+use crate::cpu::{CpuError, Register, RomLoadResult};
+use crate::palette::Preset;
+use crate::romfile;
+use crate::runner::{Command, Frame, Runner};
+use crate::screenshot;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use thiserror::Error;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_COUNT: usize = 100_000; // Steps or frames in one request
+const DEFAULT_SCALE: usize = 8;
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum RemoteError {
+    #[error("Failed to start remote control on port {port}: {err}")]
+    BindError {
+        port: u16,
+        err: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// A request for the frontend.
+pub enum Request {
+    Load(String, Sender<Result<Loaded, String>>), // Loaded the frontend's own way
+    Run(Command),
+}
+
+/// Reply to a ROM load.
+#[derive(Debug, Serialize)]
+pub struct Loaded {
+    sha1: String,
+    bytes: usize,
+    title: Option<String>,
+}
+
+pub struct Server {
+    http: Arc<tiny_http::Server>,
+    requests: Receiver<Request>,
+    loads: VecDeque<Sender<Result<Loaded, String>>>, // Waiting for `Event::RomLoaded`
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Listen on `port` on the loopback interface only, or any free port for 0.
+    pub fn start(port: u16) -> Result<Server, RemoteError> {
+        let http = tiny_http::Server::http(("127.0.0.1", port))
+            .map(Arc::new)
+            .map_err(|e| RemoteError::BindError { port, err: e })?;
+        let port = http.server_addr().to_ip().map_or(port, |addr| addr.port());
+        let (requests_tx, requests) = mpsc::channel();
+        let handler = Handler {
+            requests: requests_tx,
+            hosts: [format!("127.0.0.1:{}", port), format!("localhost:{}", port)],
+        };
+        let listener = Arc::clone(&http);
+        let thread = thread::Builder::new()
+            .name(String::from("c8emu-remote"))
+            .spawn(move || {
+                for request in listener.incoming_requests() {
+                    handler.serve(request);
+                }
+            })
+            .map_err(|e| RemoteError::BindError {
+                port,
+                err: Box::new(e),
+            })?;
+        info!("Remote control listening on http://127.0.0.1:{}", port);
+
+        Ok(Server {
+            http,
+            requests,
+            loads: VecDeque::new(),
+            thread: Some(thread),
+        })
+    }
+
+    /// Forward waiting requests to the runner. Returns the ROMs asked for, for the
+    /// frontend to load with its per-ROM settings, then report with `rom_loaded`.
+    pub fn poll(&mut self, runner: &Runner) -> Vec<String> {
+        let mut roms = Vec::new();
+        while let Ok(request) = self.requests.try_recv() {
+            match request {
+                Request::Load(path, reply) => {
+                    self.loads.push_back(reply);
+                    roms.push(path);
+                }
+                Request::Run(command) => runner.send(command),
+            }
+        }
+        roms
+    }
+
+    /// Answer the oldest load request still waiting.
+    pub fn rom_loaded(&mut self, result: &Result<RomLoadResult, CpuError>) {
+        if let Some(reply) = self.loads.pop_front() {
+            let _ = reply.send(match result {
+                Ok(loaded) => Ok(Loaded {
+                    sha1: loaded.sha1.clone(),
+                    bytes: loaded.bytes_read,
+                    title: loaded.info.as_ref().map(|info| info.title.clone()),
+                }),
+                Err(e) => Err(e.to_string()),
+            });
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.http.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Registers as returned by `GET /registers`.
+#[derive(Serialize)]
+struct Registers {
+    v: [u8; 16],
+    i: u16,
+    pc: u16,
+    sp: u8,
+    dt: u8,
+    st: u8,
+    stack: Vec<u16>,
+    keys: Vec<usize>, // Keypad keys held
+    paused: bool,
+    frames: u64,
+}
+
+impl Registers {
+    fn new(frame: &Frame) -> Registers {
+        let state = &frame.state;
+        Registers {
+            v: state.v,
+            i: state.i,
+            pc: state.pc,
+            sp: state.sp,
+            dt: state.dt,
+            st: state.st,
+            stack: state.stack.clone(),
+            keys: (0..16).filter(|&k| state.keypad[k]).collect(),
+            paused: frame.paused,
+            frames: frame.count,
+        }
+    }
+}
+
+/// Body of `POST /registers`, registers left out are unchanged.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegisterWrite {
+    #[serde(default)]
+    v: Vec<u8>, // From V0 on
+    i: Option<u16>,
+    pc: Option<u16>,
+    dt: Option<u8>,
+    st: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Memory {
+    addr: usize,
+    data: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoadRom {
+    path: String,
+}
+
+/// An HTTP response before it is sent.
+enum Reply {
+    Json(serde_json::Value),
+    Bytes(Vec<u8>, &'static str), // Data and content type
+}
+
+/// A failed request, as a status code and message.
+struct Failure(u16, String);
+
+type Response = Result<Reply, Failure>;
+
+fn bad_request(msg: impl Into<String>) -> Failure {
+    Failure(400, msg.into())
+}
+
+fn timed_out() -> Failure {
+    Failure(504, String::from("The emulator didn't answer"))
+}
+
+fn json(value: impl Serialize) -> Response {
+    serde_json::to_value(value)
+        .map(Reply::Json)
+        .map_err(|e| Failure(500, e.to_string()))
+}
+
+fn parse<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, Failure> {
+    serde_json::from_slice(body).map_err(|e| bad_request(e.to_string()))
+}
+
+struct Handler {
+    requests: Sender<Request>,
+    hosts: [String; 2], // Host headers accepted
+}
+
+impl Handler {
+    fn serve(&self, mut request: tiny_http::Request) {
+        let mut body = Vec::new();
+        let reply = match request.as_reader().read_to_end(&mut body) {
+            Ok(_) if !self.trusted(&request) => Err(Failure(
+                403,
+                format!("Only requests to http://{}/ are served", self.hosts[0]),
+            )),
+            Ok(_) => {
+                let url = request.url().to_string();
+                let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+                self.route(request.method(), path, query, body)
+            }
+            Err(e) => Err(bad_request(e.to_string())),
+        };
+
+        let (data, content_type, status) = match reply {
+            Ok(Reply::Json(value)) => (value.to_string().into_bytes(), "application/json", 200),
+            Ok(Reply::Bytes(data, content_type)) => (data, content_type, 200),
+            Err(Failure(status, msg)) => (
+                serde_json::json!({ "error": msg }).to_string().into_bytes(),
+                "application/json",
+                status,
+            ),
+        };
+        let header =
+            tiny_http::Header::from_bytes("Content-Type", content_type).expect("valid header");
+        let response = tiny_http::Response::from_data(data)
+            .with_status_code(status)
+            .with_header(header);
+        if let Err(e) = request.respond(response) {
+            error!("Failed to send remote control response: {}", e);
+        }
+    }
+
+    /// Whether a request came from a local client rather than a web page.
+    fn trusted(&self, request: &tiny_http::Request) -> bool {
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv(name))
+                .map(|header| header.value.as_str())
+        };
+        header("Origin").is_none()
+            && header("Host").is_some_and(|host| {
+                self.hosts
+                    .iter()
+                    .any(|allowed| host.eq_ignore_ascii_case(allowed))
+            })
+    }
+
+    fn route(
+        &self,
+        method: &tiny_http::Method,
+        path: &str,
+        query: &str,
+        body: Vec<u8>,
+    ) -> Response {
+        use tiny_http::Method::{Get, Post};
+
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, &segments[..]) {
+            (Get, ["registers"]) => self.inspect(|frame| json(Registers::new(frame))),
+            (Post, ["registers"]) => self.write_registers(&body),
+            (Get, ["memory"]) => {
+                let addr = param(query, "addr")?.ok_or_else(|| bad_request("Expected ?addr=A"))?;
+                let len = param(query, "len")?.unwrap_or(1);
+                self.inspect(
+                    |frame| match frame.memory.get(addr..addr.saturating_add(len)) {
+                        Some(data) => json(Memory {
+                            addr,
+                            data: data.to_vec(),
+                        }),
+                        None => Err(bad_request("Memory range out of bounds")),
+                    },
+                )
+            }
+            (Post, ["memory"]) => {
+                let memory: Memory = parse(&body)?;
+                let end = memory.addr.saturating_add(memory.data.len());
+                if end > 0x1000 {
+                    return Err(bad_request("Memory range out of bounds"));
+                }
+                self.writable()?;
+                for (offset, &byte) in memory.data.iter().enumerate() {
+                    self.run(Command::WriteMemory(memory.addr + offset, byte));
+                }
+                self.inspect(|frame| {
+                    json(Memory {
+                        addr: memory.addr,
+                        data: frame.memory[memory.addr..end].to_vec(),
+                    })
+                })
+            }
+            (Post, ["load"]) => self.load(parse::<LoadRom>(&body)?.path),
+            (Post, ["pause"]) => self.command(Command::Pause(true), 1),
+            (Post, ["resume"]) => self.command(Command::Pause(false), 1),
+            (Post, ["step"]) => self.repeat(Command::Step, query),
+            (Post, ["frame"]) => self.repeat(Command::FrameAdvance, query),
+            (Post, ["keys", key, action @ ("press" | "release")]) => {
+                match u8::from_str_radix(key, 16) {
+                    Ok(key) if key < 16 => {
+                        self.command(Command::Key(key as usize, *action == "press"), 1)
+                    }
+                    _ => Err(bad_request(format!(
+                        "'{}' is not a key, expected 0 - F",
+                        key
+                    ))),
+                }
+            }
+            (Get, ["screenshot"]) => self.screenshot(query),
+            (Get, ["state"]) => {
+                let (reply, state) = mpsc::channel();
+                self.run(Command::SaveState(reply));
+                match state.recv_timeout(REPLY_TIMEOUT) {
+                    Ok(Ok(state)) => Ok(Reply::Bytes(state, "application/octet-stream")),
+                    Ok(Err(e)) => Err(bad_request(e.to_string())),
+                    Err(_) => Err(timed_out()),
+                }
+            }
+            (Post, ["state"]) => {
+                let (reply, result) = mpsc::channel();
+                self.run(Command::LoadState(body, reply));
+                match result.recv_timeout(REPLY_TIMEOUT) {
+                    Ok(Ok(())) => self.inspect(|frame| json(Registers::new(frame))),
+                    Ok(Err(e)) => Err(bad_request(e.to_string())),
+                    Err(_) => Err(timed_out()),
+                }
+            }
+            (Get | Post, _) => Err(Failure(404, format!("No endpoint {} {}", method, path))),
+            _ => Err(Failure(405, format!("{} not supported", method))),
+        }
+    }
+
+    fn run(&self, command: Command) {
+        let _ = self.requests.send(Request::Run(command));
+    }
+
+    /// Send `count` copies of a command, then reply with the registers.
+    fn command(&self, command: Command, count: usize) -> Response {
+        for _ in 0..count {
+            self.run(command.clone());
+        }
+        self.inspect(|frame| json(Registers::new(frame)))
+    }
+
+    fn repeat(&self, command: Command, query: &str) -> Response {
+        match param(query, "count")?.unwrap_or(1) {
+            count @ 1..=MAX_COUNT => self.command(command, count),
+            _ => Err(bad_request(format!("count must be 1 - {}", MAX_COUNT))),
+        }
+    }
+
+    /// Reply from the machine as it is once the commands sent before have run.
+    fn inspect(&self, reply: impl FnOnce(&Frame) -> Response) -> Response {
+        let (frame_tx, frame) = mpsc::channel();
+        self.run(Command::Inspect(frame_tx));
+        reply(&frame.recv_timeout(REPLY_TIMEOUT).map_err(|_| timed_out())?)
+    }
+
+    /// Fail with 409 while a movie is running, when the emulation thread drops writes.
+    fn writable(&self) -> Result<(), Failure> {
+        self.inspect(|frame| {
+            if frame.movie {
+                return Err(Failure(
+                    409,
+                    String::from("Memory and registers can't be written during movies"),
+                ));
+            }
+            json(())
+        })
+        .map(|_| ())
+    }
+
+    fn write_registers(&self, body: &[u8]) -> Response {
+        let write: RegisterWrite = parse(body)?;
+        if write.v.len() > 16 {
+            return Err(bad_request("There are 16 V registers"));
+        }
+        if write.i.into_iter().chain(write.pc).any(|addr| addr > 0xFFF) {
+            return Err(bad_request("I and PC must be 0 - FFF"));
+        }
+        self.writable()?;
+        let v = write.v.iter().enumerate();
+        let writes = v
+            .map(|(x, &value)| (Register::V(x), value as u16))
+            .chain(write.i.map(|i| (Register::I, i)))
+            .chain(write.pc.map(|pc| (Register::Pc, pc)))
+            .chain(write.dt.map(|dt| (Register::Dt, dt as u16)))
+            .chain(write.st.map(|st| (Register::St, st as u16)));
+        for (register, value) in writes {
+            self.run(Command::WriteRegister(register, value));
+        }
+        self.inspect(|frame| json(Registers::new(frame)))
+    }
+
+    fn load(&self, path: String) -> Response {
+        // The GUI would ask which ROM to run, a script has to name it
+        let (file, entry) = romfile::split(&path);
+        if entry.is_none() && romfile::is_archive(file) {
+            match romfile::entries(file) {
+                Ok(entries) if entries.len() > 1 => {
+                    return Err(bad_request(format!(
+                        "'{}' holds several ROMs, pick one with '{}#<entry>'",
+                        file, file
+                    )))
+                }
+                Ok(_) => {}
+                Err(e) => return Err(bad_request(e.to_string())),
+            }
+        }
+
+        let (reply, result) = mpsc::channel();
+        let _ = self.requests.send(Request::Load(path, reply));
+        match result.recv_timeout(REPLY_TIMEOUT) {
+            Ok(Ok(loaded)) => json(loaded),
+            Ok(Err(e)) => Err(bad_request(e)),
+            Err(_) => Err(timed_out()),
+        }
+    }
+
+    fn screenshot(&self, query: &str) -> Response {
+        let scale = param(query, "scale")?.unwrap_or(DEFAULT_SCALE);
+        if !(1..=screenshot::MAX_SCALE).contains(&scale) {
+            return Err(bad_request(format!(
+                "scale must be 1 - {}",
+                screenshot::MAX_SCALE
+            )));
+        }
+        let preset = match query_value(query, "palette") {
+            Some(name) => Preset::from_name(name)
+                .ok_or_else(|| bad_request(format!("Unknown palette '{}'", name)))?,
+            None => Preset::Classic,
+        };
+        self.inspect(|frame| {
+            let mut png = Vec::new();
+            screenshot::write_png(&mut png, &frame.display, &preset.palette(), scale)
+                .map_err(|e| Failure(500, e.to_string()))?;
+            Ok(Reply::Bytes(png, "image/png"))
+        })
+    }
+}
+
+fn query_value<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// A number from the query string, decimal or hex with `0x`, if it is there.
+fn param(query: &str, name: &str) -> Result<Option<usize>, Failure> {
+    let Some(value) = query_value(query, name) else {
+        return Ok(None);
+    };
+    let number = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    };
+    number
+        .map(Some)
+        .ok_or_else(|| bad_request(format!("{} should be a number, got '{}'", name, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AudioConfig, AudioOutput};
+    use crate::cpu::state::STATE_SIZE;
+    use crate::cpu::{Cpu, DEFAULT_IPF};
+    use crate::runner::Event;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    const ROM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/roms/test_opcode.ch8");
+
+    /// One request over a fresh connection, returning the status and body.
+    fn request(port: u16, method: &str, path: &str, headers: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            path,
+            headers,
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[9..12]).parse().unwrap();
+        (status, response[split + 4..].to_vec())
+    }
+
+    fn local(port: u16, method: &str, path: &str, body: &str) -> serde_json::Value {
+        let host = format!("Host: localhost:{}\r\n", port);
+        let (status, body) = request(port, method, path, &host, body.as_bytes());
+        assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn spawn_runner() -> Runner {
+        let audio = AudioConfig {
+            output: AudioOutput::Null,
+            ..AudioConfig::default()
+        };
+        Runner::spawn(Cpu::new(), audio, DEFAULT_IPF)
+    }
+
+    /// Stand in for a frontend until the client is done.
+    fn serve(runner: &Runner, server: &mut Server, client: thread::JoinHandle<()>) {
+        while !client.is_finished() {
+            for rom in server.poll(runner) {
+                runner.send(Command::Load(rom, None));
+            }
+            while let Some(event) = runner.poll_event() {
+                if let Event::RomLoaded(result) = event {
+                    server.rom_loaded(&result);
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn serves_loopback_clients() {
+        let runner = spawn_runner();
+        let mut server = Server::start(0).unwrap();
+        let port = server.http.server_addr().to_ip().unwrap().port();
+
+        let client = thread::spawn(move || {
+            // Browsers, and pages posing as another host, are turned away
+            let origin = format!("Host: 127.0.0.1:{}\r\nOrigin: http://evil.test\r\n", port);
+            assert_eq!(request(port, "GET", "/registers", &origin, b"").0, 403);
+            let rebound = format!("Host: evil.test:{}\r\n", port);
+            assert_eq!(request(port, "GET", "/registers", &rebound, b"").0, 403);
+
+            let path = serde_json::json!({ "path": ROM }).to_string();
+            let loaded = local(port, "POST", "/load", &path);
+            assert_eq!(loaded["bytes"], 478);
+            local(port, "POST", "/pause", "");
+
+            let registers = local(port, "POST", "/step?count=3", "");
+            assert_eq!(registers["paused"], true);
+            assert_ne!(registers["pc"], 0x200);
+
+            local(
+                port,
+                "POST",
+                "/memory",
+                r#"{"addr": 3840, "data": [1, 2, 3]}"#,
+            );
+            let memory = local(port, "GET", "/memory?addr=0xF00&len=3", "");
+            assert_eq!(memory["data"], serde_json::json!([1, 2, 3]));
+
+            // A state saved here brings the memory back after it is overwritten
+            let host = format!("Host: 127.0.0.1:{}\r\n", port);
+            let (status, state) = request(port, "GET", "/state", &host, b"");
+            assert_eq!((status, state.len()), (200, STATE_SIZE));
+            local(
+                port,
+                "POST",
+                "/memory",
+                r#"{"addr": 3840, "data": [9, 9, 9]}"#,
+            );
+            let (status, _) = request(port, "POST", "/state", &host, &state);
+            assert_eq!(status, 200);
+            let memory = local(port, "GET", "/memory?addr=0xF00&len=3", "");
+            assert_eq!(memory["data"], serde_json::json!([1, 2, 3]));
+        });
+        serve(&runner, &mut server, client);
+    }
+
+    #[test]
+    fn refuses_writes_during_movies() {
+        let movie = std::env::temp_dir().join(format!("c8emu-remote-{}.txt", std::process::id()));
+        let movie = movie.to_str().unwrap().to_string();
+        let runner = spawn_runner();
+        runner.send(Command::Load(ROM.to_string(), None));
+        runner.send(Command::RecordMovie(Some(movie.clone())));
+        let mut server = Server::start(0).unwrap();
+        let port = server.http.server_addr().to_ip().unwrap().port();
+
+        let client = thread::spawn(move || {
+            let host = format!("Host: localhost:{}\r\n", port);
+            let memory = br#"{"addr": 3840, "data": [1]}"#;
+            let (status, body) = request(port, "POST", "/memory", &host, memory);
+            assert_eq!(status, 409, "{}", String::from_utf8_lossy(&body));
+            let registers = br#"{"v": [1]}"#;
+            assert_eq!(request(port, "POST", "/registers", &host, registers).0, 409);
+            let memory = local(port, "GET", "/memory?addr=0xF00&len=1", "");
+            assert_eq!(memory["data"], serde_json::json!([0]));
+        });
+        serve(&runner, &mut server, client);
+
+        runner.send(Command::RecordMovie(None));
+        let (reply, state) = mpsc::channel();
+        runner.send(Command::SaveState(reply)); // Answered once the movie is saved
+        state.recv_timeout(REPLY_TIMEOUT).unwrap().unwrap();
+        std::fs::remove_file(&movie).unwrap();
+    }
+}
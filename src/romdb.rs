@@ -0,0 +1,310 @@
+use crate::cpu::Quirks;
+use crate::palette::{Palette, Rgb};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use thiserror::Error;
+
+// Embedded database, in the community CHIP-8 database format
+const PROGRAMS: &str = include_str!("../data/database/programs.json");
+const HASHES: &str = include_str!("../data/database/sha1-hashes.json");
+const PLATFORMS: &str = include_str!("../data/database/platforms.json");
+
+/// Short names for platforms, as accepted on the command line.
+const PLATFORM_ALIASES: [(&str, &str); 5] = [
+    ("vip", "originalChip8"),
+    ("modern", "modernChip8"),
+    ("schip", "superchip"),
+    ("schip1", "superchip1"),
+    ("xo", "xochip"),
+];
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum RomDbError {
+    #[error("Failed to read ROM database file '{path}': {err}")]
+    ReadError { path: String, err: std::io::Error },
+    #[error("Malformed ROM database file '{path}': {err}")]
+    ParseError {
+        path: String,
+        err: serde_json::Error,
+    },
+}
+
+/// Quirk flags as named in the database. Missing flags fall back to the platform.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DbQuirks {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl DbQuirks {
+    fn apply(&self, quirks: &mut Quirks) {
+        let set = |flag: &mut bool, value: Option<bool>| *flag = value.unwrap_or(*flag);
+        set(&mut quirks.shift, self.shift);
+        set(&mut quirks.increment_x, self.memory_increment_by_x);
+        set(&mut quirks.load_store, self.memory_leave_i_unchanged);
+        set(&mut quirks.wrap, self.wrap);
+        set(&mut quirks.jump, self.jump);
+        set(&mut quirks.vblank, self.vblank);
+        set(&mut quirks.logic, self.logic);
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Platform {
+    id: String,
+    name: String,
+    default_tickrate: Option<usize>,
+    #[serde(default)]
+    quirks: DbQuirks,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<Rgb>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, DbQuirks>,
+    tickrate: Option<usize>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    colors: Option<Colors>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Program {
+    title: String,
+    description: Option<String>,
+    release: Option<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    roms: HashMap<String, Rom>,
+}
+
+/// What the database knows about a ROM, resolved for its preferred platform.
+#[derive(Debug, Clone)]
+pub struct RomInfo {
+    pub title: String,
+    pub description: Option<String>,
+    pub release: Option<String>,
+    pub authors: Vec<String>,
+    pub platform: String, // Platform display name
+    pub quirks: Quirks,
+    pub tickrate: Option<usize>,   // Instructions per frame
+    pub keys: HashMap<String, u8>, // Logical button ("up", "a", ...) to keypad key
+    pub palette: Option<Palette>,  // Colours chosen for this ROM
+}
+
+pub struct RomDb {
+    programs: Vec<Program>,
+    hashes: HashMap<String, usize>, // SHA-1 to index in `programs`
+    platforms: Vec<Platform>,
+}
+
+impl RomDb {
+    /// The database from `c8emu/database` in the config directory if present, so the
+    /// full community database can be dropped in, otherwise the embedded one.
+    pub fn get() -> &'static RomDb {
+        static DB: OnceLock<RomDb> = OnceLock::new();
+        DB.get_or_init(|| {
+            let dir = dirs::config_dir().map(|dir| dir.join("c8emu").join("database"));
+            match dir
+                .filter(|dir| dir.exists())
+                .map(|dir| Self::load_dir(&dir))
+            {
+                Some(Ok(db)) => db,
+                Some(Err(e)) => {
+                    error!("{}", e);
+                    Self::embedded()
+                }
+                None => Self::embedded(),
+            }
+        })
+    }
+
+    fn embedded() -> Self {
+        Self::parse(PROGRAMS, HASHES, PLATFORMS, "embedded").expect("embedded ROM database")
+    }
+
+    fn load_dir(dir: &Path) -> Result<Self, RomDbError> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            fs::read_to_string(&path).map_err(|e| RomDbError::ReadError {
+                path: path.display().to_string(),
+                err: e,
+            })
+        };
+        let db = Self::parse(
+            &read("programs.json")?,
+            &read("sha1-hashes.json")?,
+            &read("platforms.json")?,
+            &dir.display().to_string(),
+        )?;
+        info!("Loaded ROM database from '{}'", dir.display());
+        Ok(db)
+    }
+
+    fn parse(
+        programs: &str,
+        hashes: &str,
+        platforms: &str,
+        source: &str,
+    ) -> Result<Self, RomDbError> {
+        let err = |name: &str| {
+            let path = format!("{}/{}", source, name);
+            move |e| RomDbError::ParseError { path, err: e }
+        };
+        Ok(Self {
+            programs: serde_json::from_str(programs).map_err(err("programs.json"))?,
+            hashes: serde_json::from_str(hashes).map_err(err("sha1-hashes.json"))?,
+            platforms: serde_json::from_str(platforms).map_err(err("platforms.json"))?,
+        })
+    }
+
+    /// Look up a ROM by the lowercase hex SHA-1 of its contents.
+    pub fn lookup(&self, sha1: &str) -> Option<RomInfo> {
+        let program = self.programs.get(*self.hashes.get(sha1)?)?;
+        let rom = program.roms.get(sha1).cloned().unwrap_or_default();
+
+        // The first listed platform is the one the ROM was made for
+        let platform = rom
+            .platforms
+            .first()
+            .and_then(|id| self.platforms.iter().find(|p| &p.id == id));
+        if platform.is_none() {
+            warn!("No known platform for ROM '{}'", program.title);
+        }
+
+        let mut quirks = Quirks::default();
+        if let Some(platform) = platform {
+            platform.quirks.apply(&mut quirks);
+            if let Some(overrides) = rom.quirky_platforms.get(&platform.id) {
+                overrides.apply(&mut quirks);
+            }
+        }
+
+        Some(RomInfo {
+            title: program.title.clone(),
+            description: program.description.clone(),
+            release: program.release.clone(),
+            authors: program.authors.clone(),
+            platform: platform.map_or_else(|| String::from("Unknown"), |p| p.name.clone()),
+            quirks,
+            tickrate: rom
+                .tickrate
+                .or_else(|| platform.and_then(|p| p.default_tickrate)),
+            keys: rom.keys,
+            palette: rom
+                .colors
+                .filter(|c| c.pixels.len() >= 2)
+                .map(|c| Palette { colors: c.pixels }),
+        })
+    }
+
+    /// Quirks of a platform, by database id or one of the short aliases.
+    pub fn platform_quirks(&self, name: &str) -> Option<Quirks> {
+        let id = PLATFORM_ALIASES
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+            .map_or(name, |(_, id)| id);
+        let platform = self
+            .platforms
+            .iter()
+            .find(|p| p.id.eq_ignore_ascii_case(id))?;
+        let mut quirks = Quirks::default();
+        platform.quirks.apply(&mut quirks);
+        Some(quirks)
+    }
+
+    /// Names `platform_quirks` accepts, aliases first.
+    pub fn platform_names(&self) -> Vec<&str> {
+        PLATFORM_ALIASES
+            .iter()
+            .map(|(alias, _)| *alias)
+            .chain(self.platforms.iter().map(|p| p.id.as_str()))
+            .collect()
+    }
+}
+
+/// Lowercase hex SHA-1, the key used by the database.
+pub fn sha1(bytes: &[u8]) -> String {
+    sha1_smol::Sha1::from(bytes).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const C8_TEST: &str = "8e592d3620481e00ea36d29765b95287c7349a70";
+
+    #[test]
+    fn looks_up_known_roms_by_hash() {
+        let db = RomDb::embedded();
+        let info = db.lookup(C8_TEST).unwrap();
+        assert_eq!(info.title, "C8 Test");
+        assert_eq!(info.platform, "Modern CHIP-8");
+        // modernChip8 with the ROM's quirkyPlatforms override on top
+        let expected = Quirks {
+            load_store: true,
+            ..db.platform_quirks("modernChip8").unwrap()
+        };
+        assert_eq!(info.quirks, expected);
+        assert_eq!(info.tickrate, Some(12));
+
+        assert!(db.lookup(&sha1(b"not in the database")).is_none());
+        assert!(db.lookup(&C8_TEST.to_uppercase()).is_none());
+    }
+
+    #[test]
+    fn unknown_platforms_fall_back_to_default_quirks() {
+        let programs = r#"[{
+            "title": "Stray",
+            "roms": {"aaaa": {
+                "platforms": ["amiga"],
+                "quirkyPlatforms": {"amiga": {"shift": true}},
+                "tickrate": 20
+            }}
+        }]"#;
+        let db = RomDb::parse(programs, r#"{"aaaa": 0, "bbbb": 0}"#, PLATFORMS, "test").unwrap();
+
+        let info = db.lookup("aaaa").unwrap();
+        assert_eq!(info.platform, "Unknown");
+        assert_eq!(info.quirks, Quirks::default());
+        assert_eq!(info.tickrate, Some(20));
+        // A hash listed without a ROM entry still names the program
+        let info = db.lookup("bbbb").unwrap();
+        assert_eq!((info.title.as_str(), info.tickrate), ("Stray", None));
+    }
+
+    #[test]
+    fn finds_platform_quirks_by_id_or_alias() {
+        let db = RomDb::embedded();
+        let vip = db.platform_quirks("VIP").unwrap();
+        assert_eq!(Some(vip), db.platform_quirks("originalChip8"));
+        assert!(vip.vblank && vip.logic && !vip.shift);
+        let schip = db.platform_quirks("schip").unwrap();
+        assert!(schip.shift && schip.load_store && schip.jump);
+
+        assert!(db.platform_quirks("amiga").is_none());
+        assert!(db.platform_names().contains(&"xo"));
+    }
+}
@@ -0,0 +1,402 @@
+use crate::cpu::Quirks;
+use crate::octo::{self, OctoError};
+use crate::palette::{Palette, Rgb};
+use crate::romdb::{self, RomInfo};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use thiserror::Error;
+
+// File extensions of programs, raw, as Octo cartridges or zipped
+pub const EXTENSIONS: [&str; 6] = ["ch8", "c8", "sc8", "xo8", "gif", "zip"];
+// Largest file taken out of a zip archive, with room for cartridge GIFs
+const MAX_ROM_BYTES: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum RomFileError {
+    #[error("Failed to read '{path}': {err}")]
+    ReadError { path: String, err: std::io::Error },
+    #[error("Failed to open zip archive '{path}': {err}")]
+    ArchiveError {
+        path: String,
+        err: zip::result::ZipError,
+    },
+    #[error("No ROMs in zip archive '{path}'")]
+    EmptyArchiveError { path: String },
+    #[error("No ROM named '{entry}' in zip archive '{path}'")]
+    EntryError { path: String, entry: String },
+    #[error(
+        "'{entry}' in zip archive '{path}' is larger than {} bytes",
+        MAX_ROM_BYTES
+    )]
+    EntrySizeError { path: String, entry: String },
+    #[error("Zip archive '{path}' holds several ROMs, choose one of: {}", entries.join(", "))]
+    ChoiceError { path: String, entries: Vec<String> },
+    #[error("'{path}' is not an Octo cartridge: {msg}")]
+    CartridgeError { path: String, msg: String },
+    #[error("Failed to assemble the program in '{path}': {err}")]
+    CompileError { path: String, err: OctoError },
+}
+
+/// A program ready to load, with settings from the file it came in, if any.
+pub struct RomImage {
+    pub bytes: Vec<u8>,
+    pub info: Option<RomInfo>,
+}
+
+/// Read a ROM from a plain file, an Octo cartridge GIF, or a zip archive. A ROM
+/// inside an archive is chosen as `archive.zip#entry`; the entry can be left out
+/// when the archive holds only one ROM.
+pub fn read(path: &str) -> Result<RomImage, RomFileError> {
+    let (file, entry) = split(path);
+    let bytes = std::fs::read(file).map_err(|e| RomFileError::ReadError {
+        path: file.to_string(),
+        err: e,
+    })?;
+
+    if !is_archive(file) {
+        return decode(file, bytes);
+    }
+    let mut archive = open_archive(file, bytes)?;
+    let entry = match entry {
+        Some(entry) => entry.to_string(),
+        None => match &rom_entries(&archive)[..] {
+            [] => {
+                return Err(RomFileError::EmptyArchiveError {
+                    path: file.to_string(),
+                })
+            }
+            [entry] => entry.clone(),
+            entries => {
+                return Err(RomFileError::ChoiceError {
+                    path: file.to_string(),
+                    entries: entries.to_vec(),
+                })
+            }
+        },
+    };
+
+    // Stop a small archive from unpacking into something huge
+    let mut bytes = Vec::new();
+    archive
+        .by_name(&entry)
+        .map_err(|_| RomFileError::EntryError {
+            path: file.to_string(),
+            entry: entry.clone(),
+        })?
+        .take(MAX_ROM_BYTES as u64 + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| RomFileError::ReadError {
+            path: path.to_string(),
+            err: e,
+        })?;
+    if bytes.len() > MAX_ROM_BYTES {
+        return Err(RomFileError::EntrySizeError {
+            path: file.to_string(),
+            entry,
+        });
+    }
+    info!("Extracted '{}' from '{}'", entry, file);
+    decode(&entry, bytes)
+}
+
+/// SHA-1 of the program `read` loads from a path, which keys per-ROM settings.
+pub fn sha1(path: &str) -> Result<String, RomFileError> {
+    read(path).map(|image| romdb::sha1(&image.bytes))
+}
+
+/// The ROMs in a zip archive, for choosing between them.
+pub fn entries(path: &str) -> Result<Vec<String>, RomFileError> {
+    let (file, _) = split(path);
+    let bytes = std::fs::read(file).map_err(|e| RomFileError::ReadError {
+        path: file.to_string(),
+        err: e,
+    })?;
+    Ok(rom_entries(&open_archive(file, bytes)?))
+}
+
+/// Split `archive.zip#entry` into the archive and entry.
+pub fn split(path: &str) -> (&str, Option<&str>) {
+    match path.rsplit_once('#') {
+        Some((file, entry)) if is_archive(file) => (file, Some(entry)),
+        _ => (path, None),
+    }
+}
+
+pub fn is_archive(path: &str) -> bool {
+    has_extension(path, "zip")
+}
+
+/// Whether a path looks like something `read` can load, going by its extension.
+pub fn is_rom(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn has_extension(path: &str, ext: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(ext))
+}
+
+fn open_archive(
+    path: &str,
+    bytes: Vec<u8>,
+) -> Result<zip::ZipArchive<Cursor<Vec<u8>>>, RomFileError> {
+    zip::ZipArchive::new(Cursor::new(bytes)).map_err(|e| RomFileError::ArchiveError {
+        path: path.to_string(),
+        err: e,
+    })
+}
+
+fn rom_entries(archive: &zip::ZipArchive<Cursor<Vec<u8>>>) -> Vec<String> {
+    let mut entries: Vec<String> = archive
+        .file_names()
+        .filter_map(|name| name.ok().map(|name| name.into_owned()))
+        .filter(|name| is_rom(Path::new(name)) && !is_archive(name))
+        .collect();
+    entries.sort();
+    entries
+}
+
+/// Unpack Octo cartridges; anything else is taken as a raw program.
+pub fn decode(name: &str, bytes: Vec<u8>) -> Result<RomImage, RomFileError> {
+    if !has_extension(name, "gif") {
+        return Ok(RomImage { bytes, info: None });
+    }
+
+    let cartridge = Cartridge::decode(&bytes).map_err(|msg| RomFileError::CartridgeError {
+        path: name.to_string(),
+        msg,
+    })?;
+    let bytes = octo::assemble(&cartridge.program).map_err(|e| RomFileError::CompileError {
+        path: name.to_string(),
+        err: e,
+    })?;
+    info!(
+        "Assembled {} bytes from Octo cartridge '{}'",
+        bytes.len(),
+        name
+    );
+
+    let title = Path::new(name).file_stem().map_or_else(
+        || name.to_string(),
+        |stem| stem.to_string_lossy().into_owned(),
+    );
+    Ok(RomImage {
+        bytes,
+        info: Some(cartridge.options.info(title)),
+    })
+}
+
+/// Program source and settings saved in an Octo cartridge.
+#[derive(Debug, Deserialize)]
+struct Cartridge {
+    program: String,
+    #[serde(default)]
+    options: OctoOptions,
+}
+
+/// The parts of Octo's options object that this emulator can honour.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct OctoOptions {
+    tickrate: Option<usize>,
+    background_color: Option<Rgb>,
+    fill_color: Option<Rgb>,
+    fill_color2: Option<Rgb>,
+    blend_color: Option<Rgb>,
+    shift_quirks: bool,
+    load_store_quirks: bool,
+    jump_quirks: bool,
+    logic_quirks: bool,
+    clip_quirks: bool,
+    v_blank_quirks: bool,
+}
+
+impl Cartridge {
+    /// Cartridges hide their payload in the low two bits of each pixel's palette
+    /// index, four pixels to a byte, across all frames: a big-endian length, then
+    /// that many bytes of UTF-8 JSON.
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(bytes).map_err(|e| e.to_string())?;
+
+        let mut data = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().map_err(|e| e.to_string())? {
+            data.extend(frame.buffer.chunks_exact(4).map(|quad| {
+                quad.iter()
+                    .fold(0, |byte, &pixel| (byte << 2) | (pixel & 3))
+            }));
+        }
+
+        let size = match data[..] {
+            [a, b, c, d, ..] => u32::from_be_bytes([a, b, c, d]) as usize,
+            _ => return Err(String::from("no embedded program")),
+        };
+        let json = data
+            .get(4..4 + size)
+            .ok_or_else(|| String::from("embedded program is truncated"))?;
+        serde_json::from_slice(json).map_err(|e| e.to_string())
+    }
+}
+
+impl OctoOptions {
+    fn info(&self, title: String) -> RomInfo {
+        let quirks = Quirks {
+            shift: self.shift_quirks,
+            load_store: self.load_store_quirks,
+            jump: self.jump_quirks,
+            logic: self.logic_quirks,
+            wrap: !self.clip_quirks,
+            vblank: self.v_blank_quirks,
+            ..Quirks::default()
+        };
+        let palette = match (self.background_color, self.fill_color) {
+            (Some(background), Some(fill)) => {
+                let mut colors = vec![background, fill];
+                colors.extend(self.fill_color2.into_iter().chain(self.blend_color));
+                Some(Palette { colors })
+            }
+            _ => None,
+        };
+
+        RomInfo {
+            title,
+            description: None,
+            release: None,
+            authors: Vec::new(),
+            platform: String::from("Octo cartridge"),
+            quirks,
+            tickrate: self.tickrate,
+            keys: HashMap::new(),
+            palette,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A cartridge GIF carrying `json`, two bits per pixel as Octo writes them.
+    fn cartridge(json: &str) -> Vec<u8> {
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend(json.as_bytes());
+        let mut pixels: Vec<u8> = payload
+            .iter()
+            .flat_map(|&byte| [byte >> 6, byte >> 4, byte >> 2, byte].map(|p| p & 3))
+            .collect();
+        let width = 64;
+        let height = pixels.len().div_ceil(width);
+        pixels.resize(width * height, 0);
+
+        let mut gif = Vec::new();
+        let palette = [0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255];
+        let mut encoder =
+            gif::Encoder::new(&mut gif, width as u16, height as u16, &palette).unwrap();
+        let frame = gif::Frame::from_indexed_pixels(width as u16, height as u16, pixels, None);
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+        gif
+    }
+
+    #[test]
+    fn decodes_cartridge_with_options() {
+        let json = r##"{
+            "program": ": main jump main",
+            "options": {
+                "tickrate": 20,
+                "backgroundColor": "#112233",
+                "fillColor": "#FF0000",
+                "shiftQuirks": true,
+                "clipQuirks": true
+            }
+        }"##;
+        let image = decode("Demo Cart.gif", cartridge(json)).unwrap();
+        assert_eq!(image.bytes, [0x12, 0x02, 0x12, 0x02]);
+
+        let info = image.info.unwrap();
+        assert_eq!(info.title, "Demo Cart");
+        assert_eq!(info.tickrate, Some(20));
+        assert_eq!(
+            info.quirks,
+            Quirks {
+                shift: true,
+                wrap: false,
+                ..Quirks::default()
+            }
+        );
+        assert_eq!(
+            info.palette.unwrap().colors,
+            [Rgb(0x11, 0x22, 0x33), Rgb(0xFF, 0, 0)]
+        );
+
+        assert!(matches!(
+            decode("broken.gif", vec![0x47, 0x49, 0x46]),
+            Err(RomFileError::CartridgeError { .. })
+        ));
+    }
+
+    #[test]
+    fn chooses_entries_in_zip_archives() {
+        let path = std::env::temp_dir().join(format!("c8emu-roms-{}.zip", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for (name, bytes) in [
+            ("readme.txt", &b"not a rom"[..]),
+            ("games/b.ch8", &[0x12, 0x00]),
+            ("a.ch8", &[0x00, 0xE0]),
+        ] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(bytes).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let listed = entries(&path);
+        let ambiguous = read(&path);
+        let chosen = read(&format!("{}#games/b.ch8", path));
+        let missing = read(&format!("{}#c.ch8", path));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(listed.unwrap(), ["a.ch8", "games/b.ch8"]);
+        match ambiguous {
+            Err(RomFileError::ChoiceError { entries, .. }) => {
+                assert_eq!(entries, ["a.ch8", "games/b.ch8"])
+            }
+            _ => panic!("expected a choice between entries"),
+        }
+        assert_eq!(chosen.unwrap().bytes, [0x12, 0x00]);
+        assert!(matches!(missing, Err(RomFileError::EntryError { .. })));
+    }
+
+    #[test]
+    fn rejects_oversized_zip_entries() {
+        let path = std::env::temp_dir().join(format!("c8emu-bomb-{}.zip", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        for (name, len) in [("fits.ch8", MAX_ROM_BYTES), ("bomb.ch8", MAX_ROM_BYTES + 1)] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(&vec![0; len]).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let archive_len = std::fs::metadata(&path).unwrap().len();
+        let fits = read(&format!("{}#fits.ch8", path));
+        let bomb = read(&format!("{}#bomb.ch8", path));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(archive_len < 16 * 1024);
+        assert_eq!(fits.unwrap().bytes.len(), MAX_ROM_BYTES);
+        assert!(matches!(bomb, Err(RomFileError::EntrySizeError { .. })));
+    }
+}
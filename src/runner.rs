@@ -0,0 +1,547 @@
+use crate::audio::AudioBackend;
+use crate::config::AudioConfig;
+use crate::cpu::{Cpu, CpuError, CpuState, Quirks, Register, RomLoadResult, DEFAULT_IPF};
+use crate::movie::{Movie, MovieError, MoviePlayer, MovieRecorder};
+use crate::recorder::{self, FrameSink, RecordError, RecordSettings};
+use log::{error, info, warn};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60); // 60 Hz frame period
+const MAX_LAG_FRAMES: u32 = 5; // Frames to catch up on before resyncing after a stall
+const IPS_WINDOW: Duration = Duration::from_secs(1); // Instructions per second sample period
+
+pub const MIN_SPEED: u32 = 10; // Percent of normal speed
+pub const MAX_SPEED: u32 = 1000;
+
+/// Requests to the emulation thread.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Load(String, Option<Quirks>), // ROM path and quirks that override the ROM database
+    Pause(bool),
+    Step,              // Execute a single instruction
+    FrameAdvance,      // Execute a single frame
+    SetSpeed(u32),     // Percent of normal speed, `MIN_SPEED` - `MAX_SPEED`
+    SetUncapped(bool), // Run frames back to back, for benchmarking
+    SetIpf(usize),     // Instructions per frame, until the next ROM is loaded
+    Key(usize, bool),
+    WriteMemory(usize, u8),
+    WriteRegister(Register, u16),
+    Inspect(Sender<Frame>), // Reply with the machine as it is now
+    SaveState(Sender<Result<Vec<u8>, CpuError>>), // Reply with a save state
+    LoadState(Vec<u8>, Sender<Result<(), CpuError>>),
+    Record(Option<RecordSettings>), // Start recording every frame, or stop with None
+    SetQuirks(Option<Quirks>),      // Override the current ROM's quirks, or go back to its own
+    RecordMovie(Option<String>),    // Restart the ROM and record input to a path, or stop
+    PlayMovie(String),              // Restart the ROM and replay a movie file
+    Shutdown,
+}
+
+/// Notifications from the emulation thread.
+#[derive(Debug)]
+pub enum Event {
+    RomLoaded(Result<RomLoadResult, CpuError>),
+    Fault(CpuError),
+    RecordError(RecordError),             // Recording failed and has stopped
+    MovieStopped(Result<(), MovieError>), // Movie saved or replay ended
+}
+
+/// Input movie being recorded or replayed.
+enum MovieState {
+    None,
+    Recording(MovieRecorder, String), // Output path
+    Playing(MoviePlayer),
+}
+
+/// Machine state published after every frame or step.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub display: [[bool; 64]; 32],
+    pub previous_display: [[bool; 64]; 32], // Display at the end of the frame before
+    pub display_version: u64,               // Bumped whenever the display is redrawn
+    pub state: CpuState,
+    pub memory: Vec<u8>,
+    pub quirks: Quirks,
+    pub paused: bool,
+    pub movie: bool, // Recording or replaying a movie, which blocks writes
+    pub count: u64,  // Frames emulated since start
+    pub ips: u64,    // Instructions per second over the last sample period
+}
+
+impl Frame {
+    fn new(cpu: &Cpu) -> Self {
+        let mut frame = Frame {
+            display: [[false; 64]; 32],
+            previous_display: [[false; 64]; 32],
+            display_version: 0,
+            state: CpuState::default(),
+            memory: Vec::new(),
+            quirks: Quirks::default(),
+            paused: false,
+            movie: false,
+            count: 0,
+            ips: 0,
+        };
+        frame.capture(cpu);
+        frame
+    }
+
+    fn capture(&mut self, cpu: &Cpu) {
+        self.display = cpu.get_display();
+        self.state = cpu.get_state();
+        self.quirks = cpu.quirks();
+        self.memory.clear();
+        self.memory.extend_from_slice(cpu.get_memory());
+    }
+}
+
+/// Runs a `Cpu` on its own thread, so emulation keeps time regardless of the UI.
+///
+/// Commands go in over a channel and frames come out through a lock-free triple
+/// buffer, so neither side ever waits on the other.
+pub struct Runner {
+    commands: Sender<Command>,
+    events: Receiver<Event>,
+    frames: triple_buffer::Output<Frame>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Runner {
+    pub fn spawn(cpu: Cpu, audio: AudioConfig, ipf: usize) -> Self {
+        let (commands, command_rx) = mpsc::channel();
+        let (event_tx, events) = mpsc::channel();
+
+        let initial = Frame::new(&cpu);
+        let default_quirks = cpu.default_quirks();
+        let (input, frames) = triple_buffer::triple_buffer(&initial);
+
+        let thread = thread::Builder::new()
+            .name(String::from("c8emu-cpu"))
+            .spawn(move || {
+                // Audio is opened here, host streams can't move between threads
+                let worker = Worker {
+                    cpu,
+                    audio: crate::audio::open(&audio),
+                    ipf,
+                    default_quirks,
+                    base_quirks: default_quirks,
+                    rom_quirks: None,
+                    speed: 100,
+                    uncapped: false,
+                    paused: false,
+                    next_frame: Instant::now(),
+                    count: 0,
+                    previous_display: [[false; 64]; 32],
+                    display_version: 0,
+                    ips: IpsCounter::new(),
+                    recorder: None,
+                    rom_path: None,
+                    movie: MovieState::None,
+                    input,
+                    events: event_tx,
+                };
+                worker.run(command_rx);
+            })
+            .map_err(|e| error!("Failed to start emulation thread: {}", e))
+            .ok();
+
+        Self {
+            commands,
+            events,
+            frames,
+            thread,
+        }
+    }
+
+    pub fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            error!("Emulation thread has stopped");
+        }
+    }
+
+    pub fn poll_event(&self) -> Option<Event> {
+        self.events.try_recv().ok()
+    }
+
+    /// Fetch the newest published frame, returns false if nothing changed since the last call.
+    pub fn update(&mut self) -> bool {
+        self.frames.update()
+    }
+
+    /// Most recently fetched frame.
+    pub fn frame(&self) -> &Frame {
+        self.frames.peek_output_buffer()
+    }
+}
+
+impl Drop for Runner {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Worker {
+    cpu: Cpu,
+    audio: Box<dyn AudioBackend>,
+    ipf: usize,
+    default_quirks: Quirks, // Used when the ROM database doesn't know the ROM
+    base_quirks: Quirks,    // From the ROM database or the defaults, for the current ROM
+    rom_quirks: Option<Quirks>, // User override for the current ROM
+    speed: u32,             // Percent of normal speed
+    uncapped: bool,
+    paused: bool,
+    next_frame: Instant, // Deadline for the next emulated frame
+    count: u64,
+    previous_display: [[bool; 64]; 32],
+    display_version: u64,
+    ips: IpsCounter,
+    recorder: Option<Box<dyn FrameSink>>,
+    rom_path: Option<String>,
+    movie: MovieState,
+    input: triple_buffer::Input<Frame>,
+    events: Sender<Event>,
+}
+
+impl Worker {
+    fn run(mut self, commands: Receiver<Command>) {
+        loop {
+            let now = Instant::now();
+            if self.uncapped && !self.paused {
+                // Never wait, only pick up commands between frames
+                self.next_frame = now;
+                self.frame(now);
+                match commands.try_recv() {
+                    Ok(Command::Shutdown) | Err(TryRecvError::Disconnected) => break,
+                    Ok(command) => self.handle(command),
+                    Err(TryRecvError::Empty) => {}
+                }
+                continue;
+            }
+
+            if now >= self.next_frame {
+                self.frame(now);
+                continue;
+            }
+
+            match commands.recv_timeout(self.next_frame - now) {
+                Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(command) => self.handle(command),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+        self.stop_recording();
+        self.stop_movie(Ok(()));
+        info!("Emulation thread stopped");
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Load(path, quirks) => {
+                self.stop_movie(Ok(()));
+                self.rom_quirks = quirks;
+                let result = self.load(&path);
+                if result.is_ok() {
+                    self.rom_path = Some(path);
+                }
+                let _ = self.events.send(Event::RomLoaded(result));
+            }
+            Command::Pause(paused) => {
+                if self.paused && !paused {
+                    self.ips = IpsCounter::new(); // Don't count time spent paused
+                }
+                self.paused = paused;
+                self.publish();
+            }
+            Command::Step if !matches!(self.movie, MovieState::None) => {
+                warn!("Single instruction steps are disabled during movies");
+            }
+            Command::Step => {
+                self.paused = true;
+                if let Err(e) = self.cpu.cpu_exec() {
+                    let _ = self.events.send(Event::Fault(e));
+                }
+                self.display_version += 1;
+                self.publish();
+            }
+            Command::FrameAdvance => {
+                self.paused = true;
+                self.run_frame();
+                self.publish();
+            }
+            Command::SetSpeed(speed) => {
+                self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+                self.next_frame = Instant::now();
+            }
+            Command::SetUncapped(uncapped) => {
+                self.uncapped = uncapped;
+                self.next_frame = Instant::now();
+            }
+            Command::SetIpf(_) if !matches!(self.movie, MovieState::None) => {
+                warn!("Instructions per frame can't be changed during movies");
+            }
+            Command::SetIpf(ipf) => self.ipf = ipf.max(1),
+            Command::SetQuirks(_) if !matches!(self.movie, MovieState::None) => {
+                warn!("Quirks can't be changed during movies");
+            }
+            Command::SetQuirks(quirks) => {
+                self.rom_quirks = quirks;
+                self.cpu.set_quirks(quirks.unwrap_or(self.base_quirks));
+                self.publish();
+            }
+            Command::Key(..) if matches!(self.movie, MovieState::Playing(_)) => {}
+            Command::Key(key, pressed) => {
+                self.cpu.set_key(key, pressed);
+                self.publish();
+            }
+            Command::WriteMemory(..) | Command::WriteRegister(..)
+                if !matches!(self.movie, MovieState::None) =>
+            {
+                warn!("Memory and registers can't be written during movies");
+            }
+            Command::WriteMemory(addr, value) => {
+                self.cpu.set_memory(addr, value);
+                self.publish();
+            }
+            Command::WriteRegister(register, value) => {
+                self.cpu.set_register(register, value);
+                self.publish();
+            }
+            Command::Inspect(reply) => {
+                let _ = reply.send(self.frame_now());
+            }
+            Command::SaveState(reply) if !matches!(self.movie, MovieState::None) => {
+                let _ = reply.send(Err(CpuError::StateError {
+                    msg: String::from("states can't be saved during movies"),
+                }));
+            }
+            Command::SaveState(reply) => {
+                let _ = reply.send(Ok(self.cpu.save_state()));
+            }
+            Command::LoadState(_, reply) if !matches!(self.movie, MovieState::None) => {
+                let _ = reply.send(Err(CpuError::StateError {
+                    msg: String::from("states can't be loaded during movies"),
+                }));
+            }
+            Command::LoadState(state, reply) => {
+                let result = self.cpu.load_state(&state);
+                if result.is_ok() {
+                    self.previous_display = self.cpu.get_display();
+                    self.display_version += 1;
+                    self.publish();
+                }
+                let _ = reply.send(result);
+            }
+            Command::Record(settings) => {
+                self.stop_recording();
+                if let Some(settings) = settings {
+                    match recorder::create(&settings) {
+                        Ok(sink) => self.recorder = Some(sink),
+                        Err(e) => {
+                            let _ = self.events.send(Event::RecordError(e));
+                        }
+                    }
+                }
+            }
+            Command::RecordMovie(Some(path)) => {
+                self.stop_movie(Ok(()));
+                match self.restart() {
+                    Ok(rom) => {
+                        let recorder = MovieRecorder::start(&self.cpu, &rom, self.ipf);
+                        info!("Recording movie to '{}'", path);
+                        self.movie = MovieState::Recording(recorder, path);
+                    }
+                    Err(e) => self.stop_movie(Err(e)),
+                }
+            }
+            Command::RecordMovie(None) => self.stop_movie(Ok(())),
+            Command::PlayMovie(path) => {
+                self.stop_movie(Ok(()));
+                let started = Movie::load(&path).and_then(|movie| {
+                    let player = MoviePlayer::new(movie);
+                    self.restart()?;
+                    player.start(&mut self.cpu)?;
+                    Ok(player)
+                });
+                match started {
+                    Ok(player) => {
+                        info!("Replaying movie '{}'", path);
+                        self.ipf = player.ipf();
+                        self.movie = MovieState::Playing(player);
+                    }
+                    Err(e) => self.stop_movie(Err(e)),
+                }
+            }
+            Command::Shutdown => {}
+        }
+    }
+
+    /// Normal frame period scaled by the speed setting.
+    fn period(&self) -> Duration {
+        FRAME * 100 / self.speed
+    }
+
+    fn frame(&mut self, now: Instant) {
+        let period = self.period();
+        // Resync rather than bursting through frames missed during a stall
+        if now.duration_since(self.next_frame) > period * MAX_LAG_FRAMES {
+            self.next_frame = now;
+        }
+        self.next_frame += period;
+
+        if !self.paused {
+            self.run_frame();
+            self.publish();
+        }
+        self.audio.frame(self.cpu.sound_active() && !self.paused);
+    }
+
+    fn load(&mut self, path: &str) -> Result<RomLoadResult, CpuError> {
+        let result = self.cpu.load_rom(path);
+        if let Ok(loaded) = &result {
+            let info = loaded.info.as_ref();
+            self.base_quirks = info.map_or(self.default_quirks, |info| info.quirks);
+            self.cpu
+                .set_quirks(self.rom_quirks.unwrap_or(self.base_quirks));
+            self.ipf = info.and_then(|info| info.tickrate).unwrap_or(DEFAULT_IPF);
+            self.paused = false;
+            self.next_frame = Instant::now();
+            self.previous_display = self.cpu.get_display();
+            self.display_version += 1;
+        }
+        self.publish();
+        result
+    }
+
+    /// Reload the current ROM for a movie, returning its file name.
+    fn restart(&mut self) -> Result<String, MovieError> {
+        let path = self.rom_path.clone().ok_or(MovieError::NoRomError)?;
+        self.load(&path)
+            .map_err(|e| MovieError::CpuError { err: e })?;
+        Ok(std::path::Path::new(&path)
+            .file_name()
+            .map_or(path.clone(), |name| name.to_string_lossy().into_owned()))
+    }
+
+    /// End the current movie, saving it if recording, and report how it ended.
+    fn stop_movie(&mut self, result: Result<(), MovieError>) {
+        let result = match std::mem::replace(&mut self.movie, MovieState::None) {
+            MovieState::Recording(recorder, path) => {
+                result.and_then(|_| recorder.finish(&self.cpu, &path))
+            }
+            MovieState::Playing(_) => result,
+            MovieState::None if result.is_ok() => return,
+            MovieState::None => result,
+        };
+        let _ = self.events.send(Event::MovieStopped(result));
+    }
+
+    fn run_frame(&mut self) {
+        self.count += 1;
+        self.previous_display = self.cpu.get_display();
+        match &mut self.movie {
+            MovieState::Recording(recorder, _) => recorder.before_frame(&self.cpu),
+            MovieState::Playing(player) => player.before_frame(&mut self.cpu),
+            MovieState::None => {}
+        }
+        match self.cpu.run_frame(self.ipf) {
+            Ok(result) => {
+                self.ips.add(result.instructions as u64);
+                if result.display_changed {
+                    self.display_version += 1;
+                }
+            }
+            Err(e) => {
+                self.paused = true;
+                let _ = self.events.send(Event::Fault(e));
+            }
+        }
+
+        match &mut self.movie {
+            MovieState::Recording(recorder, _) => recorder.after_frame(&self.cpu),
+            MovieState::Playing(player) => match player.after_frame(&self.cpu) {
+                Err(e) => self.stop_movie(Err(e)),
+                Ok(()) if player.finished(&self.cpu) => self.stop_movie(Ok(())),
+                Ok(()) => {}
+            },
+            MovieState::None => {}
+        }
+
+        if let Some(sink) = self.recorder.as_mut() {
+            if let Err(e) = sink.frame(&self.cpu.get_display()) {
+                self.recorder = None;
+                let _ = self.events.send(Event::RecordError(e));
+            }
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(mut sink) = self.recorder.take() {
+            match sink.finish() {
+                Ok(()) => info!("Recording stopped"),
+                Err(e) => {
+                    let _ = self.events.send(Event::RecordError(e));
+                }
+            }
+        }
+    }
+
+    fn publish(&mut self) {
+        let frame = self.input.input_buffer_mut();
+        frame.capture(&self.cpu);
+        frame.paused = self.paused;
+        frame.movie = !matches!(self.movie, MovieState::None);
+        frame.count = self.count;
+        frame.previous_display = self.previous_display;
+        frame.display_version = self.display_version;
+        frame.ips = if self.paused { 0 } else { self.ips.rate() };
+        self.input.publish();
+    }
+
+    /// A frame captured outside the triple buffer, for callers waiting on a reply.
+    fn frame_now(&self) -> Frame {
+        Frame {
+            paused: self.paused,
+            movie: !matches!(self.movie, MovieState::None),
+            count: self.count,
+            previous_display: self.previous_display,
+            display_version: self.display_version,
+            ips: if self.paused { 0 } else { self.ips.rate() },
+            ..Frame::new(&self.cpu)
+        }
+    }
+}
+
+/// Counts instructions over a fixed window, holding the last complete sample.
+struct IpsCounter {
+    start: Instant,
+    count: u64,
+    rate: u64,
+}
+
+impl IpsCounter {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            count: 0,
+            rate: 0,
+        }
+    }
+
+    fn add(&mut self, instructions: u64) {
+        self.count += instructions;
+        let elapsed = self.start.elapsed();
+        if elapsed >= IPS_WINDOW {
+            self.rate = (self.count as f64 / elapsed.as_secs_f64()) as u64;
+            self.start = Instant::now();
+            self.count = 0;
+        }
+    }
+
+    fn rate(&self) -> u64 {
+        self.rate
+    }
+}
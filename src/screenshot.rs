@@ -0,0 +1,350 @@
+use crate::config::Config;
+use crate::cpu::{Cpu, CpuError, DEFAULT_IPF, HEIGHT, WIDTH};
+use crate::palette::{Palette, Preset, Rgb};
+use log::info;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use thiserror::Error;
+
+const USAGE: &str =
+    "usage: c8emu screenshot <rom> <output.png|.pbm|.pgm> [--frames N] [--scale N] [--palette NAME]";
+const DEFAULT_FRAMES: usize = 60; // Run for one second before capturing
+pub const MAX_SCALE: usize = 64; // Largest pixel size for screenshots and recordings
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum ScreenshotError {
+    #[error("{}", USAGE)]
+    Usage,
+    #[error("Unknown image format for '{path}', expected .png, .pbm or .pgm")]
+    FormatError { path: String },
+    #[error("Failed to write image '{path}': {err}")]
+    WriteError { path: String, err: std::io::Error },
+    #[error("Failed to encode PNG '{path}': {err}")]
+    EncodeError {
+        path: String,
+        err: png::EncodingError,
+    },
+    #[error("Unknown palette '{name}'")]
+    PaletteError { name: String },
+    #[error("Scale {scale} out of range 1 - {}", MAX_SCALE)]
+    ScaleError { scale: usize },
+    #[error("{err}")]
+    CpuError { err: CpuError },
+}
+
+/// Save a framebuffer, picking the format from the file extension. Every pixel is
+/// drawn as a `scale` x `scale` block; PBM ignores the palette and stores on/off.
+pub fn save(
+    path: &str,
+    display: &[[bool; WIDTH]; HEIGHT],
+    palette: &Palette,
+    scale: usize,
+) -> Result<(), ScreenshotError> {
+    if !(1..=MAX_SCALE).contains(&scale) {
+        return Err(ScreenshotError::ScaleError { scale });
+    }
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    // Check the format before creating the file, so a bad name doesn't truncate it
+    let format = match ext.as_deref() {
+        Some(format @ ("png" | "pbm" | "pgm")) => format,
+        _ => {
+            return Err(ScreenshotError::FormatError {
+                path: path.to_string(),
+            })
+        }
+    };
+    let (width, height) = (WIDTH * scale, HEIGHT * scale);
+    let pixels = (0..height).flat_map(|y| (0..width).map(move |x| display[y / scale][x / scale]));
+
+    let write_err = |e: std::io::Error| ScreenshotError::WriteError {
+        path: path.to_string(),
+        err: e,
+    };
+    let file = File::create(path).map_err(write_err)?;
+    let mut out = BufWriter::new(file);
+
+    match format {
+        "png" => write_png(&mut out, display, palette, scale).map_err(|e| {
+            ScreenshotError::EncodeError {
+                path: path.to_string(),
+                err: e,
+            }
+        })?,
+        "pbm" => {
+            // Binary PBM: 1 is black, rows padded to a whole byte
+            write!(out, "P4\n{} {}\n", width, height).map_err(write_err)?;
+            let pixels: Vec<bool> = pixels.collect();
+            for row in pixels.chunks(width) {
+                let bytes: Vec<u8> = row
+                    .chunks(8)
+                    .map(|bits| {
+                        bits.iter()
+                            .enumerate()
+                            .fold(0u8, |byte, (i, &on)| byte | ((on as u8) << (7 - i)))
+                    })
+                    .collect();
+                out.write_all(&bytes).map_err(write_err)?;
+            }
+        }
+        "pgm" => {
+            // Binary PGM, palette colours reduced to luma
+            write!(out, "P5\n{} {}\n255\n", width, height).map_err(write_err)?;
+            let luma = |Rgb(r, g, b): Rgb| {
+                ((299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000) as u8
+            };
+            let shades = [luma(palette.background()), luma(palette.foreground())];
+            let data: Vec<u8> = pixels.map(|on| shades[on as usize]).collect();
+            out.write_all(&data).map_err(write_err)?;
+        }
+        _ => unreachable!("format checked above"),
+    }
+    out.flush().map_err(write_err)?;
+
+    info!("Saved screenshot to '{}'", path);
+    Ok(())
+}
+
+/// Encode a framebuffer as an RGB PNG, every pixel drawn as a `scale` x `scale` block.
+/// A scale outside 1 - `MAX_SCALE` exceeds the encoder's limits.
+pub fn write_png(
+    out: impl Write,
+    display: &[[bool; WIDTH]; HEIGHT],
+    palette: &Palette,
+    scale: usize,
+) -> Result<(), png::EncodingError> {
+    if !(1..=MAX_SCALE).contains(&scale) {
+        return Err(png::EncodingError::LimitsExceeded);
+    }
+    let (width, height) = (WIDTH * scale, HEIGHT * scale);
+    let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data: Vec<u8> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| display[y / scale][x / scale]))
+        .flat_map(|on| {
+            let Rgb(r, g, b) = palette.color(on as usize);
+            [r, g, b]
+        })
+        .collect();
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+}
+
+/// File name for a screenshot or recording started now, in the working directory.
+pub fn timestamped_path(ext: &str) -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("c8emu-{}.{}", secs, ext)
+}
+
+/// Entry point for `c8emu screenshot`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    match capture(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn capture(args: &[String]) -> Result<(), ScreenshotError> {
+    let args = CaptureArgs::parse(args, DEFAULT_FRAMES)?;
+
+    let mut cpu = Cpu::new();
+    let loaded = cpu
+        .load_rom(&args.rom)
+        .map_err(|e| ScreenshotError::CpuError { err: e })?;
+    let ipf = loaded
+        .info
+        .and_then(|info| info.tickrate)
+        .unwrap_or(DEFAULT_IPF);
+    for _ in 0..args.frames {
+        cpu.run_frame(ipf)
+            .map_err(|e| ScreenshotError::CpuError { err: e })?;
+    }
+
+    save(&args.output, &cpu.get_display(), &args.palette, args.scale)
+}
+
+/// Options shared by the headless `screenshot` and `record` commands.
+pub struct CaptureArgs {
+    pub rom: String,
+    pub output: String,
+    pub frames: usize, // Frames to run before capturing, or to record
+    pub scale: usize,
+    pub palette: Palette,
+}
+
+impl CaptureArgs {
+    pub fn parse(args: &[String], frames: usize) -> Result<Self, ScreenshotError> {
+        let mut paths = Vec::new();
+        let mut frames = frames;
+        let mut scale = 1;
+        let mut palette = None;
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--frames" => frames = parse_next(&mut iter)?,
+                "--scale" => scale = parse_next(&mut iter)?,
+                "--palette" => {
+                    let name = iter.next().ok_or(ScreenshotError::Usage)?;
+                    let preset =
+                        Preset::from_name(name).ok_or_else(|| ScreenshotError::PaletteError {
+                            name: name.to_string(),
+                        })?;
+                    palette = Some(preset.palette());
+                }
+                _ => paths.push(arg.as_str()),
+            }
+        }
+        let [rom, output] = paths[..] else {
+            return Err(ScreenshotError::Usage);
+        };
+        if !(1..=MAX_SCALE).contains(&scale) {
+            return Err(ScreenshotError::ScaleError { scale });
+        }
+        // Fall back to the palette chosen in the GUI
+        let palette = palette.unwrap_or_else(|| {
+            Config::load()
+                .map(|config| config.display.palette())
+                .unwrap_or_else(|_| Preset::Classic.palette())
+        });
+
+        Ok(Self {
+            rom: rom.to_string(),
+            output: output.to_string(),
+            frames,
+            scale,
+            palette,
+        })
+    }
+}
+
+fn parse_next<'a, T: std::str::FromStr>(
+    iter: &mut impl Iterator<Item = &'a String>,
+) -> Result<T, ScreenshotError> {
+    iter.next()
+        .and_then(|n| n.parse().ok())
+        .ok_or(ScreenshotError::Usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A blank screen with the top left and bottom right pixels lit.
+    fn corners() -> [[bool; WIDTH]; HEIGHT] {
+        let mut display = [[false; WIDTH]; HEIGHT];
+        display[0][0] = true;
+        display[HEIGHT - 1][WIDTH - 1] = true;
+        display
+    }
+
+    fn palette() -> Palette {
+        Palette {
+            colors: vec![Rgb(0x10, 0x20, 0x30), Rgb(0xFF, 0xFF, 0xFF)],
+        }
+    }
+
+    /// Save to a scratch file and read it back.
+    fn saved(name: &str, scale: usize) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("c8emu-{}-{}", std::process::id(), name));
+        let path = path.to_str().unwrap();
+        save(path, &corners(), &palette(), scale).unwrap();
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        data
+    }
+
+    #[test]
+    fn writes_scaled_png() {
+        let data = saved("shot.png", 2);
+        let decoder = png::Decoder::new(data.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+
+        let at = |x: usize, y: usize| &pixels[(y * 128 + x) * 3..][..3];
+        for (x, y) in [(0, 0), (1, 1), (127, 63)] {
+            assert_eq!(at(x, y), [0xFF, 0xFF, 0xFF], "({}, {})", x, y);
+        }
+        for (x, y) in [(2, 0), (0, 2), (126, 61)] {
+            assert_eq!(at(x, y), [0x10, 0x20, 0x30], "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn writes_pbm_and_pgm() {
+        let data = saved("shot.pbm", 1);
+        let header = b"P4\n64 32\n";
+        assert_eq!(&data[..header.len()], header);
+        let rows = &data[header.len()..];
+        assert_eq!(rows.len(), 8 * 32);
+        assert_eq!(rows[0], 0x80);
+        assert_eq!(rows[8 * 32 - 1], 0x01);
+        assert_eq!(rows.iter().filter(|&&b| b != 0).count(), 2);
+
+        let data = saved("shot.pgm", 1);
+        let header = b"P5\n64 32\n255\n";
+        assert_eq!(&data[..header.len()], header);
+        let pixels = &data[header.len()..];
+        assert_eq!(pixels.len(), 64 * 32);
+        // Luma of #102030 and white
+        assert_eq!((pixels[0], pixels[1], pixels[64 * 32 - 1]), (255, 29, 255));
+    }
+
+    #[test]
+    fn rejects_bad_scales_and_formats() {
+        let args = |scale: &str| -> Vec<String> {
+            [
+                "rom.ch8",
+                "out.png",
+                "--palette",
+                "classic",
+                "--scale",
+                scale,
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+        };
+        assert_eq!(CaptureArgs::parse(&args("64"), 1).unwrap().scale, 64);
+        for scale in ["0", "65", "100000"] {
+            assert!(
+                matches!(
+                    CaptureArgs::parse(&args(scale), 1),
+                    Err(ScreenshotError::ScaleError { .. })
+                ),
+                "{}",
+                scale
+            );
+        }
+        assert!(matches!(
+            CaptureArgs::parse(&args("big"), 1),
+            Err(ScreenshotError::Usage)
+        ));
+
+        let display = corners();
+        assert!(write_png(Vec::new(), &display, &palette(), 100_000).is_err());
+        assert!(matches!(
+            save("shot.png", &display, &palette(), 0),
+            Err(ScreenshotError::ScaleError { scale: 0 })
+        ));
+        assert!(matches!(
+            save("shot.bmp", &display, &palette(), 1),
+            Err(ScreenshotError::FormatError { .. })
+        ));
+    }
+}
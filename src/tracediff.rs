@@ -0,0 +1,655 @@
+// `c8emu tracediff` - compare an execution trace against a reference emulator's log
+//
+// Both inputs may be a c8emu trace (text or binary, see `cpu::trace`) or a reference log
+// with one instruction per line made of `KEY:VALUE` / `KEY=VALUE` hex fields, e.g.
+//   PC:0200 OP:00E0 V0:00 V1:00 ... VF:00 I:0000 SP:0 DT:00 ST:00
+// Register values in a reference log are the state *before* the instruction executes.
+// c8emu traces list changes made between instructions (timer ticks, debugger writes)
+// separately, so the rebuilt state matches a reference at every frame boundary.
+
+use crate::cpu::trace::{BIN_MAGIC, BIN_VERSION, MASK_FAULT, MASK_PRE};
+use std::fs;
+use thiserror::Error;
+
+const DEFAULT_CONTEXT: usize = 5;
+const USAGE: &str = "usage: c8emu tracediff <trace> <reference> [--context N]";
+
+#[derive(Error, Debug)]
+pub enum TraceDiffError {
+    #[error("{}", USAGE)]
+    Usage,
+    #[error("Failed to read trace file '{path}': {err}")]
+    ReadError { path: String, err: std::io::Error },
+    #[error("{path}:{line}: {msg}")]
+    ParseError {
+        path: String,
+        line: usize,
+        msg: String,
+    },
+}
+
+/// One executed instruction with the register state before it ran. Registers not
+/// present in the source log are `None` and never compared.
+#[derive(Debug, Clone, Default)]
+pub struct Step {
+    pub line: usize,
+    pub pc: u16,
+    pub opcode: Option<u16>,
+    pub v: [Option<u8>; 16],
+    pub i: Option<u16>,
+    pub sp: Option<u8>,
+    pub dt: Option<u8>,
+    pub st: Option<u8>,
+}
+
+impl Step {
+    /// Names and values of fields that differ where both sides are known.
+    fn diff(&self, other: &Step) -> Vec<String> {
+        let mut out = Vec::new();
+        if self.pc != other.pc {
+            out.push(format!("PC {:03X} != {:03X}", self.pc, other.pc));
+        }
+        if let (Some(a), Some(b)) = (self.opcode, other.opcode) {
+            if a != b {
+                out.push(format!("opcode {:04X} != {:04X}", a, b));
+            }
+        }
+        for r in 0..16 {
+            if let (Some(a), Some(b)) = (self.v[r], other.v[r]) {
+                if a != b {
+                    out.push(format!("V{:X} {:02X} != {:02X}", r, a, b));
+                }
+            }
+        }
+        if let (Some(a), Some(b)) = (self.i, other.i) {
+            if a != b {
+                out.push(format!("I {:03X} != {:03X}", a, b));
+            }
+        }
+        for (name, a, b) in [
+            ("SP", self.sp, other.sp),
+            ("DT", self.dt, other.dt),
+            ("ST", self.st, other.st),
+        ] {
+            if let (Some(a), Some(b)) = (a, b) {
+                if a != b {
+                    out.push(format!("{} {:02X} != {:02X}", name, a, b));
+                }
+            }
+        }
+        out
+    }
+
+    fn summary(&self) -> String {
+        let mut line = format!(
+            "{:>6}: {:03X} {}",
+            self.line,
+            self.pc,
+            self.opcode
+                .map(|op| format!("{:04X}", op))
+                .unwrap_or_else(|| String::from("----"))
+        );
+        for (r, v) in self.v.iter().enumerate() {
+            if let Some(v) = v {
+                line += &format!(" V{:X}:{:02X}", r, v);
+            }
+        }
+        if let Some(i) = self.i {
+            line += &format!(" I:{:03X}", i);
+        }
+        line
+    }
+}
+
+/// Register state rebuilt from a delta trace, unknown until first written.
+struct Replay {
+    state: Step,
+}
+
+impl Replay {
+    fn new(from_reset: bool) -> Self {
+        let zero = from_reset.then_some(0);
+        Self {
+            state: Step {
+                v: [zero; 16],
+                i: zero.map(u16::from),
+                sp: zero,
+                dt: zero,
+                st: zero,
+                ..Step::default()
+            },
+        }
+    }
+
+    fn step(&self, line: usize, pc: u16, opcode: u16) -> Step {
+        Step {
+            line,
+            pc,
+            opcode: Some(opcode),
+            ..self.state.clone()
+        }
+    }
+
+    fn set(&mut self, reg: &str, value: u16) -> bool {
+        match reg {
+            "I" => self.state.i = Some(value),
+            "SP" => self.state.sp = Some(value as u8),
+            "DT" => self.state.dt = Some(value as u8),
+            "ST" => self.state.st = Some(value as u8),
+            _ => match reg
+                .strip_prefix('V')
+                .and_then(|r| u8::from_str_radix(r, 16).ok())
+            {
+                Some(r) if r < 16 => self.state.v[r as usize] = Some(value as u8),
+                _ => return false,
+            },
+        }
+        true
+    }
+}
+
+fn hex<T: TryFrom<u32>>(text: &str) -> Option<T> {
+    let text = text.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(text, 16)
+        .ok()
+        .and_then(|v| T::try_from(v).ok())
+}
+
+/// Parse a c8emu text trace, rebuilding register state from the per-line deltas.
+fn parse_c8emu_text(path: &str, text: &str) -> Result<Vec<Step>, TraceDiffError> {
+    let err = |line: usize, msg: &str| TraceDiffError::ParseError {
+        path: path.to_string(),
+        line,
+        msg: msg.to_string(),
+    };
+
+    let mut steps = Vec::new();
+    let mut replay: Option<Replay> = None;
+
+    for (n, raw) in text.lines().enumerate() {
+        let line = n + 1;
+        let fields: Vec<&str> = raw.split_whitespace().collect();
+        if fields.len() < 3 {
+            continue;
+        }
+        if fields[2] == "FAULT" {
+            break;
+        }
+
+        let cycle: u64 = fields[0]
+            .parse()
+            .map_err(|_| err(line, "bad cycle count"))?;
+        let pc = hex::<u16>(fields[1]).ok_or_else(|| err(line, "bad PC"))?;
+        let opcode = hex::<u16>(fields[2]).ok_or_else(|| err(line, "bad opcode"))?;
+        let replay = replay.get_or_insert_with(|| Replay::new(cycle == 1));
+
+        // Changes made between instructions, such as a timer tick at the end of a frame
+        for field in fields.iter().skip(3).filter(|f| !f.contains("->")) {
+            let Some((reg, value)) = field.split_once('=') else {
+                continue;
+            };
+            let value = hex::<u16>(value).ok_or_else(|| err(line, "bad register value"))?;
+            if !replay.set(reg, value) {
+                return Err(err(line, "unknown register"));
+            }
+        }
+        steps.push(replay.step(line, pc, opcode));
+
+        for delta in fields.iter().filter(|f| f.contains("->")) {
+            let (reg, values) = delta
+                .split_once(':')
+                .ok_or_else(|| err(line, "bad delta"))?;
+            let (_, new) = values
+                .split_once("->")
+                .ok_or_else(|| err(line, "bad delta"))?;
+            let new = hex::<u16>(new).ok_or_else(|| err(line, "bad register value"))?;
+            if !replay.set(reg, new) {
+                return Err(err(line, "unknown register"));
+            }
+        }
+    }
+    Ok(steps)
+}
+
+/// Parse a c8emu binary trace (`C8TR` header).
+fn parse_c8emu_binary(path: &str, data: &[u8]) -> Result<Vec<Step>, TraceDiffError> {
+    let err = |offset: usize, msg: &str| TraceDiffError::ParseError {
+        path: path.to_string(),
+        line: offset,
+        msg: msg.to_string(),
+    };
+
+    match data.get(BIN_MAGIC.len()) {
+        Some(&BIN_VERSION) => {}
+        Some(version) => return Err(err(4, &format!("unsupported trace version {}", version))),
+        None => return Err(err(4, "truncated header")),
+    }
+
+    let mut steps = Vec::new();
+    let mut replay: Option<Replay> = None;
+    let mut pos = BIN_MAGIC.len() + 1;
+
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + 16)
+            .ok_or_else(|| err(pos, "truncated record"))?;
+        let cycle = u64::from_le_bytes(header[0..8].try_into().unwrap_or_default());
+        let pc = u16::from_le_bytes([header[8], header[9]]);
+        let opcode = u16::from_le_bytes([header[10], header[11]]);
+        let mask = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        pos += 16;
+
+        if mask & MASK_FAULT != 0 {
+            break; // Fault marker
+        }
+
+        let replay = replay.get_or_insert_with(|| Replay::new(cycle == 1));
+        if mask & MASK_PRE != 0 {
+            let pre = data
+                .get(pos..pos + 4)
+                .ok_or_else(|| err(pos, "truncated record"))?;
+            let pre = u32::from_le_bytes([pre[0], pre[1], pre[2], pre[3]]);
+            pos += 4;
+            read_registers(data, &mut pos, pre, replay)
+                .map_err(|at| err(at, "truncated record"))?;
+        }
+        steps.push(replay.step(steps.len() + 1, pc, opcode));
+        read_registers(data, &mut pos, mask, replay).map_err(|at| err(at, "truncated record"))?;
+    }
+    Ok(steps)
+}
+
+/// Apply the register values selected by `mask` at `pos`, returning the offset of a
+/// truncated value on error.
+fn read_registers(
+    data: &[u8],
+    pos: &mut usize,
+    mask: u32,
+    replay: &mut Replay,
+) -> Result<(), usize> {
+    let mut take = |len: usize| -> Result<u16, usize> {
+        let bytes = data.get(*pos..*pos + len).ok_or(*pos)?;
+        *pos += len;
+        Ok(if len == 2 {
+            u16::from_le_bytes([bytes[0], bytes[1]])
+        } else {
+            bytes[0] as u16
+        })
+    };
+    for r in 0..16 {
+        if mask & (1 << r) != 0 {
+            replay.set(&format!("V{:X}", r), take(1)?);
+        }
+    }
+    for (bit, reg, len) in [(16, "I", 2), (17, "DT", 1), (18, "ST", 1), (19, "SP", 1)] {
+        if mask & (1 << bit) != 0 {
+            replay.set(reg, take(len)?);
+        }
+    }
+    Ok(())
+}
+
+/// Parse a reference log of `KEY:VALUE` fields, one instruction per line.
+fn parse_reference(path: &str, text: &str) -> Result<Vec<Step>, TraceDiffError> {
+    let mut steps = Vec::new();
+
+    for (n, raw) in text.lines().enumerate() {
+        let mut replay = Replay::new(false);
+        let mut step_pc = None;
+        let mut opcode = None;
+
+        for field in raw.split(|c: char| c.is_whitespace() || c == ',') {
+            let Some((key, value)) = field.split_once([':', '=']) else {
+                continue;
+            };
+            let key = key.trim().to_ascii_uppercase();
+            let Some(value) = hex::<u16>(value.trim()) else {
+                continue;
+            };
+            match key.as_str() {
+                "PC" => step_pc = Some(value),
+                "OP" | "OPCODE" | "INSTR" => opcode = Some(value),
+                _ => {
+                    replay.set(&key, value);
+                }
+            }
+        }
+
+        let Some(pc) = step_pc else {
+            continue; // Not an instruction line
+        };
+        steps.push(Step {
+            line: n + 1,
+            pc,
+            opcode,
+            ..replay.state
+        });
+    }
+
+    if steps.is_empty() {
+        return Err(TraceDiffError::ParseError {
+            path: path.to_string(),
+            line: 0,
+            msg: String::from("no instruction lines with a PC field"),
+        });
+    }
+    Ok(steps)
+}
+
+/// Our text traces start with a decimal cycle count, zero padded to at least 8
+/// digits; reference logs start with a `KEY:VALUE` field.
+fn is_c8emu_text(text: &str) -> bool {
+    text.lines()
+        .next()
+        .and_then(|l| l.split_whitespace().next())
+        .is_some_and(|f| f.len() >= 8 && f.bytes().all(|b| b.is_ascii_digit()))
+}
+
+pub fn load(path: &str) -> Result<Vec<Step>, TraceDiffError> {
+    let data = fs::read(path).map_err(|e| TraceDiffError::ReadError {
+        path: path.to_string(),
+        err: e,
+    })?;
+
+    let steps = if data.starts_with(BIN_MAGIC) {
+        parse_c8emu_binary(path, &data)?
+    } else {
+        let text = String::from_utf8_lossy(&data);
+        if is_c8emu_text(&text) {
+            parse_c8emu_text(path, &text)?
+        } else {
+            parse_reference(path, &text)?
+        }
+    };
+
+    // An empty trace would otherwise match anything
+    if steps.is_empty() {
+        return Err(TraceDiffError::ParseError {
+            path: path.to_string(),
+            line: 0,
+            msg: String::from("no instructions in trace"),
+        });
+    }
+    Ok(steps)
+}
+
+pub struct Divergence {
+    pub index: usize,
+    pub ours: usize,   // Index into our trace
+    pub theirs: usize, // Index into the reference trace
+    pub fields: Vec<String>,
+}
+
+/// Align both traces on the first instruction of ours, then walk them in lockstep.
+/// An empty trace on either side diverges at once.
+pub fn first_divergence(ours: &[Step], theirs: &[Step]) -> Option<Divergence> {
+    let Some(first) = ours.first() else {
+        return Some(Divergence {
+            index: 0,
+            ours: 0,
+            theirs: 0,
+            fields: vec![format!("trace length 0 != {}", theirs.len())],
+        });
+    };
+    let start = theirs
+        .iter()
+        .position(|s| s.pc == first.pc && first.diff(s).is_empty())
+        .or_else(|| theirs.iter().position(|s| s.pc == first.pc))
+        .unwrap_or(0);
+
+    for (index, (a, b)) in ours.iter().zip(theirs[start..].iter()).enumerate() {
+        let fields = a.diff(b);
+        if !fields.is_empty() {
+            return Some(Divergence {
+                index,
+                ours: index,
+                theirs: start + index,
+                fields,
+            });
+        }
+    }
+
+    let compared = ours.len().min(theirs.len() - start);
+    if ours.len() != theirs.len() - start {
+        return Some(Divergence {
+            index: compared,
+            ours: compared,
+            theirs: start + compared,
+            fields: vec![format!(
+                "trace length {} != {}",
+                ours.len(),
+                theirs.len() - start
+            )],
+        });
+    }
+    None
+}
+
+/// Summaries of the steps within `context` of `at`, which is marked.
+fn context_lines(steps: &[Step], at: usize, context: usize) -> Vec<String> {
+    steps
+        .iter()
+        .enumerate()
+        .take(at + context + 1)
+        .skip(at.saturating_sub(context))
+        .map(|(idx, step)| format!("{} {}", if idx == at { ">>" } else { "  " }, step.summary()))
+        .collect()
+}
+
+fn print_context(label: &str, steps: &[Step], at: usize, context: usize) {
+    println!("{}:", label);
+    for line in context_lines(steps, at, context) {
+        println!("{}", line);
+    }
+}
+
+/// Entry point for `c8emu tracediff`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    match diff_files(args) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+fn diff_files(args: &[String]) -> Result<bool, TraceDiffError> {
+    let mut paths = Vec::new();
+    let mut context = DEFAULT_CONTEXT;
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--context" | "-C" => {
+                context = iter
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(TraceDiffError::Usage)?;
+            }
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let [ours_path, theirs_path] = paths[..] else {
+        return Err(TraceDiffError::Usage);
+    };
+
+    let ours = load(ours_path)?;
+    let theirs = load(theirs_path)?;
+
+    match first_divergence(&ours, &theirs) {
+        None => {
+            println!("Traces match ({} instructions)", ours.len());
+            Ok(true)
+        }
+        Some(d) => {
+            println!("First divergence at instruction {}:", d.index + 1);
+            for field in &d.fields {
+                println!("  {}", field);
+            }
+            println!();
+            print_context(ours_path, &ours, d.ours, context);
+            println!();
+            print_context(theirs_path, &theirs, d.theirs, context);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::trace::{Registers, TraceFormat, TraceRecord, Tracer};
+    use crate::cpu::Cpu;
+
+    const OURS: &str = "\
+00000001 200 6005 LD V0, 05        V0:00->05
+00000002 202 A210 LD I, 210        I:000->210
+00000003 204 7001 ADD V0, 01       V0:05->06
+00000004 206 1206 JP 206
+";
+
+    /// Write a trace to a scratch file and load it.
+    fn load_bytes(name: &str, data: &[u8]) -> Result<Vec<Step>, TraceDiffError> {
+        let path = std::env::temp_dir().join(format!("c8emu-{}-{}", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, data).unwrap();
+        let result = load(&path);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn reference(v0_after_add: u8) -> String {
+        format!(
+            "PC:0200 OP:6005 V0:00 I:0000\n\
+             PC=0202, OP=A210, V0=05, I=0000\n\
+             PC:0204 OP:7001 V0:05 I:0210\n\
+             PC:0206 OP:1206 V0:{:02X} I:0210\n",
+            v0_after_add
+        )
+    }
+
+    #[test]
+    fn parses_text_traces_and_reference_logs() {
+        let ours = load_bytes("ours.log", OURS.as_bytes()).unwrap();
+        assert_eq!(ours.len(), 4);
+        assert_eq!((ours[3].pc, ours[3].opcode), (0x206, Some(0x1206)));
+        assert_eq!((ours[3].v[0], ours[3].i), (Some(0x06), Some(0x210)));
+
+        let theirs = load_bytes("ref.log", reference(6).as_bytes()).unwrap();
+        assert_eq!(theirs.len(), 4);
+        assert_eq!((theirs[1].pc, theirs[1].v[0]), (0x202, Some(0x05)));
+        assert_eq!(theirs[1].v[1], None);
+        assert!(first_divergence(&ours, &theirs).is_none());
+
+        // Cycle counts grow past 8 digits in long runs
+        let late = "123456789 204 7001 ADD V0, 01       V0:05->06\n";
+        let late = load_bytes("late.log", late.as_bytes()).unwrap();
+        assert_eq!((late[0].pc, late[0].v[0]), (0x204, None));
+    }
+
+    #[test]
+    fn parses_binary_traces() {
+        let before = Registers {
+            v: [0; 16],
+            i: 0,
+            dt: 0,
+            st: 0,
+            sp: 0,
+        };
+        let mut after = before;
+        after.v[0] = 5;
+        let mut data = BIN_MAGIC.to_vec();
+        data.push(BIN_VERSION);
+        for (cycle, pc, opcode, before, after) in [
+            (1, 0x200, 0x6005, before, after),
+            (2, 0x202, 0x1202, after, after),
+        ] {
+            let record = TraceRecord {
+                cycle,
+                pc,
+                opcode,
+                prev: before,
+                before,
+                after,
+            };
+            data.extend(record.to_binary());
+        }
+
+        let steps = load_bytes("ours.bin", &data).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!((steps[1].pc, steps[1].v[0]), (0x202, Some(5)));
+
+        data[BIN_MAGIC.len()] = BIN_VERSION + 1;
+        assert!(load_bytes("future.bin", &data).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_traces() {
+        let fault = "00000001 200 FAULT Program counter out of range: 1000\n";
+        for (name, data) in [
+            ("fault.log", fault),
+            ("empty.log", ""),
+            ("notes.log", "hello\n"),
+        ] {
+            let err = load_bytes(name, data.as_bytes()).unwrap_err();
+            assert!(
+                matches!(err, TraceDiffError::ParseError { line: 0, .. }),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn reports_first_divergence_with_context() {
+        let ours = load_bytes("diverging.log", OURS.as_bytes()).unwrap();
+        let theirs = load_bytes("diverging-ref.log", reference(7).as_bytes()).unwrap();
+
+        let d = first_divergence(&ours, &theirs).unwrap();
+        assert_eq!((d.index, d.ours, d.theirs), (3, 3, 3));
+        assert_eq!(d.fields, ["V0 06 != 07"]);
+
+        let lines = context_lines(&theirs, d.theirs, 1);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("   "));
+        assert!(lines[1].starts_with(">>") && lines[1].contains("206 1206 V0:07"));
+    }
+
+    #[test]
+    fn timer_ticks_between_frames_match_the_reference() {
+        // LD V0, 05; LD DT, V0; JP 204 - the delay timer ticks after every frame
+        let reference = "\
+PC:0200 OP:6005 V0:00 DT:00
+PC:0202 OP:F015 V0:05 DT:00
+PC:0204 OP:1204 V0:05 DT:05
+PC:0204 OP:1204 V0:05 DT:04
+PC:0204 OP:1204 V0:05 DT:03
+";
+        let theirs = load_bytes("timers-ref.log", reference.as_bytes()).unwrap();
+
+        for (name, format) in [
+            ("timers.log", TraceFormat::Text),
+            ("timers.bin", TraceFormat::Binary),
+        ] {
+            let path = std::env::temp_dir().join(format!("c8emu-{}-{}", name, std::process::id()));
+            let path = path.to_str().unwrap().to_string();
+            let mut cpu = Cpu::new();
+            for (addr, byte) in [0x60, 0x05, 0xF0, 0x15, 0x12, 0x04].iter().enumerate() {
+                cpu.set_memory(0x200 + addr, *byte);
+            }
+            cpu.set_tracer(Some(Tracer::to_file(&path, format, None).unwrap()));
+            for ipf in [3, 1, 1] {
+                cpu.run_frame(ipf).unwrap();
+            }
+            cpu.set_tracer(None); // Flush
+
+            let ours = load(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(ours.len(), 5, "{}", name);
+            assert_eq!(ours[4].dt, Some(3), "{}", name);
+            assert!(first_divergence(&ours, &theirs).is_none(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use crate::args::{ArgsError, LaunchArgs};
+use crate::config::Config;
+use crate::cpu::{Cpu, CpuError, Quirks, RomLoadResult, DEFAULT_IPF, HEIGHT, WIDTH};
+use crate::keymap::KeyMap;
+use crate::palette::{Palette, Preset, Rgb};
+use crate::remote::{self, RemoteError};
+use crate::romfile;
+use crate::runner::{self, Frame, Runner, FRAME};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+};
+use crossterm::style::{Color, Print, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, terminal, QueueableCommand};
+use std::io::{BufWriter, Stdout, Write};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+const USAGE: &str = "usage: c8emu tui <rom> [--sidebar] [--quirks PLATFORM] [--ips N] \
+[--palette NAME] [--paused] [--remote PORT]";
+const HELP: &str = "Esc quit  F4 step  F5 pause  F6 frame  Tab registers";
+// Terminals without key release events repeat held keys instead; a key counts as
+// released once it stops repeating for this long
+const KEY_HOLD: Duration = Duration::from_millis(150);
+const SIDEBAR_COLUMN: u16 = WIDTH as u16 + 2;
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum TuiError {
+    #[error("{}", USAGE)]
+    Usage,
+    #[error("{err}")]
+    ArgsError { err: ArgsError },
+    #[error("Terminal error: {err}")]
+    TerminalError { err: std::io::Error },
+    #[error("Error loading ROM: {err}")]
+    CpuError { err: CpuError },
+    #[error("{err}")]
+    RemoteError { err: RemoteError },
+}
+
+impl From<std::io::Error> for TuiError {
+    fn from(err: std::io::Error) -> Self {
+        TuiError::TerminalError { err }
+    }
+}
+
+/// Entry point for `c8emu tui`, returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    match play(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn play(args: &[String]) -> Result<(), TuiError> {
+    let sidebar = args.iter().any(|arg| arg == "--sidebar");
+    let args: Vec<String> = args
+        .iter()
+        .filter(|&arg| arg != "--sidebar")
+        .cloned()
+        .collect();
+    let launch = LaunchArgs::parse(&args).map_err(|e| match e {
+        ArgsError::Usage => TuiError::Usage,
+        e => TuiError::ArgsError { err: e },
+    })?;
+    let rom = match &launch.rom {
+        Some(rom) if launch.scale.is_none() && !launch.fullscreen => rom.clone(),
+        _ => return Err(TuiError::Usage),
+    };
+
+    // Shown before the terminal switches to raw mode, where it would be lost
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}, using the default settings", e);
+        Config::default()
+    });
+    let mut cpu = Cpu::new();
+    cpu.set_default_quirks(config.quirks);
+    let runner = Runner::spawn(cpu, config.audio.clone(), DEFAULT_IPF);
+    runner.send(runner::Command::SetSpeed(config.speed));
+
+    let mut tui = Tui {
+        runner,
+        remote: None,
+        key_map: config.key_map(None),
+        palette: config.display.palette(),
+        preset: launch.palette,
+        config,
+        rom: String::new(),
+        sidebar,
+        status: String::new(),
+        held: [None; 16],
+        releases: false,
+        out: BufWriter::new(std::io::stdout()),
+    };
+    tui.load(rom, launch.quirks);
+    if let Some(ipf) = launch.ipf() {
+        tui.runner.send(runner::Command::SetIpf(ipf));
+    }
+    if launch.paused {
+        tui.runner.send(runner::Command::Pause(true));
+    }
+
+    // Wait for the ROM, so a bad path is reported before the screen is taken over
+    let loaded = loop {
+        match tui.runner.poll_event() {
+            Some(runner::Event::RomLoaded(result)) => {
+                break result.map_err(|e| TuiError::CpuError { err: e })?
+            }
+            Some(_) => {}
+            None => std::thread::sleep(Duration::from_millis(1)),
+        }
+    };
+    tui.loaded(&loaded);
+
+    tui.remote = launch
+        .remote
+        .map(remote::Server::start)
+        .transpose()
+        .map_err(|e| TuiError::RemoteError { err: e })?;
+
+    tui.enter()?;
+    let result = tui.run();
+    tui.leave()?;
+    result
+}
+
+/// Terminal frontend, drawing two CHIP-8 pixels per character cell.
+struct Tui {
+    runner: Runner,
+    remote: Option<remote::Server>,
+    key_map: KeyMap,
+    palette: Palette,
+    preset: Option<Preset>, // Chosen on the command line, over the config file and ROM colours
+    config: Config,
+    rom: String,   // Path of the last ROM sent to the runner
+    sidebar: bool, // Show registers beside the screen
+    status: String,
+    held: [Option<Instant>; 16], // When each held keypad key is released, without release events
+    releases: bool,              // Whether the terminal reports key releases
+    out: BufWriter<Stdout>,
+}
+
+impl Tui {
+    /// Load a ROM with the quirks saved for it in the config file, unless `quirks`
+    /// overrides them. Every load goes through here, including remote ones.
+    fn load(&mut self, rom: String, quirks: Option<Quirks>) {
+        let quirks = quirks.or_else(|| {
+            romfile::sha1(&rom)
+                .ok()
+                .and_then(|sha1| self.config.rom(&sha1, &rom))
+                .and_then(|config| config.quirks)
+        });
+        self.rom = rom.clone();
+        self.runner.send(runner::Command::Load(rom, quirks));
+    }
+
+    /// Pick up the key map and colours for a ROM that has just loaded.
+    fn loaded(&mut self, loaded: &RomLoadResult) {
+        self.key_map = self
+            .config
+            .key_map(self.config.rom(&loaded.sha1, &self.rom));
+        let colors = loaded.info.as_ref().and_then(|info| info.palette.clone());
+        self.palette = match (self.preset, colors) {
+            (Some(preset), _) => preset.palette(),
+            (None, Some(palette)) => palette,
+            (None, None) => self.config.display.palette(),
+        };
+    }
+
+    fn enter(&mut self) -> std::io::Result<()> {
+        terminal::enable_raw_mode()?;
+        self.out
+            .queue(terminal::EnterAlternateScreen)?
+            .queue(cursor::Hide)?;
+        // Only some terminals can report releases, the rest fall back to `KEY_HOLD`
+        self.releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if self.releases {
+            self.out.queue(event::PushKeyboardEnhancementFlags(
+                KeyboardEnhancementFlags::REPORT_EVENT_TYPES,
+            ))?;
+        }
+        self.out.flush()
+    }
+
+    fn leave(&mut self) -> std::io::Result<()> {
+        if self.releases {
+            self.out.queue(event::PopKeyboardEnhancementFlags)?;
+        }
+        self.out
+            .queue(crossterm::style::ResetColor)?
+            .queue(cursor::Show)?
+            .queue(terminal::LeaveAlternateScreen)?
+            .flush()?;
+        terminal::disable_raw_mode()
+    }
+
+    fn run(&mut self) -> Result<(), TuiError> {
+        self.out.queue(terminal::Clear(terminal::ClearType::All))?;
+        self.draw()?;
+        let mut next_frame = Instant::now();
+
+        loop {
+            // Input is read while waiting for the next frame
+            while event::poll(next_frame.saturating_duration_since(Instant::now()))? {
+                if let Event::Key(key) = event::read()? {
+                    if !self.key(key) {
+                        return Ok(());
+                    }
+                }
+            }
+            next_frame = Instant::now() + FRAME;
+
+            let now = Instant::now();
+            for (k, held) in self.held.iter_mut().enumerate() {
+                if held.is_some_and(|until| until <= now) {
+                    *held = None;
+                    self.runner.send(runner::Command::Key(k, false));
+                }
+            }
+            let roms = self
+                .remote
+                .as_mut()
+                .map(|remote| remote.poll(&self.runner))
+                .unwrap_or_default();
+            for rom in roms {
+                self.load(rom, None);
+            }
+            while let Some(event) = self.runner.poll_event() {
+                if let (Some(remote), runner::Event::RomLoaded(result)) = (&mut self.remote, &event)
+                {
+                    remote.rom_loaded(result);
+                }
+                match event {
+                    runner::Event::Fault(e) => self.status = format!("CPU fault: {}", e),
+                    runner::Event::RomLoaded(Ok(loaded)) => {
+                        self.loaded(&loaded);
+                        self.status.clear();
+                    }
+                    runner::Event::RomLoaded(Err(e)) => self.status = e.to_string(),
+                    _ => {}
+                }
+            }
+            if self.runner.update() {
+                self.draw()?;
+            }
+        }
+    }
+
+    /// Handle a key, returns false to quit.
+    fn key(&mut self, key: KeyEvent) -> bool {
+        let pressed = key.kind != KeyEventKind::Release;
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::F(4) if key.kind == KeyEventKind::Press => {
+                self.runner.send(runner::Command::Step)
+            }
+            KeyCode::F(5) if key.kind == KeyEventKind::Press => {
+                let paused = self.runner.frame().paused;
+                self.runner.send(runner::Command::Pause(!paused));
+            }
+            KeyCode::F(6) if key.kind == KeyEventKind::Press => {
+                self.runner.send(runner::Command::FrameAdvance)
+            }
+            KeyCode::Tab if key.kind == KeyEventKind::Press => {
+                self.sidebar = !self.sidebar;
+                let _ = self.out.queue(terminal::Clear(terminal::ClearType::All));
+                let _ = self.draw();
+            }
+            KeyCode::Char(c) => {
+                if let Some(k) = self.key_map.lookup(c) {
+                    if !self.releases {
+                        let was_held = self.held[k].is_some();
+                        self.held[k] = Some(Instant::now() + KEY_HOLD);
+                        if was_held {
+                            return true;
+                        }
+                    }
+                    self.runner.send(runner::Command::Key(k, pressed));
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&mut self) -> std::io::Result<()> {
+        let frame = self.runner.frame().clone();
+        self.draw_screen(&frame)?;
+        if self.sidebar {
+            self.draw_sidebar(&frame)?;
+        }
+
+        let state = if frame.paused { "Paused" } else { "Running" };
+        let status = if self.status.is_empty() {
+            format!("{}  {:>8} IPS  {}", state, frame.ips, HELP)
+        } else {
+            format!("{}  {}", state, self.status)
+        };
+        self.out
+            .queue(crossterm::style::ResetColor)?
+            .queue(cursor::MoveTo(0, HEIGHT as u16 / 2 + 1))?
+            .queue(terminal::Clear(terminal::ClearType::CurrentLine))?
+            .queue(Print(status))?;
+        self.out.flush()
+    }
+
+    /// Upper half blocks, the top pixel in the foreground and the bottom one behind.
+    fn draw_screen(&mut self, frame: &Frame) -> std::io::Result<()> {
+        let color = |on: bool| {
+            let Rgb(r, g, b) = self.palette.color(on as usize);
+            Color::Rgb { r, g, b }
+        };
+        let mut colors = None;
+        for (row, pair) in frame.display.chunks_exact(2).enumerate() {
+            self.out.queue(cursor::MoveTo(0, row as u16))?;
+            for (&top, &bottom) in pair[0].iter().zip(&pair[1]) {
+                let cell = (color(top), color(bottom));
+                if colors != Some(cell) {
+                    self.out
+                        .queue(SetForegroundColor(cell.0))?
+                        .queue(SetBackgroundColor(cell.1))?;
+                    colors = Some(cell);
+                }
+                self.out.queue(Print('▀'))?;
+            }
+        }
+        self.out.queue(crossterm::style::ResetColor)?;
+        Ok(())
+    }
+
+    fn draw_sidebar(&mut self, frame: &Frame) -> std::io::Result<()> {
+        let state = &frame.state;
+        let mut lines: Vec<String> = state
+            .v
+            .chunks(4)
+            .enumerate()
+            .map(|(row, regs)| {
+                regs.iter()
+                    .enumerate()
+                    .map(|(i, v)| format!("V{:X}:{:02X}", row * 4 + i, v))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+        lines.push(String::new());
+        lines.push(format!(
+            "PC:{:03X}  I:{:03X}  SP:{:X}",
+            state.pc, state.i, state.sp
+        ));
+        lines.push(format!("DT:{:02X}   ST:{:02X}", state.dt, state.st));
+        let opcode = frame
+            .memory
+            .get(state.pc as usize..state.pc as usize + 2)
+            .map_or(0, |b| u16::from_be_bytes([b[0], b[1]]));
+        lines.push(format!("Next:{:04X}", opcode));
+        lines.push(String::new());
+        let keys: String = (0..16)
+            .map(|k| {
+                if state.keypad[k] {
+                    format!("{:X}", k)
+                } else {
+                    String::from(".")
+                }
+            })
+            .collect();
+        lines.push(format!("Keys:{}", keys));
+
+        for (row, line) in lines.iter().enumerate() {
+            self.out
+                .queue(cursor::MoveTo(SIDEBAR_COLUMN, row as u16))?
+                .queue(terminal::Clear(terminal::ClearType::UntilNewLine))?
+                .queue(Print(line))?;
+        }
+        Ok(())
+    }
+}